@@ -0,0 +1,54 @@
+//! Runs the bundled images through `um::bench`, same as `um bench` does.
+//! Built with the `jit` feature, each image is also run with the native
+//! code backend, which is compared against the interpreter.
+//!
+//! `cargo bench` passes `--bench`, any other argument restricts the run to
+//! workloads with matching names.
+
+use std::env;
+use std::process;
+use um::bench::{self, Report, Workload};
+use um::mem::Strategy;
+
+fn main() {
+    let filters: Vec<String> = env::args()
+        .skip(1)
+        .filter(|a| !a.starts_with("--"))
+        .collect();
+    let workloads = Workload::bundled(env!("CARGO_MANIFEST_DIR"));
+
+    for workload in workloads {
+        if !filters.is_empty() && !filters.iter().any(|f| workload.name.contains(f.as_str())) {
+            continue;
+        }
+        let report = check(&workload, bench::run(&workload, Strategy::default()));
+        print!("{}", report);
+        jit(&workload, &report);
+    }
+}
+
+fn check(workload: &Workload, result: Result<Report, Box<dyn std::error::Error>>) -> Report {
+    result.unwrap_or_else(|e| {
+        eprintln!("{}: {}", workload.name, e);
+        process::exit(1);
+    })
+}
+
+#[cfg(all(feature = "jit", target_arch = "x86_64", unix))]
+fn jit(workload: &Workload, interpreted: &Report) {
+    let report = check(workload, bench::run_jit(workload, Strategy::default()));
+    if report.instructions != interpreted.instructions || report.stats != interpreted.stats {
+        eprintln!("{}: jit disagrees with the interpreter", workload.name);
+        process::exit(1);
+    }
+    println!(
+        "{} (jit): {:.3}s, {:.1} MIPS, {:.2}x the interpreter",
+        workload.name,
+        report.elapsed.as_secs_f64(),
+        report.mips(),
+        report.mips() / interpreted.mips()
+    );
+}
+
+#[cfg(not(all(feature = "jit", target_arch = "x86_64", unix)))]
+fn jit(_: &Workload, _: &Report) {}
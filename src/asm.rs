@@ -0,0 +1,523 @@
+//! Assembler for a small textual UM assembly language.
+//!
+//! One statement per line, optionally preceded by `label:`. Comments start
+//! with `;` or `#`. Instructions use the lowercase `Op` names with their
+//! operands in the order of the variant's fields, the same syntax the
+//! disassembler prints:
+//!
+//! ```text
+//! start:  mov r1, 'H'
+//!         output r1
+//!         li r2, 0xdeadbeef, r7   ; any 32-bit constant, r7 is clobbered
+//!         li r3, msg              ; address of a label
+//!         halt
+//! msg:    .string "hi\n"          ; one word per byte, no terminator
+//!         .word 0, 0x10, msg
+//! ```
+//!
+//! `li rA, VALUE [, rT]` loads any 32-bit constant. It takes a single `mov`
+//! if the value fits in 25 bits, `mov` and `nand` if its complement does,
+//! and otherwise five instructions which need the scratch register `rT`.
+//! Numbers are decimal, `0x` hex or character literals.
+
+use crate::image::Symbols;
+use crate::op::{Op, Reg};
+use byteorder::{BigEndian, WriteBytesExt};
+use std::collections::HashMap;
+use std::error;
+use std::fmt;
+
+const MAX_IMM: u32 = 0x01FF_FFFF;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AsmError {
+    pub line: usize,
+    pub msg: String,
+}
+
+impl fmt::Display for AsmError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.msg)
+    }
+}
+
+impl error::Error for AsmError {}
+
+enum Value {
+    Num(u32),
+    Label(String),
+}
+
+enum Stmt {
+    Op(Op),
+    Mov(Reg, Value),
+    Li(Reg, Value, Option<Reg>),
+    Words(Vec<Value>),
+}
+
+impl Stmt {
+    fn size(&self) -> usize {
+        match self {
+            Stmt::Op(_) | Stmt::Mov(..) => 1,
+            Stmt::Li(_, Value::Num(n), _) => li_size(*n),
+            // Label addresses are checked to fit a single `mov`.
+            Stmt::Li(_, Value::Label(_), _) => 1,
+            Stmt::Words(words) => words.len(),
+        }
+    }
+}
+
+fn li_size(n: u32) -> usize {
+    if n <= MAX_IMM {
+        1
+    } else if !n <= MAX_IMM {
+        2
+    } else {
+        5
+    }
+}
+
+/// Assembles `src` into the words of a program, the first statement ends
+/// up at address 0.
+pub fn assemble(src: &str) -> Result<Vec<u32>, AsmError> {
+    assemble_symbols(src).map(|(words, _)| words)
+}
+
+/// Like `assemble`, but also returns the labels sorted by address, for an
+/// image container.
+pub fn assemble_symbols(src: &str) -> Result<(Vec<u32>, Symbols), AsmError> {
+    let mut labels = HashMap::new();
+    let mut stmts = Vec::new();
+    let mut addr = 0;
+
+    for (n, line) in src.lines().enumerate() {
+        let err = |msg: String| AsmError { line: n + 1, msg };
+        let mut rest = strip_comment(line).trim();
+
+        if let Some(colon) = label_end(rest) {
+            let label = &rest[..colon];
+            if labels.insert(label.to_string(), addr as u32).is_some() {
+                return Err(err(format!("duplicate label {}", label)));
+            }
+            rest = rest[colon + 1..].trim();
+        }
+        if rest.is_empty() {
+            continue;
+        }
+
+        let stmt = parse_stmt(rest).map_err(err)?;
+        addr += stmt.size();
+        stmts.push((n + 1, stmt));
+    }
+
+    let mut words = Vec::with_capacity(addr);
+    for (line, stmt) in &stmts {
+        let err = |msg: String| AsmError { line: *line, msg };
+        let resolve = |v: &Value| match v {
+            Value::Num(n) => Ok(*n),
+            Value::Label(l) => labels
+                .get(l)
+                .copied()
+                .ok_or_else(|| err(format!("undefined label {}", l))),
+        };
+
+        match stmt {
+            Stmt::Op(op) => words.push(op.encode()),
+            Stmt::Mov(a, v) => match resolve(v)? {
+                n if n <= MAX_IMM => words.push(Op::Mov(*a, n).encode()),
+                n => return Err(err(format!("{:#x} doesn't fit in 25 bits, use li", n))),
+            },
+            Stmt::Li(a, v, scratch) => {
+                let n = resolve(v)?;
+                if let Value::Label(_) = v {
+                    if n > MAX_IMM {
+                        return Err(err(format!("label address {:#x} is too large", n)));
+                    }
+                }
+                emit_li(&mut words, *a, n, *scratch).map_err(err)?;
+            }
+            Stmt::Words(values) => {
+                for v in values {
+                    words.push(resolve(v)?);
+                }
+            }
+        }
+    }
+
+    let mut symbols: Vec<_> = labels.into_iter().map(|(l, addr)| (addr, l)).collect();
+    symbols.sort();
+    Ok((words, symbols))
+}
+
+/// Big-endian image of `words`, as expected by `Machine::load`.
+pub fn image(words: &[u32]) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(words.len() * 4);
+    for &w in words {
+        bytes.write_u32::<BigEndian>(w).unwrap();
+    }
+    bytes
+}
+
+fn emit_li(words: &mut Vec<u32>, a: Reg, n: u32, scratch: Option<Reg>) -> Result<(), String> {
+    match li_size(n) {
+        1 => words.push(Op::Mov(a, n).encode()),
+        2 => {
+            words.push(Op::Mov(a, !n).encode());
+            words.push(Op::Nand(a, a, a).encode());
+        }
+        _ => {
+            let t = match scratch {
+                Some(t) if t != a => t,
+                Some(_) => return Err("scratch register must differ from the target".into()),
+                None => return Err(format!("{:#x} needs a scratch register", n)),
+            };
+            words.push(Op::Mov(a, n >> 16).encode());
+            words.push(Op::Mov(t, 0x10000).encode());
+            words.push(Op::Mul(a, a, t).encode());
+            words.push(Op::Mov(t, n & 0xFFFF).encode());
+            words.push(Op::Add(a, a, t).encode());
+        }
+    }
+    Ok(())
+}
+
+/// Cuts the comment off, unless the comment character is quoted.
+fn strip_comment(line: &str) -> &str {
+    let mut quote = None;
+    let mut escaped = false;
+    for (i, c) in line.char_indices() {
+        match quote {
+            Some(_) if escaped => escaped = false,
+            Some(_) if c == '\\' => escaped = true,
+            Some(q) if c == q => quote = None,
+            Some(_) => {}
+            None if c == '"' || c == '\'' => quote = Some(c),
+            None if c == ';' || c == '#' => return &line[..i],
+            None => {}
+        }
+    }
+    line
+}
+
+fn is_ident(s: &str) -> bool {
+    let mut chars = s.chars();
+    match chars.next() {
+        Some(c) if c.is_ascii_alphabetic() || c == '_' || c == '.' => {}
+        _ => return false,
+    }
+    chars.all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '.')
+}
+
+fn label_end(s: &str) -> Option<usize> {
+    s.find(':').filter(|&colon| is_ident(&s[..colon]))
+}
+
+fn parse_stmt(s: &str) -> Result<Stmt, String> {
+    let (name, rest) = match s.find(char::is_whitespace) {
+        Some(i) => (&s[..i], s[i..].trim()),
+        None => (s, ""),
+    };
+    let name = name.to_lowercase();
+
+    if name == ".string" {
+        return Ok(Stmt::Words(
+            parse_string(rest)?.into_iter().map(Value::Num).collect(),
+        ));
+    }
+
+    let args = split_args(rest);
+    match name.as_str() {
+        ".word" if args.is_empty() => Err(".word needs a value".into()),
+        ".word" => Ok(Stmt::Words(
+            args.iter()
+                .map(|a| parse_value(a))
+                .collect::<Result<_, _>>()?,
+        )),
+        "mov" => match args[..] {
+            [a, v] => Ok(Stmt::Mov(parse_reg(a)?, parse_value(v)?)),
+            _ => Err("mov takes a register and a value".into()),
+        },
+        "li" => match args[..] {
+            [a, v] => Ok(Stmt::Li(parse_reg(a)?, parse_value(v)?, None)),
+            [a, v, t] => Ok(Stmt::Li(
+                parse_reg(a)?,
+                parse_value(v)?,
+                Some(parse_reg(t)?),
+            )),
+            _ => Err("li takes a register, a value and an optional scratch register".into()),
+        },
+        _ if !Op::NAMES.iter().any(|n| n.to_lowercase() == name) => {
+            Err(format!("unknown instruction {}", name))
+        }
+        _ => {
+            let regs = args
+                .iter()
+                .map(|a| parse_reg(a))
+                .collect::<Result<Vec<_>, _>>()?;
+            parse_op(&name, &regs).map(Stmt::Op)
+        }
+    }
+}
+
+fn parse_op(name: &str, regs: &[Reg]) -> Result<Op, String> {
+    let op = match (name, regs) {
+        ("condmov", &[a, b, c]) => Op::CondMov(a, b, c),
+        ("memread", &[a, b, c]) => Op::MemRead(a, b, c),
+        ("memwrite", &[a, b, c]) => Op::MemWrite(a, b, c),
+        ("add", &[a, b, c]) => Op::Add(a, b, c),
+        ("mul", &[a, b, c]) => Op::Mul(a, b, c),
+        ("div", &[a, b, c]) => Op::Div(a, b, c),
+        ("nand", &[a, b, c]) => Op::Nand(a, b, c),
+        ("halt", &[]) => Op::Halt,
+        ("alloc", &[b, c]) => Op::Alloc(b, c),
+        ("free", &[c]) => Op::Free(c),
+        ("output", &[c]) => Op::Output(c),
+        ("input", &[c]) => Op::Input(c),
+        ("loadprogram", &[b, c]) => Op::LoadProgram(b, c),
+        _ => return Err(format!("wrong number of operands for {}", name)),
+    };
+    Ok(op)
+}
+
+/// Splits operands on commas which aren't inside a character literal.
+fn split_args(s: &str) -> Vec<&str> {
+    if s.is_empty() {
+        return Vec::new();
+    }
+    let mut args = Vec::new();
+    let mut start = 0;
+    let mut quoted = false;
+    let mut escaped = false;
+    for (i, c) in s.char_indices() {
+        if escaped {
+            escaped = false;
+        } else if quoted && c == '\\' {
+            escaped = true;
+        } else if c == '\'' {
+            quoted = !quoted;
+        } else if c == ',' && !quoted {
+            args.push(s[start..i].trim());
+            start = i + 1;
+        }
+    }
+    args.push(s[start..].trim());
+    args
+}
+
+fn parse_reg(s: &str) -> Result<Reg, String> {
+    match s.as_bytes() {
+        [b'r', d @ b'0'..=b'7'] | [b'R', d @ b'0'..=b'7'] => Ok((d - b'0') as Reg),
+        _ => Err(format!("expected register r0-r7, got {:?}", s)),
+    }
+}
+
+fn parse_value(s: &str) -> Result<Value, String> {
+    if s.starts_with('\'') {
+        let chars = parse_quoted(s, '\'')?;
+        return match chars[..] {
+            [c] => Ok(Value::Num(c)),
+            _ => Err(format!("bad character literal {}", s)),
+        };
+    }
+    if parse_reg(s).is_ok() {
+        return Err(format!(
+            "register {} isn't allowed here, expected a value",
+            s
+        ));
+    }
+    if is_ident(s) {
+        return Ok(Value::Label(s.to_string()));
+    }
+    let parsed = match s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        Some(hex) => u32::from_str_radix(hex, 16),
+        None => s.parse(),
+    };
+    parsed
+        .map(Value::Num)
+        .map_err(|_| format!("bad number {:?}", s))
+}
+
+fn parse_string(s: &str) -> Result<Vec<u32>, String> {
+    if !s.starts_with('"') {
+        return Err(".string needs a quoted string".into());
+    }
+    parse_quoted(s, '"')
+}
+
+/// Parses a literal enclosed in `quote`, which must span all of `s`.
+/// Each byte of its UTF-8 encoding becomes a separate value.
+fn parse_quoted(s: &str, quote: char) -> Result<Vec<u32>, String> {
+    let inner = s
+        .strip_prefix(quote)
+        .and_then(|s| s.strip_suffix(quote))
+        .ok_or_else(|| format!("unterminated literal {}", s))?;
+
+    let mut bytes = Vec::new();
+    let mut chars = inner.chars();
+    while let Some(c) = chars.next() {
+        let c = match c {
+            '\\' => match chars.next() {
+                Some('n') => '\n',
+                Some('t') => '\t',
+                Some('r') => '\r',
+                Some('0') => '\0',
+                Some('x') => {
+                    let hex: String = chars.by_ref().take(2).collect();
+                    let byte = u8::from_str_radix(&hex, 16)
+                        .map_err(|_| format!("bad escape \\x{}", hex))?;
+                    bytes.push(byte as u32);
+                    continue;
+                }
+                Some(c @ '\\') | Some(c @ '"') | Some(c @ '\'') => c,
+                Some(c) => return Err(format!("bad escape \\{}", c)),
+                None => return Err(format!("unterminated literal {}", s)),
+            },
+            c if c == quote => return Err(format!("stray quote in {}", s)),
+            c => c,
+        };
+        let mut buf = [0; 4];
+        bytes.extend(c.encode_utf8(&mut buf).bytes().map(u32::from));
+    }
+    Ok(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::disasm;
+    use crate::io::Buffer;
+    use crate::machine::{Machine, Status};
+
+    fn run(src: &str) -> Vec<u8> {
+        let image = image(&assemble(src).unwrap());
+        let mut um = Machine::load_with_io(&image, Buffer::new("")).unwrap();
+        assert_eq!(um.run(), Ok(Status::Halted));
+        um.into_io().take_output()
+    }
+
+    fn error(src: &str) -> String {
+        assemble(src).unwrap_err().to_string()
+    }
+
+    #[test]
+    fn instructions() {
+        let words = assemble("mov r0, 4239\nloadprogram r6, r0\nhalt").unwrap();
+        assert_eq!(words, [0xD000_108F, 0xC000_0030, 0x7000_0000]);
+    }
+
+    #[test]
+    fn disasm_roundtrip() {
+        let ops = [
+            Op::CondMov(1, 2, 3),
+            Op::MemWrite(7, 0, 1),
+            Op::Alloc(2, 1),
+            Op::Free(4),
+            Op::Input(5),
+            Op::Mov(6, 0x41),
+            Op::Halt,
+        ];
+        let src: String = ops.iter().map(|op| format!("{}\n", op)).collect();
+        let words = assemble(&src).unwrap();
+        let parsed: Vec<_> = disasm::disassemble(&words).map(|l| l.op.unwrap()).collect();
+        assert_eq!(parsed, ops);
+    }
+
+    #[test]
+    fn hello() {
+        let src = r#"
+            ; prints the zero terminated msg
+                    li r1, msg
+                    mov r3, 0
+                    mov r5, 1
+            loop:   memread r4, r3, r1      # r3 is 0, the program itself
+                    mov r6, print
+                    mov r7, done
+                    condmov r7, r6, r4
+                    loadprogram r3, r7
+            print:  output r4
+                    add r1, r1, r5
+                    mov r7, loop
+                    loadprogram r3, r7
+            done:   halt
+            msg:    .string "Hi, \"UM\"; bye\n"
+                    .word 0
+        "#;
+        assert_eq!(run(src), b"Hi, \"UM\"; bye\n");
+    }
+
+    #[test]
+    fn load_constants() {
+        for &(n, len) in &[
+            (0x41, 1),
+            (0xFFFF_FFFF, 2),
+            (0xDEAD_BEEF, 5),
+            (0x0200_0000, 5),
+        ] {
+            let src = format!(
+                "li r1, {:#x}, r2\nmov r3, 0\nmov r4, 1\nalloc r5, r4\nmemwrite r5, r3, r1\nhalt",
+                n
+            );
+            let words = assemble(&src).unwrap();
+            assert_eq!(words.len(), len + 5);
+            let mut um = Machine::load_with_io(&image(&words), Buffer::new("")).unwrap();
+            assert_eq!(um.run(), Ok(Status::Halted));
+            assert_eq!(um.reg(1), n);
+        }
+    }
+
+    #[test]
+    fn words_and_labels() {
+        let words = assemble("a: .word 1, 'x', b ; c\nb: .string \"\\x41;#\"\n").unwrap();
+        assert_eq!(words, [1, 0x78, 3, 0x41, 0x3B, 0x23]);
+
+        let (_, symbols) = assemble_symbols("b: halt\na:\nc: .word a").unwrap();
+        assert_eq!(
+            symbols,
+            [
+                (0, "b".to_string()),
+                (1, "a".to_string()),
+                (1, "c".to_string())
+            ]
+        );
+    }
+
+    #[test]
+    fn errors() {
+        assert_eq!(error("nop"), "line 1: unknown instruction nop");
+        assert_eq!(error("nop r9"), "line 1: unknown instruction nop");
+        assert_eq!(
+            error("\nadd r1, r2"),
+            "line 2: wrong number of operands for add"
+        );
+        assert_eq!(
+            error("output r8"),
+            "line 1: expected register r0-r7, got \"r8\""
+        );
+        assert_eq!(
+            error("mov r0, 0x2000000"),
+            "line 1: 0x2000000 doesn't fit in 25 bits, use li"
+        );
+        assert_eq!(
+            error("li r0, 0x12345678"),
+            "line 1: 0x12345678 needs a scratch register"
+        );
+        assert_eq!(
+            error("mov r1, r2"),
+            "line 1: register r2 isn't allowed here, expected a value"
+        );
+        assert_eq!(
+            error("li r1, R7, r2"),
+            "line 1: register R7 isn't allowed here, expected a value"
+        );
+        assert_eq!(
+            error(".word 1, r3"),
+            "line 1: register r3 isn't allowed here, expected a value"
+        );
+        assert_eq!(error("x: halt\nx: halt"), "line 2: duplicate label x");
+        assert_eq!(error(".word nowhere"), "line 1: undefined label nowhere");
+        assert_eq!(
+            error(".string \"open"),
+            "line 1: unterminated literal \"open"
+        );
+    }
+}
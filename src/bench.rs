@@ -0,0 +1,287 @@
+use crate::image::Image;
+use crate::io::{FlushPolicy, Streams};
+#[cfg(all(feature = "jit", target_arch = "x86_64", unix))]
+use crate::jit::Jit;
+#[cfg(all(feature = "jit", target_arch = "x86_64", unix))]
+use crate::machine::Limits;
+use crate::machine::{Fault, Machine, Status};
+use crate::mem::Strategy;
+use crate::stats::Stats;
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+/// A program image together with the input it's fed.
+pub struct Workload {
+    pub name: String,
+    /// In any format of `image`.
+    pub image: Vec<u8>,
+    pub input: Vec<u8>,
+}
+
+impl Workload {
+    pub fn new(name: &str, image: Vec<u8>, input: Vec<u8>) -> Self {
+        Workload {
+            name: name.to_string(),
+            image,
+            input,
+        }
+    }
+
+    /// Loads the image in any format of `image` and concatenates the input
+    /// files, like `cat` would, after the input script of a container.
+    pub fn from_files<P: AsRef<Path>, Q: AsRef<Path>>(image: P, inputs: &[Q]) -> io::Result<Self> {
+        let name = image.as_ref().display().to_string();
+        let bytes = fs::read(image)?;
+        let mut input = Image::read(&bytes)?.input;
+        for path in inputs {
+            input.extend(fs::read(path)?);
+        }
+        Ok(Workload::new(&name, bytes, input))
+    }
+
+    /// Workloads built from the images shipped with the repository: the
+    /// sandmark, the codex extraction and a scripted UMIX session. The ones
+    /// whose files are missing in `dir` are skipped.
+    pub fn bundled<P: AsRef<Path>>(dir: P) -> Vec<Workload> {
+        let dir = dir.as_ref();
+        let sets: [(&str, &str, &[&str]); 3] = [
+            ("sandmark", "sandmark.umz", &[]),
+            ("codex", "codex.umz", &["key", "extract.script"]),
+            ("umix", "prog.um", &["adventure.script"]),
+        ];
+
+        let mut workloads = Vec::new();
+        for (name, image, inputs) in sets.iter() {
+            let inputs: Vec<_> = inputs.iter().map(|f| dir.join(f)).collect();
+            if let Ok(mut w) = Workload::from_files(dir.join(image), &inputs) {
+                w.name = name.to_string();
+                workloads.push(w);
+            }
+        }
+        workloads
+    }
+}
+
+/// Outcome of a single benchmark run.
+pub struct Report {
+    pub name: String,
+    pub status: Status,
+    pub instructions: u64,
+    pub elapsed: Duration,
+    pub stats: Stats,
+}
+
+impl Report {
+    /// Millions of instructions per second.
+    pub fn mips(&self) -> f64 {
+        self.instructions as f64 / self.elapsed.as_secs_f64() / 1e6
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "{}: {} instructions in {:.3}s, {:.1} MIPS ({:?})",
+            self.name,
+            self.instructions,
+            self.elapsed.as_secs_f64(),
+            self.mips(),
+            self.status
+        )?;
+        write!(f, "{}", self.stats)
+    }
+}
+
+/// Runs the workload to completion with arrays allocated using `alloc`,
+/// output of the program is discarded. Fails with the `io::Error` if the
+/// image doesn't load, or with the `Fault` the program ran into.
+pub fn run(workload: &Workload, alloc: Strategy) -> Result<Report, Box<dyn Error>> {
+    measure(workload, alloc, |um| um.run())
+}
+
+/// Like `run`, with the native code backend instead of the interpreter.
+#[cfg(all(feature = "jit", target_arch = "x86_64", unix))]
+pub fn run_jit(workload: &Workload, alloc: Strategy) -> Result<Report, Box<dyn Error>> {
+    let mut jit = Jit::new();
+    measure(workload, alloc, |um| jit.run(um, Limits::default()))
+}
+
+fn measure<'w, F>(workload: &'w Workload, alloc: Strategy, run: F) -> Result<Report, Box<dyn Error>>
+where
+    F: FnOnce(&mut Machine<Streams<&'w [u8], io::Sink>>) -> Result<Status, Fault>,
+{
+    let io = Streams::with_policy(&workload.input[..], io::sink(), FlushPolicy::Never);
+    let mut um = Machine::open_with_io(&workload.image, io)?;
+    um.set_alloc_strategy(alloc);
+    um.collect_stats(true);
+
+    let start = Instant::now();
+    let status = run(&mut um)?;
+    let elapsed = start.elapsed();
+
+    Ok(Report {
+        name: workload.name.clone(),
+        status,
+        instructions: um.steps(),
+        elapsed,
+        stats: um.stats().expect("stats are collected"),
+    })
+}
+
+/// Results of an earlier run to compare against, one line per workload:
+/// name, number of instructions and MIPS.
+#[derive(Debug, Default, PartialEq)]
+pub struct Baseline {
+    entries: Vec<(String, u64, f64)>,
+}
+
+impl Baseline {
+    pub fn from_reports(reports: &[Report]) -> Self {
+        Baseline {
+            entries: reports
+                .iter()
+                .map(|r| (r.name.clone(), r.instructions, r.mips()))
+                .collect(),
+        }
+    }
+
+    /// Compares a report against the baseline entry of the same name.
+    pub fn compare(&self, report: &Report) -> Option<Comparison> {
+        self.entries
+            .iter()
+            .find(|(name, _, _)| *name == report.name)
+            .map(|&(_, instructions, mips)| Comparison {
+                same_instructions: instructions == report.instructions,
+                speedup: report.mips() / mips,
+            })
+    }
+}
+
+impl fmt::Display for Baseline {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (name, instructions, mips) in &self.entries {
+            writeln!(f, "{} {} {:.3}", name, instructions, mips)?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for Baseline {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut entries = Vec::new();
+        for (n, line) in s.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let fields: Vec<_> = line.split_whitespace().collect();
+            let entry = match fields[..] {
+                [name, instructions, mips] => instructions
+                    .parse()
+                    .ok()
+                    .and_then(|i| mips.parse().ok().map(|m| (name.to_string(), i, m))),
+                _ => None,
+            };
+            match entry {
+                Some(entry) => entries.push(entry),
+                None => return Err(format!("baseline line {}: malformed entry", n + 1)),
+            }
+        }
+        Ok(Baseline { entries })
+    }
+}
+
+pub struct Comparison {
+    /// Whether the program executed as many instructions as before, if not
+    /// the interpreter's behaviour has changed.
+    pub same_instructions: bool,
+    /// Ratio of current MIPS to baseline MIPS.
+    pub speedup: f64,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mem::MemStats;
+
+    fn report(name: &str, instructions: u64, millis: u64) -> Report {
+        Report {
+            name: name.to_string(),
+            status: Status::Halted,
+            instructions,
+            elapsed: Duration::from_millis(millis),
+            stats: Stats {
+                instructions,
+                op_counts: [0; 14],
+                program_loads: 0,
+                live_arrays: 1,
+                mem: MemStats::default(),
+            },
+        }
+    }
+
+    #[test]
+    fn run_hello() {
+        let image = vec![
+            0xD0, 0x00, 0x00, 0x21, // mov r0, '!'
+            0xA0, 0x00, 0x00, 0x00, // out r0
+            0x70, 0x00, 0x00, 0x00, // halt
+        ];
+        let report = run(&Workload::new("hello", image, vec![]), Strategy::Slab).unwrap();
+        assert_eq!(report.status, Status::Halted);
+        assert_eq!(report.instructions, 3);
+        assert_eq!(report.stats.op_counts[10], 1);
+
+        let truncated = Workload::new("truncated", vec![0xD0, 0x00], vec![]);
+        match run(&truncated, Strategy::Slab) {
+            Err(e) => assert!(e.is::<io::Error>()),
+            Ok(_) => panic!("truncated image ran"),
+        }
+    }
+
+    #[cfg(all(feature = "jit", target_arch = "x86_64", unix))]
+    #[test]
+    fn run_jit_hello() {
+        let image = vec![
+            0xD0, 0x00, 0x00, 0x21, // mov r0, '!'
+            0xA0, 0x00, 0x00, 0x00, // out r0
+            0x70, 0x00, 0x00, 0x00, // halt
+        ];
+        let workload = Workload::new("hello", image, vec![]);
+        let report = run_jit(&workload, Strategy::Slab).unwrap();
+        let expected = run(&workload, Strategy::Slab).unwrap();
+        assert_eq!(report.status, Status::Halted);
+        assert_eq!(report.instructions, 3);
+        assert_eq!(report.stats, expected.stats);
+    }
+
+    #[test]
+    fn baseline_roundtrip() {
+        let baseline = Baseline::from_reports(&[report("a", 1000, 1), report("b", 10, 1)]);
+        let parsed: Baseline = baseline.to_string().parse().unwrap();
+        assert_eq!(parsed, baseline);
+    }
+
+    #[test]
+    fn baseline_compare() {
+        let baseline: Baseline = "# name insns mips\nsandmark 4000 2.0\n".parse().unwrap();
+        let cmp = baseline.compare(&report("sandmark", 4000, 1)).unwrap();
+        assert!(cmp.same_instructions);
+        assert!((cmp.speedup - 2.0).abs() < 1e-9);
+        assert!(baseline.compare(&report("codex", 1, 1)).is_none());
+    }
+
+    #[test]
+    fn baseline_malformed() {
+        assert!("sandmark fast".parse::<Baseline>().is_err());
+    }
+}
@@ -0,0 +1,652 @@
+//! Command line of the `um` binary.
+
+use std::fmt::Display;
+use std::path::Path;
+use std::str::FromStr;
+use std::time::Duration;
+use um::debugger::parse_num;
+use um::io::FlushPolicy;
+use um::mem::{MemLimits, Quarantine, Strategy};
+use um::op::Reg;
+use um::trace::Filter;
+
+pub const USAGE: &str = "\
+usage: um [options] <program>
+       um [options] --restore <snapshot>
+       um debug [options] <program>
+       um bench [options] [<program> [<input>...]]
+       um disasm <program>
+       um asm [-o <output>] [image options] <source>
+       um pack [-o <output>] [image options] <program>
+       um help
+
+Runs a Universal Machine program image. disasm prints the program as
+assembly instead of running it, asm builds an image from assembly source,
+pack compresses an image. Images compressed with pack are loaded as they
+are, and so are gzip, zstd or xz compressed ones in builds with the
+features of the same names. Given any of the image options, asm and pack
+write a container which also sets the machine up to start the program.
+
+options:
+  --input FILE        feed FILE to the program before anything else, may be
+                      given several times, files are read in order
+  --then-stdin        continue with stdin once all --input files are consumed
+                      (default when no --input is given, except for debug
+                      which reads its commands from stdin)
+  --flush POLICY      when to flush output: newline (default), input, halt
+                      or never
+  --alloc STRATEGY    how to allocate arrays: lowest reuses the lowest freed
+                      id (default), lifo the most recently freed one, slab
+                      also packs small arrays together; a restored snapshot
+                      keeps its own unless given
+  --quarantine N      hold ids of freed arrays back until N more arrays have
+                      been allocated, forever to never reuse them, so that
+                      use of a stale id faults; a restored snapshot keeps
+                      its own unless given
+  --max-words N       fault when an allocation would take the words in live
+                      arrays over N, array 0 included
+  --max-arrays N      fault when an allocation would take the number of live
+                      arrays over N, array 0 included
+  --trace FILE        write every executed instruction with registers before
+                      and after it to FILE, - for stderr
+  --trace-ip RANGE    only trace instructions at ips in RANGE, e.g. 0x10-0x2f,
+                      may be given several times
+  --trace-op OPS      only trace the given ops, e.g. alloc,free,loadprogram
+  --jit               compile the program to native code as it runs, needs a
+                      build with the jit feature on x86_64
+  --sanitize          check how the program uses arrays: ids which didn't
+                      come from alloc, use after free, reads of words never
+                      written and arrays leaked at halt, reported on exit
+  --max-steps N       stop after executing N instructions
+  --timeout SECS      stop after running for SECS seconds, fractions allowed
+  --stats             print execution statistics to stderr on exit
+  --profile FILE      write the address ranges the most instructions were
+                      executed in to FILE on exit, - for stderr
+  --profile-folded FILE
+                      write instruction counts per call stack to FILE in the
+                      folded format read by flamegraph tools
+  --save-on-halt FILE save a snapshot of the machine to FILE once the program
+                      stops without a fault: it halts, runs out of input or
+                      reaches --max-steps
+  --snapshot-at-step N
+                      save a snapshot to <program>.N.snap once N instructions
+                      have been executed since the program started, may be
+                      given several times
+  --restore FILE      resume from a snapshot instead of loading a program,
+                      input is taken from --input and stdin as usual
+  -h, --help          print this help
+
+asm and pack options:
+  -o, --output FILE   where to write the image, defaults to the source file
+                      name with a .um extension for asm and .umz for pack
+
+image options, added to those of a container given to pack:
+  --entry IP          start executing at IP instead of 0
+  --reg rN=VALUE      start with register rN set to VALUE, may be given
+                      several times
+  --array FILE        allocate an array holding the program of image FILE
+                      before starting, may be given several times, the
+                      arrays get ids 1, 2 and so on
+  --input FILE        feed FILE to the program before any --input given to
+                      run it, may be given several times
+  --symbols           keep the labels of the source for disasm (asm only)
+
+bench options:
+  --baseline FILE     compare results against FILE saved earlier
+  --save FILE         save results to FILE to be used as a baseline
+  --tolerance PCT     slowdown against baseline to tolerate, 5 by default
+  --alloc STRATEGY    allocator strategy to run the images with
+  --jit               run the images with the native code backend, see --jit
+                      above
+
+exit status:
+  0  program halted
+  1  machine fault, or errors in assembly source
+  2  usage or I/O error
+  3  program asked for input after it was told that input is over
+  4  instruction limit reached
+  5  time limit reached
+  6  program halted, but --sanitize found problems
+";
+
+#[derive(Debug)]
+pub struct RunOptions {
+    pub program: String,
+    pub inputs: Vec<String>,
+    pub then_stdin: bool,
+    pub flush: FlushPolicy,
+    pub alloc: Option<Strategy>,
+    pub quarantine: Option<Quarantine>,
+    pub mem_limits: MemLimits,
+    pub trace: Option<String>,
+    pub trace_filter: Filter,
+    pub max_steps: Option<u64>,
+    pub timeout: Option<Duration>,
+    pub stats: bool,
+    pub profile: Option<String>,
+    pub profile_folded: Option<String>,
+    pub jit: bool,
+    pub sanitize: bool,
+    pub save_on_halt: Option<String>,
+    pub snapshots: Vec<u64>,
+    /// Whether `program` is a snapshot to restore.
+    pub restore: bool,
+}
+
+#[derive(Debug)]
+pub struct BenchOptions {
+    pub baseline: Option<String>,
+    pub save: Option<String>,
+    pub tolerance: f64,
+    pub alloc: Strategy,
+    pub jit: bool,
+    pub files: Vec<String>,
+}
+
+#[derive(Debug)]
+pub struct FileOptions {
+    pub input: String,
+    pub output: String,
+    pub image: ImageOptions,
+}
+
+/// What to put in an image container besides the program.
+#[derive(Debug, Default)]
+pub struct ImageOptions {
+    pub entry: Option<u32>,
+    pub regs: Vec<(Reg, u32)>,
+    pub arrays: Vec<String>,
+    pub inputs: Vec<String>,
+    pub symbols: bool,
+}
+
+impl ImageOptions {
+    pub fn is_empty(&self) -> bool {
+        self.entry.is_none()
+            && self.regs.is_empty()
+            && self.arrays.is_empty()
+            && self.inputs.is_empty()
+            && !self.symbols
+    }
+}
+
+#[derive(Debug)]
+pub enum Command {
+    Run(RunOptions),
+    Debug(RunOptions),
+    Bench(BenchOptions),
+    Disasm(String),
+    Asm(FileOptions),
+    Pack(FileOptions),
+    Help,
+}
+
+/// Arguments with support for both `--flag value` and `--flag=value`.
+struct Args<I> {
+    iter: I,
+    pending: Option<String>,
+}
+
+impl<I: Iterator<Item = String>> Args<I> {
+    /// Next flag or positional argument.
+    fn next(&mut self) -> Option<String> {
+        let arg = self.iter.next()?;
+        if arg.starts_with("--") {
+            if let Some(eq) = arg.find('=') {
+                self.pending = Some(arg[eq + 1..].to_string());
+                return Some(arg[..eq].to_string());
+            }
+        }
+        Some(arg)
+    }
+
+    fn value(&mut self, flag: &str) -> Result<String, String> {
+        self.pending
+            .take()
+            .or_else(|| self.iter.next())
+            .ok_or_else(|| format!("{} needs a value", flag))
+    }
+
+    fn parsed<T: FromStr>(&mut self, flag: &str) -> Result<T, String>
+    where
+        T::Err: Display,
+    {
+        let value = self.value(flag)?;
+        value
+            .parse()
+            .map_err(|e| format!("{} {}: {}", flag, value, e))
+    }
+
+    /// Makes sure a flag which takes no value didn't get one.
+    fn no_value(&mut self, flag: &str) -> Result<(), String> {
+        match self.pending.take() {
+            Some(_) => Err(format!("{} takes no value", flag)),
+            None => Ok(()),
+        }
+    }
+}
+
+pub fn parse<I: Iterator<Item = String>>(args: I) -> Result<Command, String> {
+    let mut args = Args {
+        iter: args,
+        pending: None,
+    };
+
+    let mut run = RunOptions {
+        program: String::new(),
+        inputs: Vec::new(),
+        then_stdin: false,
+        flush: FlushPolicy::default(),
+        alloc: None,
+        quarantine: None,
+        mem_limits: MemLimits::default(),
+        trace: None,
+        trace_filter: Filter::default(),
+        max_steps: None,
+        timeout: None,
+        stats: false,
+        profile: None,
+        profile_folded: None,
+        jit: false,
+        sanitize: false,
+        save_on_halt: None,
+        snapshots: Vec::new(),
+        restore: false,
+    };
+    let mut program = None;
+    let mut first = true;
+    let mut debug = false;
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "bench" if first => return parse_bench(args),
+            "debug" if first => debug = true,
+            "disasm" if first => return parse_disasm(args),
+            "asm" if first => return parse_file(args, true),
+            "pack" if first => return parse_file(args, false),
+            "help" if first => return Ok(Command::Help),
+            "-h" | "--help" => return Ok(Command::Help),
+            "--input" => run.inputs.push(args.value(&arg)?),
+            "--then-stdin" => {
+                args.no_value(&arg)?;
+                run.then_stdin = true;
+            }
+            "--flush" => run.flush = args.parsed(&arg)?,
+            "--alloc" => run.alloc = Some(args.parsed(&arg)?),
+            "--quarantine" => run.quarantine = Some(args.parsed(&arg)?),
+            "--max-words" => run.mem_limits.max_words = Some(args.parsed(&arg)?),
+            "--max-arrays" => run.mem_limits.max_arrays = Some(args.parsed(&arg)?),
+            "--trace" => run.trace = Some(args.value(&arg)?),
+            "--trace-ip" => {
+                let value = args.value(&arg)?;
+                run.trace_filter
+                    .parse_ips(&value)
+                    .map_err(|e| format!("{} {}: {}", arg, value, e))?;
+            }
+            "--trace-op" => {
+                let value = args.value(&arg)?;
+                run.trace_filter
+                    .parse_ops(&value)
+                    .map_err(|e| format!("{} {}: {}", arg, value, e))?;
+            }
+            "--jit" => {
+                args.no_value(&arg)?;
+                run.jit = true;
+            }
+            "--sanitize" => {
+                args.no_value(&arg)?;
+                run.sanitize = true;
+            }
+            "--max-steps" => run.max_steps = Some(args.parsed(&arg)?),
+            "--timeout" => {
+                let secs: f64 = args.parsed(&arg)?;
+                let timeout = Duration::try_from_secs_f64(secs)
+                    .map_err(|e| format!("{} {}: {}", arg, secs, e))?;
+                run.timeout = Some(timeout);
+            }
+            "--stats" => {
+                args.no_value(&arg)?;
+                run.stats = true;
+            }
+            "--profile" => run.profile = Some(args.value(&arg)?),
+            "--profile-folded" => run.profile_folded = Some(args.value(&arg)?),
+            "--save-on-halt" => run.save_on_halt = Some(args.value(&arg)?),
+            "--snapshot-at-step" => run.snapshots.push(args.parsed(&arg)?),
+            "--restore" if program.is_none() => {
+                program = Some(args.value(&arg)?);
+                run.restore = true;
+            }
+            "--restore" => return Err("--restore replaces the program".to_string()),
+            _ if arg.starts_with('-') => return Err(format!("unknown option {}", arg)),
+            _ if program.is_none() => program = Some(arg),
+            _ if run.restore => return Err("--restore replaces the program".to_string()),
+            _ => return Err(format!("unexpected argument {}", arg)),
+        }
+        first = false;
+    }
+
+    run.program = program.ok_or_else(|| "no program given".to_string())?;
+    let profile = run.profile.is_some() || run.profile_folded.is_some();
+    if [run.trace.is_some(), profile, run.jit, run.sanitize]
+        .iter()
+        .filter(|&&b| b)
+        .count()
+        > 1
+    {
+        return Err("--trace, --profile, --jit and --sanitize can't be combined".to_string());
+    }
+    if debug {
+        Ok(Command::Debug(run))
+    } else {
+        if run.inputs.is_empty() {
+            run.then_stdin = true;
+        }
+        Ok(Command::Run(run))
+    }
+}
+
+fn parse_bench<I: Iterator<Item = String>>(mut args: Args<I>) -> Result<Command, String> {
+    let mut bench = BenchOptions {
+        baseline: None,
+        save: None,
+        tolerance: 5.0,
+        alloc: Strategy::default(),
+        jit: false,
+        files: Vec::new(),
+    };
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-h" | "--help" => return Ok(Command::Help),
+            "--baseline" => bench.baseline = Some(args.value(&arg)?),
+            "--save" => bench.save = Some(args.value(&arg)?),
+            "--tolerance" => bench.tolerance = args.parsed(&arg)?,
+            "--alloc" => bench.alloc = args.parsed(&arg)?,
+            "--jit" => {
+                args.no_value(&arg)?;
+                bench.jit = true;
+            }
+            _ if arg.starts_with('-') => return Err(format!("unknown option {}", arg)),
+            _ => bench.files.push(arg),
+        }
+    }
+    Ok(Command::Bench(bench))
+}
+
+fn parse_disasm<I: Iterator<Item = String>>(mut args: Args<I>) -> Result<Command, String> {
+    let mut program = None;
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-h" | "--help" => return Ok(Command::Help),
+            _ if arg.starts_with('-') => return Err(format!("unknown option {}", arg)),
+            _ if program.is_none() => program = Some(arg),
+            _ => return Err(format!("unexpected argument {}", arg)),
+        }
+    }
+    program
+        .map(Command::Disasm)
+        .ok_or_else(|| "no program given".to_string())
+}
+
+/// Parses `asm` or `pack`, which turn one file into an image. The output
+/// file name defaults to the input's with the extension of the command.
+fn parse_file<I: Iterator<Item = String>>(mut args: Args<I>, asm: bool) -> Result<Command, String> {
+    let mut source = None;
+    let mut output = None;
+    let mut image = ImageOptions::default();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-h" | "--help" => return Ok(Command::Help),
+            "-o" | "--output" => output = Some(args.value(&arg)?),
+            "--entry" => {
+                let value = args.value(&arg)?;
+                image.entry = Some(parse_num(&value).map_err(|e| format!("{} {}", arg, e))?);
+            }
+            "--reg" => {
+                let value = args.value(&arg)?;
+                image
+                    .regs
+                    .push(parse_reg(&value).map_err(|e| format!("{} {}: {}", arg, value, e))?);
+            }
+            "--array" => image.arrays.push(args.value(&arg)?),
+            "--input" => image.inputs.push(args.value(&arg)?),
+            "--symbols" if asm => {
+                args.no_value(&arg)?;
+                image.symbols = true;
+            }
+            _ if arg.starts_with('-') => return Err(format!("unknown option {}", arg)),
+            _ if source.is_none() => source = Some(arg),
+            _ => return Err(format!("unexpected argument {}", arg)),
+        }
+    }
+
+    let source = source.ok_or_else(|| "no input file given".to_string())?;
+    let output = output.unwrap_or_else(|| {
+        Path::new(&source)
+            .with_extension(if asm { "um" } else { "umz" })
+            .to_string_lossy()
+            .into_owned()
+    });
+    if output == source {
+        return Err(format!("{} would be overwritten, use -o", source));
+    }
+    let opts = FileOptions {
+        input: source,
+        output,
+        image,
+    };
+    Ok(if asm {
+        Command::Asm(opts)
+    } else {
+        Command::Pack(opts)
+    })
+}
+
+/// Parses `rN=VALUE`.
+fn parse_reg(s: &str) -> Result<(Reg, u32), String> {
+    let (reg, value) = s
+        .split_once('=')
+        .ok_or_else(|| "expected rN=VALUE".to_string())?;
+    let reg = match reg.strip_prefix('r').map(str::parse) {
+        Some(Ok(r)) if r < 8 => r,
+        _ => return Err(format!("bad register {}", reg)),
+    };
+    Ok((reg, parse_num(value)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_str(args: &str) -> Result<Command, String> {
+        parse(args.split_whitespace().map(String::from))
+    }
+
+    #[test]
+    fn run_options() {
+        let cmd = parse_str("--input a --input=b --max-steps 10 --trace t.log prog.um").unwrap();
+        match cmd {
+            Command::Run(run) => {
+                assert_eq!(run.program, "prog.um");
+                assert_eq!(run.quarantine, None);
+                assert_eq!(run.alloc, None);
+                assert_eq!(run.inputs, ["a", "b"]);
+                assert!(!run.then_stdin);
+                assert_eq!(run.trace.as_deref(), Some("t.log"));
+                assert_eq!(run.max_steps, Some(10));
+                assert_eq!(run.timeout, None);
+            }
+            _ => panic!("expected run command"),
+        }
+        match parse_str("--quarantine=forever --alloc lifo --max-words 1000 prog.um").unwrap() {
+            Command::Run(run) => {
+                assert_eq!(run.quarantine, Some(Quarantine::Forever));
+                assert_eq!(run.alloc, Some(Strategy::Lifo));
+                assert_eq!(run.mem_limits.max_words, Some(1000));
+                assert_eq!(run.mem_limits.max_arrays, None);
+            }
+            _ => panic!("expected run command"),
+        }
+    }
+
+    #[test]
+    fn snapshots() {
+        let cmd = parse_str("--restore s.snap --snapshot-at-step 10 --snapshot-at-step=5").unwrap();
+        match cmd {
+            Command::Run(run) => {
+                assert_eq!(run.program, "s.snap");
+                assert!(run.restore);
+                assert_eq!(run.snapshots, [10, 5]);
+            }
+            _ => panic!("expected run command"),
+        }
+        assert!(parse_str("--restore s.snap prog.um").is_err());
+        assert!(parse_str("prog.um --restore s.snap").is_err());
+    }
+
+    #[test]
+    fn timeout() {
+        match parse_str("--timeout=1.5 prog.um").unwrap() {
+            Command::Run(run) => assert_eq!(run.timeout, Some(Duration::from_millis(1500))),
+            _ => panic!("expected run command"),
+        }
+    }
+
+    #[test]
+    fn backends() {
+        match parse_str("--jit prog.um").unwrap() {
+            Command::Run(run) => assert!(run.jit && run.trace.is_none()),
+            _ => panic!("expected run command"),
+        }
+        match parse_str("--profile - --profile-folded=p.folded prog.um").unwrap() {
+            Command::Run(run) => {
+                assert_eq!(run.profile.as_deref(), Some("-"));
+                assert_eq!(run.profile_folded.as_deref(), Some("p.folded"));
+            }
+            _ => panic!("expected run command"),
+        }
+        assert!(parse_str("--profile p.txt --trace t.log prog.um").is_err());
+        assert!(parse_str("--profile-folded p.txt --jit prog.um").is_err());
+        match parse_str("--sanitize prog.um").unwrap() {
+            Command::Run(run) => assert!(run.sanitize && !run.jit),
+            _ => panic!("expected run command"),
+        }
+        assert!(parse_str("--sanitize --jit prog.um").is_err());
+    }
+
+    #[test]
+    fn stdin_by_default() {
+        match parse_str("prog.um").unwrap() {
+            Command::Run(run) => assert!(run.then_stdin),
+            _ => panic!("expected run command"),
+        }
+    }
+
+    #[test]
+    fn debug_keeps_stdin() {
+        match parse_str("debug prog.um").unwrap() {
+            Command::Debug(run) => assert!(!run.then_stdin),
+            _ => panic!("expected debug command"),
+        }
+    }
+
+    #[test]
+    fn bench_options() {
+        match parse_str("bench --tolerance 2.5 --alloc=lifo sandmark.umz").unwrap() {
+            Command::Bench(bench) => {
+                assert_eq!(bench.tolerance, 2.5);
+                assert_eq!(bench.alloc, Strategy::Lifo);
+                assert!(!bench.jit);
+                assert_eq!(bench.files, ["sandmark.umz"]);
+            }
+            _ => panic!("expected bench command"),
+        }
+        match parse_str("bench --jit").unwrap() {
+            Command::Bench(bench) => assert!(bench.jit && bench.files.is_empty()),
+            _ => panic!("expected bench command"),
+        }
+    }
+
+    #[test]
+    fn disasm() {
+        match parse_str("disasm codex.umz").unwrap() {
+            Command::Disasm(program) => assert_eq!(program, "codex.umz"),
+            _ => panic!("expected disasm command"),
+        }
+        assert!(parse_str("disasm").is_err());
+        assert!(parse_str("disasm a.um b.um").is_err());
+    }
+
+    #[test]
+    fn asm() {
+        match parse_str("asm hello.s").unwrap() {
+            Command::Asm(asm) => {
+                assert_eq!(asm.input, "hello.s");
+                assert_eq!(asm.output, "hello.um");
+            }
+            _ => panic!("expected asm command"),
+        }
+        match parse_str("asm -o out.img hello.s").unwrap() {
+            Command::Asm(asm) => assert_eq!(asm.output, "out.img"),
+            _ => panic!("expected asm command"),
+        }
+        assert!(parse_str("asm hello.um").is_err());
+    }
+
+    #[test]
+    fn pack() {
+        match parse_str("pack prog.um").unwrap() {
+            Command::Pack(pack) => assert_eq!(pack.output, "prog.umz"),
+            _ => panic!("expected pack command"),
+        }
+        assert!(parse_str("pack sandmark.umz").is_err());
+        assert!(parse_str("pack").is_err());
+    }
+
+    #[test]
+    fn image_options() {
+        let cmd = parse_str(
+            "pack --entry 0x10 --reg r1=5 --reg=r7=0xff --array a.um --input login umix.um",
+        )
+        .unwrap();
+        match cmd {
+            Command::Pack(pack) => {
+                assert_eq!(pack.image.entry, Some(16));
+                assert_eq!(pack.image.regs, [(1, 5), (7, 255)]);
+                assert_eq!(pack.image.arrays, ["a.um"]);
+                assert_eq!(pack.image.inputs, ["login"]);
+                assert!(!pack.image.symbols);
+            }
+            _ => panic!("expected pack command"),
+        }
+        match parse_str("asm --symbols hello.s").unwrap() {
+            Command::Asm(asm) => assert!(asm.image.symbols && !asm.image.is_empty()),
+            _ => panic!("expected asm command"),
+        }
+        assert!(parse_str("pack --symbols umix.um").is_err());
+        assert!(parse_str("pack --reg r8=1 umix.um").is_err());
+        assert!(parse_str("pack --reg r1 umix.um").is_err());
+        assert!(parse_str("pack --entry x umix.um").is_err());
+    }
+
+    #[test]
+    fn errors() {
+        assert!(parse_str("").is_err());
+        assert!(parse_str("--max-steps lots prog.um").is_err());
+        assert!(parse_str("--stats=yes prog.um").is_err());
+        assert!(parse_str("--jit=1 prog.um").is_err());
+        assert!(parse_str("--alloc best prog.um").is_err());
+        assert!(parse_str("--quarantine never prog.um").is_err());
+        assert!(parse_str("--max-arrays -1 prog.um").is_err());
+        assert!(parse_str("--bogus prog.um").is_err());
+        assert!(parse_str("--trace-op jump prog.um").is_err());
+        assert!(parse_str("--timeout -1 prog.um").is_err());
+        assert!(parse_str("--timeout 1e20 prog.um").is_err());
+        assert!(parse_str("--timeout inf prog.um").is_err());
+        assert!(parse_str("a.um b.um").is_err());
+        assert_eq!(
+            parse_str("--max-steps").unwrap_err(),
+            "--max-steps needs a value"
+        );
+    }
+}
@@ -0,0 +1,148 @@
+use crate::op::Op;
+
+/// Array 0 decoded into ops ahead of time, so that the interpreter loop
+/// doesn't have to parse the same words over and over again.
+///
+/// Words with an invalid opcode are kept as `None`, they only become a
+/// fault if the machine actually gets to execute them.
+#[derive(Clone, Default)]
+pub(crate) struct Code {
+    ops: Vec<Slot>,
+}
+
+/// An op in 8 bytes rather than the 32 of an `Op`: the opcode, registers
+/// and the `Mov` immediate, unpacked from the word but not widened.
+#[derive(Clone, Copy)]
+struct Slot {
+    opcode: u8,
+    a: u8,
+    b: u8,
+    c: u8,
+    val: u32,
+}
+
+const INVALID: u8 = 0xFF;
+
+impl Slot {
+    /// Unpacks `word` the way `Op::parse` does.
+    fn new(word: u32) -> Self {
+        match word >> 28 {
+            13 => Slot {
+                opcode: 13,
+                a: ((word >> 25) & 7) as u8,
+                b: 0,
+                c: 0,
+                val: word & 0x01FF_FFFF,
+            },
+            opcode => Slot {
+                opcode: if opcode < 13 { opcode as u8 } else { INVALID },
+                a: ((word >> 6) & 7) as u8,
+                b: ((word >> 3) & 7) as u8,
+                c: (word & 7) as u8,
+                val: 0,
+            },
+        }
+    }
+
+    #[inline]
+    fn op(self) -> Option<Op> {
+        let (a, b, c) = (
+            usize::from(self.a),
+            usize::from(self.b),
+            usize::from(self.c),
+        );
+        let op = match self.opcode {
+            0 => Op::CondMov(a, b, c),
+            1 => Op::MemRead(a, b, c),
+            2 => Op::MemWrite(a, b, c),
+            3 => Op::Add(a, b, c),
+            4 => Op::Mul(a, b, c),
+            5 => Op::Div(a, b, c),
+            6 => Op::Nand(a, b, c),
+            7 => Op::Halt,
+            8 => Op::Alloc(b, c),
+            9 => Op::Free(c),
+            10 => Op::Output(c),
+            11 => Op::Input(c),
+            12 => Op::LoadProgram(b, c),
+            13 => Op::Mov(a, self.val),
+            _ => return None,
+        };
+        Some(op)
+    }
+}
+
+impl Code {
+    pub(crate) fn decode(words: &[u32]) -> Self {
+        Code {
+            ops: words.iter().map(|&w| Slot::new(w)).collect(),
+        }
+    }
+
+    /// Returns decoded op at `ip`, `None` if it is out of bounds or invalid.
+    #[inline]
+    pub(crate) fn get(&self, ip: u32) -> Option<Op> {
+        self.ops.get(ip as usize)?.op()
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.ops.len()
+    }
+
+    /// Keeps the cache in sync with a write to array 0.
+    pub(crate) fn update(&mut self, offset: u32, word: u32) {
+        if let Some(slot) = self.ops.get_mut(offset as usize) {
+            *slot = Slot::new(word);
+        }
+    }
+
+    /// Drops everything, next `get` will miss.
+    pub(crate) fn clear(&mut self) {
+        self.ops.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decode_and_update() {
+        let mut code = Code::decode(&[0x7000_0000, 0xE000_0000]);
+        assert_eq!(code.get(0), Some(Op::Halt));
+        assert_eq!(code.get(1), None);
+        assert_eq!(code.get(2), None);
+
+        code.update(1, 0xD200_0005);
+        assert_eq!(code.get(1), Some(Op::Mov(1, 5)));
+
+        code.clear();
+        assert_eq!(code.len(), 0);
+    }
+
+    #[test]
+    fn every_op() {
+        let words = [
+            0x0000_01D1, // condmov r7, r2, r1
+            0x1000_0053,
+            0x2000_0188,
+            0x3000_0001,
+            0x4000_0002,
+            0x5000_0003,
+            0x6000_0004,
+            0x7000_0000,
+            0x8000_0012,
+            0x9000_0005,
+            0xA000_0006,
+            0xB000_0007,
+            0xC000_0038,
+            0xDFFF_FFFF, // mov r7, 0x1ffffff
+            0xF000_0000,
+        ];
+        let code = Code::decode(&words);
+        for (ip, &word) in words.iter().enumerate() {
+            assert_eq!(code.get(ip as u32), Op::parse(word).ok());
+        }
+        assert_eq!(std::mem::size_of::<Slot>(), 8);
+    }
+}
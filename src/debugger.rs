@@ -0,0 +1,550 @@
+use crate::disasm::Line;
+use crate::io::Io;
+use crate::machine::{Fault, Machine, Status};
+use crate::op::Op;
+use crate::reverse::{History, Replay};
+use std::collections::BTreeSet;
+use std::io;
+use std::io::Write;
+
+pub const HELP: &str = "\
+commands:
+  s, step [N]          execute N instructions (1 by default)
+  c, continue          run until a breakpoint, watchpoint or the end
+  rs, reverse-step [N] go N instructions back (1 by default)
+  rc, reverse-continue go back to the previous breakpoint or watchpoint hit
+  b, break IP          set a breakpoint at IP
+  d, delete IP         delete the breakpoint at IP
+  w, watch ARR [OFF]   stop on writes to array ARR or just to ARR[OFF]
+  unwatch ARR [OFF]    delete a watchpoint
+  r, regs              show registers
+  x ARR OFF [N]        show N words of array ARR starting at OFF
+  l, list [IP]         disassemble around IP (current ip by default)
+  i, info              show breakpoints, watchpoints and the loaded program
+  q, quit              leave the debugger
+  h, help              show this help
+numbers are decimal or hex with 0x prefix, empty line repeats the last command
+";
+
+/// Why the debugger gave control back.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stop {
+    /// Requested number of instructions has been executed.
+    Step,
+    Breakpoint(u32),
+    /// An instruction wrote to a watched location.
+    Watchpoint {
+        addr: u32,
+        offset: u32,
+        old: u32,
+        new: u32,
+    },
+    /// The machine stopped by itself.
+    Exited(Status),
+    /// Going back has reached the earliest step in the history.
+    HistoryStart,
+    Fault(Fault),
+}
+
+/// Memory location to watch for writes, `offset` of `None` covers the
+/// whole array.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Watch {
+    pub addr: u32,
+    pub offset: Option<u32>,
+}
+
+impl Watch {
+    fn covers(&self, addr: u32, offset: u32) -> bool {
+        self.addr == addr && self.offset.is_none_or(|o| o == offset)
+    }
+}
+
+/// Interactive debugger, which can also run the program backwards by
+/// re-executing it from checkpoints.
+pub struct Debugger<T> {
+    um: Machine<Replay<T>>,
+    history: History,
+    breakpoints: BTreeSet<u32>,
+    watchpoints: Vec<Watch>,
+    last: String,
+}
+
+impl<T: Io> Debugger<T> {
+    pub fn new(um: Machine<T>) -> Self {
+        let um = um.map_io(Replay::new);
+        Debugger {
+            history: History::new(&um),
+            um,
+            breakpoints: BTreeSet::new(),
+            watchpoints: Vec::new(),
+            last: String::new(),
+        }
+    }
+
+    pub fn machine(&self) -> &Machine<Replay<T>> {
+        &self.um
+    }
+
+    pub fn into_machine(self) -> Machine<T> {
+        self.um.map_io(Replay::into_inner)
+    }
+
+    /// Returns `false` if there already was a breakpoint at `ip`.
+    pub fn add_breakpoint(&mut self, ip: u32) -> bool {
+        self.breakpoints.insert(ip)
+    }
+
+    /// Returns `false` if there was no breakpoint at `ip`.
+    pub fn remove_breakpoint(&mut self, ip: u32) -> bool {
+        self.breakpoints.remove(&ip)
+    }
+
+    pub fn watch(&mut self, watch: Watch) {
+        if !self.watchpoints.contains(&watch) {
+            self.watchpoints.push(watch);
+        }
+    }
+
+    /// Returns `false` if there was no such watchpoint.
+    pub fn unwatch(&mut self, watch: Watch) -> bool {
+        let len = self.watchpoints.len();
+        self.watchpoints.retain(|w| *w != watch);
+        self.watchpoints.len() != len
+    }
+
+    /// Executes a single instruction.
+    pub fn step(&mut self) -> Stop {
+        self.history.record(&self.um);
+        let watched = watched_write(&self.um, &self.watchpoints);
+        match self.um.step() {
+            Ok(Status::Running) => match watched {
+                Some((addr, offset, old)) => Stop::Watchpoint {
+                    addr,
+                    offset,
+                    old,
+                    new: *self.um.mem().read(addr, offset).unwrap_or(&old),
+                },
+                None => Stop::Step,
+            },
+            Ok(status) => Stop::Exited(status),
+            Err(fault) => Stop::Fault(fault),
+        }
+    }
+
+    /// Runs until something interesting happens. At least one instruction is
+    /// executed, so continuing from a breakpoint doesn't stop at it again.
+    pub fn cont(&mut self) -> Stop {
+        loop {
+            match self.step() {
+                Stop::Step if self.breakpoints.contains(&self.um.ip()) => {
+                    return Stop::Breakpoint(self.um.ip())
+                }
+                Stop::Step => continue,
+                stop => return stop,
+            }
+        }
+    }
+
+    /// Goes `n` instructions back, or as far back as the history goes.
+    pub fn reverse_step(&mut self, n: u64) -> Stop {
+        let start = self.history.start();
+        let clamped = self.um.steps() < start.saturating_add(n);
+        let step = if clamped { start } else { self.um.steps() - n };
+        match self.history.seek(&mut self.um, step) {
+            Ok(()) if clamped => Stop::HistoryStart,
+            Ok(()) => Stop::Step,
+            Err(fault) => Stop::Fault(fault),
+        }
+    }
+
+    /// Goes back to the latest point where `cont` would have stopped, or to
+    /// the start of the history if there is none.
+    pub fn reverse_cont(&mut self) -> Stop {
+        let now = self.um.steps();
+        let breakpoints = &self.breakpoints;
+        let watchpoints = &self.watchpoints;
+        // Breakpoints stop before the instruction at them, watchpoints right
+        // after the write, that is before the next instruction.
+        let mut watch_hit = false;
+        let found = self.history.find_last(&mut self.um, now, |um| {
+            let at_watch = um.steps() + 1 < now && watched_write(um, watchpoints).is_some();
+            let hit = at_watch || breakpoints.contains(&um.ip());
+            if hit {
+                watch_hit = at_watch;
+            }
+            hit
+        });
+
+        let step = match found {
+            Ok(Some(step)) => step,
+            Ok(None) => self.history.start(),
+            Err(fault) => return Stop::Fault(fault),
+        };
+        if let Err(fault) = self.history.seek(&mut self.um, step) {
+            return Stop::Fault(fault);
+        }
+        match found {
+            // Executes the write once more to report it.
+            Ok(Some(_)) if watch_hit => self.step(),
+            Ok(Some(_)) => Stop::Breakpoint(self.um.ip()),
+            _ => Stop::HistoryStart,
+        }
+    }
+
+    /// Executes one command line, returns `false` when it's time to quit.
+    pub fn command<W: Write>(&mut self, line: &str, out: &mut W) -> io::Result<bool> {
+        let line = match line.trim() {
+            "" => self.last.clone(),
+            line => {
+                self.last = line.to_string();
+                line.to_string()
+            }
+        };
+
+        let mut words = line.split_whitespace();
+        let cmd = match words.next() {
+            Some(cmd) => cmd,
+            None => return Ok(true),
+        };
+        let args: Result<Vec<u32>, String> = words.map(parse_num).collect();
+        let args = match args {
+            Ok(args) => args,
+            Err(e) => {
+                writeln!(out, "{}", e)?;
+                return Ok(true);
+            }
+        };
+
+        match (cmd, &args[..]) {
+            ("s", []) | ("step", []) => self.run(out, 1)?,
+            ("s", [n]) | ("step", [n]) => self.run(out, *n)?,
+            ("c", []) | ("continue", []) => {
+                let stop = self.cont();
+                self.report(out, stop)?;
+            }
+            ("rs", []) | ("reverse-step", []) => {
+                let stop = self.reverse_step(1);
+                self.report(out, stop)?;
+            }
+            ("rs", [n]) | ("reverse-step", [n]) => {
+                let stop = self.reverse_step(u64::from(*n));
+                self.report(out, stop)?;
+            }
+            ("rc", []) | ("reverse-continue", []) => {
+                let stop = self.reverse_cont();
+                self.report(out, stop)?;
+            }
+            ("b", [ip]) | ("break", [ip]) => {
+                self.add_breakpoint(*ip);
+                writeln!(out, "breakpoint at {:08x}", ip)?;
+            }
+            ("d", [ip]) | ("delete", [ip]) => {
+                if !self.remove_breakpoint(*ip) {
+                    writeln!(out, "no breakpoint at {:08x}", ip)?;
+                }
+            }
+            ("w", [addr]) | ("watch", [addr]) => self.watch(Watch {
+                addr: *addr,
+                offset: None,
+            }),
+            ("w", [addr, offset]) | ("watch", [addr, offset]) => self.watch(Watch {
+                addr: *addr,
+                offset: Some(*offset),
+            }),
+            ("unwatch", [addr]) | ("unwatch", [addr, _]) => {
+                let watch = Watch {
+                    addr: *addr,
+                    offset: args.get(1).copied(),
+                };
+                if !self.unwatch(watch) {
+                    writeln!(out, "no such watchpoint")?;
+                }
+            }
+            ("r", []) | ("regs", []) => self.regs(out)?,
+            ("x", [addr, offset]) => self.examine(out, *addr, *offset, 1)?,
+            ("x", [addr, offset, n]) => self.examine(out, *addr, *offset, *n)?,
+            ("l", []) | ("list", []) => self.list(out, self.um.ip())?,
+            ("l", [ip]) | ("list", [ip]) => self.list(out, *ip)?,
+            ("i", []) | ("info", []) => self.info(out)?,
+            ("q", []) | ("quit", []) => return Ok(false),
+            ("h", []) | ("help", []) => write!(out, "{}", HELP)?,
+            _ => writeln!(out, "bad command, try 'help'")?,
+        }
+        Ok(true)
+    }
+
+    fn run<W: Write>(&mut self, out: &mut W, n: u32) -> io::Result<()> {
+        let mut stop = Stop::Step;
+        for _ in 0..n {
+            stop = self.step();
+            if stop != Stop::Step {
+                break;
+            }
+        }
+        self.report(out, stop)
+    }
+
+    fn report<W: Write>(&mut self, out: &mut W, stop: Stop) -> io::Result<()> {
+        // Program output goes first, it's been produced before the stop.
+        if let Err(fault) = self.um.flush() {
+            writeln!(out, "{}", fault)?;
+        }
+
+        match stop {
+            Stop::Step => {}
+            Stop::Breakpoint(ip) => writeln!(out, "breakpoint at {:08x}", ip)?,
+            Stop::Watchpoint {
+                addr,
+                offset,
+                old,
+                new,
+            } => writeln!(
+                out,
+                "watchpoint: [{}][{}] {:08x} -> {:08x}",
+                addr, offset, old, new
+            )?,
+            Stop::Exited(Status::Halted) => {
+                writeln!(out, "halted after {} instructions", self.um.steps())?
+            }
+            Stop::Exited(Status::Eof) => writeln!(out, "waiting for input, but it's over")?,
+            Stop::Exited(Status::Running)
+            | Stop::Exited(Status::BudgetExhausted)
+            | Stop::Exited(Status::TimedOut) => {}
+            Stop::HistoryStart => writeln!(out, "reached the start of the history")?,
+            Stop::Fault(fault) => writeln!(out, "{}", fault)?,
+        }
+        self.location(out, self.um.ip(), "=>")
+    }
+
+    fn location<W: Write>(&self, out: &mut W, ip: u32, mark: &str) -> io::Result<()> {
+        match self.um.mem().read(0, ip) {
+            Ok(&word) => writeln!(out, "{:>2} {}", mark, Line::new(ip, word)),
+            Err(_) => writeln!(out, "{:>2} {:08x}: out of program", mark, ip),
+        }
+    }
+
+    fn regs<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        for (r, val) in self.um.regs().iter().enumerate() {
+            let sep = if r % 4 == 3 { "\n" } else { "  " };
+            write!(out, "r{} {:08x}{}", r, val, sep)?;
+        }
+        writeln!(out, "ip {:08x}  steps {}", self.um.ip(), self.um.steps())
+    }
+
+    fn examine<W: Write>(&self, out: &mut W, addr: u32, offset: u32, n: u32) -> io::Result<()> {
+        let words = match self.um.mem().array(addr) {
+            Ok(words) => words,
+            Err(e) => return writeln!(out, "{}", e),
+        };
+        let start = (offset as usize).min(words.len());
+        let end = start.saturating_add(n as usize).min(words.len());
+        if start == end {
+            return writeln!(
+                out,
+                "offset {} is out of bounds (len: {})",
+                offset,
+                words.len()
+            );
+        }
+        for (i, chunk) in words[start..end].chunks(4).enumerate() {
+            write!(out, "[{}][{:08x}]:", addr, start + i * 4)?;
+            for word in chunk {
+                write!(out, " {:08x}", word)?;
+            }
+            writeln!(out)?;
+        }
+        Ok(())
+    }
+
+    fn list<W: Write>(&self, out: &mut W, ip: u32) -> io::Result<()> {
+        let len = self.um.mem().array(0).map_or(0, |a| a.len() as u32);
+        let start = ip.saturating_sub(5);
+        let end = ip.saturating_add(6).min(len);
+        for at in start..end {
+            let mark = match (at == self.um.ip(), self.breakpoints.contains(&at)) {
+                (true, true) => "*>",
+                (true, false) => "=>",
+                (false, true) => "*",
+                (false, false) => "",
+            };
+            self.location(out, at, mark)?;
+        }
+        Ok(())
+    }
+
+    fn info<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        let origin = self.um.origin();
+        if origin == 0 {
+            writeln!(out, "program: initial image")?;
+        } else {
+            let state = match self.um.mem().array(origin) {
+                Ok(_) => "",
+                Err(_) => " (freed since)",
+            };
+            writeln!(out, "program: loaded from array {}{}", origin, state)?;
+        }
+
+        for ip in &self.breakpoints {
+            writeln!(out, "breakpoint at {:08x}", ip)?;
+        }
+        for w in &self.watchpoints {
+            match w.offset {
+                Some(offset) => writeln!(out, "watchpoint on [{}][{}]", w.addr, offset)?,
+                None => writeln!(out, "watchpoint on [{}]", w.addr)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Location the next instruction is going to write to and its current
+/// value, if it's watched.
+fn watched_write<T: Io>(um: &Machine<T>, watchpoints: &[Watch]) -> Option<(u32, u32, u32)> {
+    if watchpoints.is_empty() {
+        return None;
+    }
+    match um.next_op() {
+        Some(Op::MemWrite(a, b, _)) => {
+            let (addr, offset) = (um.reg(a), um.reg(b));
+            let old = *um.mem().read(addr, offset).ok()?;
+            if watchpoints.iter().any(|w| w.covers(addr, offset)) {
+                Some((addr, offset, old))
+            } else {
+                None
+            }
+        }
+        _ => None,
+    }
+}
+
+/// Parses a decimal or `0x` hex number.
+pub fn parse_num(s: &str) -> Result<u32, String> {
+    let parsed = match s.strip_prefix("0x") {
+        Some(hex) => u32::from_str_radix(hex, 16),
+        None => s.parse(),
+    };
+    parsed.map_err(|_| format!("bad number: {}", s))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::io::Buffer;
+
+    fn debugger(words: &[u32]) -> Debugger<Buffer> {
+        let bytes: Vec<u8> = words.iter().flat_map(|w| w.to_be_bytes()).collect();
+        Debugger::new(Machine::load_with_io(&bytes, Buffer::default()).unwrap())
+    }
+
+    fn run(dbg: &mut Debugger<Buffer>, line: &str) -> String {
+        let mut out = Vec::new();
+        dbg.command(line, &mut out).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    const PROG: &[u32] = &[
+        0xD200_0002, // mov r1, 2
+        0x8000_0011, // alloc r2 <- r1
+        0xD600_0001, // mov r3, 1
+        0x2000_009B, // write [r2 + r3] <- r3
+        0x7000_0000, // halt
+    ];
+
+    #[test]
+    fn breakpoint_and_continue() {
+        let mut dbg = debugger(PROG);
+        run(&mut dbg, "b 2");
+        assert!(run(&mut dbg, "c").contains("breakpoint at 00000002"));
+        assert_eq!(dbg.machine().reg(2), 1);
+        assert!(run(&mut dbg, "c").contains("halted after 5 instructions"));
+    }
+
+    #[test]
+    fn watchpoint() {
+        let mut dbg = debugger(PROG);
+        run(&mut dbg, "watch 1 1");
+        let out = run(&mut dbg, "continue");
+        assert!(out.contains("watchpoint: [1][1] 00000000 -> 00000001"));
+        assert_eq!(dbg.machine().ip(), 4);
+        assert!(run(&mut dbg, "x 1 0 2").contains("[1][00000000]: 00000000 00000001"));
+    }
+
+    #[test]
+    fn step_and_repeat() {
+        let mut dbg = debugger(PROG);
+        run(&mut dbg, "s 2");
+        assert_eq!(dbg.machine().ip(), 2);
+        run(&mut dbg, "");
+        assert_eq!(dbg.machine().ip(), 4);
+        assert!(run(&mut dbg, "regs").contains("r3 00000001"));
+    }
+
+    #[test]
+    fn list_marks_ip() {
+        let mut dbg = debugger(PROG);
+        run(&mut dbg, "s");
+        let out = run(&mut dbg, "l");
+        assert!(out.contains("=> 00000001: 80000011  alloc r2, r1"));
+        assert!(out.contains("00000004: 70000000  halt"));
+    }
+
+    // Counts up in r4 storing every value into [1][0].
+    const COUNTER: &str = "
+                mov r1, 1
+                alloc r2, r1
+                mov r3, 0
+                mov r5, 1
+                mov r6, loop
+        loop:   add r4, r4, r5
+                memwrite r2, r3, r4
+                loadprogram r3, r6
+    ";
+
+    fn counter() -> Debugger<Buffer> {
+        let image = crate::asm::image(&crate::asm::assemble(COUNTER).unwrap());
+        Debugger::new(Machine::load_with_io(&image, Buffer::default()).unwrap())
+    }
+
+    #[test]
+    fn reverse_step() {
+        let mut dbg = counter();
+        run(&mut dbg, "s 10");
+        let regs = *dbg.machine().regs();
+        run(&mut dbg, "rs 3");
+        assert_eq!(dbg.machine().steps(), 7);
+        assert_eq!(dbg.machine().reg(4), 1);
+        run(&mut dbg, "s 3");
+        assert_eq!(dbg.machine().regs(), &regs);
+        assert!(run(&mut dbg, "rs 100").contains("reached the start of the history"));
+        assert_eq!(dbg.machine().steps(), 0);
+    }
+
+    #[test]
+    fn reverse_continue() {
+        let mut dbg = counter();
+        run(&mut dbg, "s 30");
+        run(&mut dbg, "w 1 0");
+        let out = run(&mut dbg, "rc");
+        assert!(out.contains("watchpoint: [1][0] 00000007 -> 00000008"));
+        assert_eq!(dbg.machine().steps(), 28);
+        assert!(run(&mut dbg, "rc").contains("00000006 -> 00000007"));
+
+        run(&mut dbg, "b 5");
+        run(&mut dbg, "s 3");
+        assert!(run(&mut dbg, "rc").contains("breakpoint at 00000005"));
+        assert_eq!(dbg.machine().steps(), 26);
+        run(&mut dbg, "unwatch 1 0");
+        run(&mut dbg, "d 5");
+        assert!(run(&mut dbg, "rc").contains("reached the start of the history"));
+    }
+
+    #[test]
+    fn bad_input() {
+        let mut dbg = debugger(PROG);
+        assert!(run(&mut dbg, "b zz").contains("bad number"));
+        assert!(run(&mut dbg, "frobnicate").contains("bad command"));
+        let mut out = Vec::new();
+        assert!(!dbg.command("q", &mut out).unwrap());
+    }
+}
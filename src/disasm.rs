@@ -0,0 +1,96 @@
+use crate::op::Op;
+use byteorder::BigEndian;
+use byteorder::ReadBytesExt;
+use std::fmt;
+use std::io;
+
+/// One disassembled word of a program.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Line {
+    pub addr: u32,
+    pub word: u32,
+    /// `None` if the opcode is invalid, such words are likely data.
+    pub op: Option<Op>,
+}
+
+impl Line {
+    pub fn new(addr: u32, word: u32) -> Self {
+        Line {
+            addr,
+            word,
+            op: Op::parse(word).ok(),
+        }
+    }
+}
+
+impl fmt::Display for Line {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:08x}: {:08x}  ", self.addr, self.word)?;
+        match self.op {
+            Some(op @ Op::Mov(_, val)) => {
+                let text = op.to_string();
+                write!(f, "{:<28}; {}", text, val)?;
+                match char::from_u32(val) {
+                    Some(chr) if chr.is_ascii_graphic() || chr == ' ' => write!(f, " '{}'", chr),
+                    _ => Ok(()),
+                }
+            }
+            Some(op) => write!(f, "{}", op),
+            None => write!(f, ".word {:#010x}", self.word),
+        }
+    }
+}
+
+/// Disassembles `words` as if they were loaded at address 0.
+pub fn disassemble(words: &[u32]) -> impl Iterator<Item = Line> + '_ {
+    words
+        .iter()
+        .enumerate()
+        .map(|(addr, &word)| Line::new(addr as u32, word))
+}
+
+/// Splits a program image into big-endian words, returns them along with the
+/// number of trailing bytes which don't make up a whole word.
+pub fn words(bytes: &[u8]) -> (Vec<u32>, usize) {
+    let mut reader = io::Cursor::new(bytes);
+    let mut words = Vec::with_capacity(bytes.len() / 4);
+    while let Ok(word) = reader.read_u32::<BigEndian>() {
+        words.push(word);
+    }
+    (words, bytes.len() % 4)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lines() {
+        let lines: Vec<String> = disassemble(&[0xD000_0048, 0xC000_0030, 0xE000_0001])
+            .map(|l| l.to_string())
+            .collect();
+        assert_eq!(
+            lines,
+            [
+                "00000000: d0000048  mov r0, 0x48                ; 72 'H'",
+                "00000001: c0000030  loadprogram r6, r0",
+                "00000002: e0000001  .word 0xe0000001",
+            ]
+        );
+    }
+
+    #[test]
+    fn mov_without_ascii() {
+        let line = Line::new(0x10, 0xD200_1082).to_string();
+        assert_eq!(
+            line,
+            "00000010: d2001082  mov r1, 0x1082              ; 4226"
+        );
+    }
+
+    #[test]
+    fn split_words() {
+        assert_eq!(words(&[0, 0, 0, 1, 0xff]), (vec![1], 1));
+        assert_eq!(words(&[]), (vec![], 0));
+    }
+}
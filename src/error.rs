@@ -0,0 +1,76 @@
+use std::error;
+use std::fmt;
+use std::io;
+
+/// A fault raised by the machine while executing an instruction.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VmError {
+    /// Instruction word has an opcode outside of 0..=13.
+    InvalidOpcode(u32),
+    DivisionByZero,
+    /// `Output` was asked to print a value greater than 255.
+    OutputOutOfRange(u32),
+    ReadOutOfBounds {
+        addr: u32,
+        offset: u32,
+        len: u32,
+    },
+    WriteOutOfBounds {
+        addr: u32,
+        offset: u32,
+        len: u32,
+    },
+    /// Array has been allocated once but it's been freed since.
+    FreedArray(u32),
+    /// Array identifier has never been handed out by `alloc`.
+    UnallocatedArray(u32),
+    /// Attempt to free array 0, which holds the program.
+    FreeZero,
+    DoubleFree(u32),
+    MemoryExhausted,
+    /// Allocation of this many words would go over the limits set on `Mem`.
+    MemoryLimit(u32),
+    /// The I/O backend failed while serving `Output` or `Input`.
+    Io(io::ErrorKind),
+}
+
+impl fmt::Display for VmError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            VmError::InvalidOpcode(code) => write!(f, "unexpected op code: {}", code),
+            VmError::DivisionByZero => write!(f, "division by zero"),
+            VmError::OutputOutOfRange(chr) => write!(f, "character for output > 255: {}", chr),
+            VmError::ReadOutOfBounds { addr, offset, len } => write!(
+                f,
+                "read: offset {} is out of bounds for address {} (len: {})",
+                offset, addr, len
+            ),
+            VmError::WriteOutOfBounds { addr, offset, len } => write!(
+                f,
+                "write: offset {} is out of bounds for address {} (len: {})",
+                offset, addr, len
+            ),
+            VmError::FreedArray(addr) => write!(f, "address {} has been deallocated", addr),
+            VmError::UnallocatedArray(addr) => {
+                write!(f, "address {} has not been allocated", addr)
+            }
+            VmError::FreeZero => write!(f, "tried to free memory at program location (0)"),
+            VmError::DoubleFree(addr) => {
+                write!(f, "attempt to free address {} which is already free", addr)
+            }
+            VmError::MemoryExhausted => write!(f, "memory exhausted"),
+            VmError::MemoryLimit(size) => {
+                write!(f, "allocation of {} words is over the memory limit", size)
+            }
+            VmError::Io(kind) => write!(f, "i/o error: {}", kind),
+        }
+    }
+}
+
+impl error::Error for VmError {}
+
+impl From<io::Error> for VmError {
+    fn from(e: io::Error) -> Self {
+        VmError::Io(e.kind())
+    }
+}
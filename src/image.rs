@@ -0,0 +1,553 @@
+//! Program images as they are stored in files: big-endian words, possibly
+//! compressed, or a container which also says how to start the program.
+//!
+//! Compressed images are recognised by their magic numbers, so a raw image
+//! starting with one of them can't be loaded as is. gzip, zstd and xz need
+//! the features of the same names. The packed format is our own and always
+//! available, it stores runs of repeated words which images are full of:
+//!
+//! - magic `UMZ\x01`,
+//! - packed words: their number as a big-endian u32, then chunks of a
+//!   control byte `c` followed by `c + 1` words, or if the top bit is set,
+//!   by a single word repeated `(c & 0x7f) + 1` times.
+//!
+//! The container, which may be compressed in turn, holds an `Image`. All
+//! numbers are big-endian u32s:
+//!
+//! - magic `UMIMAGE\0` and version,
+//! - entry ip and the 8 registers,
+//! - the program as packed words,
+//! - number of arrays, then each as packed words,
+//! - length of the input script and its bytes,
+//! - number of symbols, then each as address, length of the name and the
+//!   name in UTF-8.
+
+use byteorder::{BigEndian, ByteOrder, ReadBytesExt, WriteBytesExt};
+use std::borrow::Cow;
+use std::fmt;
+use std::io;
+use std::io::Write;
+
+pub const PACKED_MAGIC: &[u8; 4] = b"UMZ\x01";
+pub const CONTAINER_MAGIC: &[u8; 8] = b"UMIMAGE\0";
+pub const CONTAINER_VERSION: u32 = 1;
+
+// Longest chunk in a packed image.
+const CHUNK: usize = 128;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Raw,
+    Packed,
+    Gzip,
+    Zstd,
+    Xz,
+    Container,
+}
+
+impl Format {
+    pub fn detect(bytes: &[u8]) -> Self {
+        if bytes.starts_with(PACKED_MAGIC) {
+            Format::Packed
+        } else if bytes.starts_with(CONTAINER_MAGIC) {
+            Format::Container
+        } else if bytes.starts_with(&[0x1f, 0x8b]) {
+            Format::Gzip
+        } else if bytes.starts_with(&[0x28, 0xb5, 0x2f, 0xfd]) {
+            Format::Zstd
+        } else if bytes.starts_with(&[0xfd, b'7', b'z', b'X', b'Z', 0]) {
+            Format::Xz
+        } else {
+            Format::Raw
+        }
+    }
+}
+
+impl fmt::Display for Format {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Format::Raw => "raw",
+            Format::Packed => "packed",
+            Format::Gzip => "gzip",
+            Format::Zstd => "zstd",
+            Format::Xz => "xz",
+            Format::Container => "container",
+        };
+        f.write_str(name)
+    }
+}
+
+fn invalid<E: Into<Box<dyn std::error::Error + Send + Sync>>>(e: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e)
+}
+
+#[cfg(any(feature = "gzip", feature = "zstd", feature = "xz"))]
+fn corrupt<E: fmt::Display>(format: Format, e: E) -> io::Error {
+    invalid(format!("truncated or corrupt {} image: {}", format, e))
+}
+
+#[cfg(not(all(feature = "gzip", feature = "zstd", feature = "xz")))]
+fn unsupported(format: Format) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::Unsupported,
+        format!(
+            "{} compressed image, needs a build with the {} feature",
+            format, format
+        ),
+    )
+}
+
+/// Converts a raw image to words.
+pub fn words(bytes: &[u8]) -> io::Result<Vec<u32>> {
+    if !bytes.len().is_multiple_of(4) {
+        return Err(invalid(format!(
+            "truncated image: {} bytes isn't a whole number of words",
+            bytes.len()
+        )));
+    }
+    Ok(bytes.chunks_exact(4).map(BigEndian::read_u32).collect())
+}
+
+/// Decompresses an image in any of the formats, raw images and containers
+/// are returned as they are.
+pub fn decompress(bytes: &[u8]) -> io::Result<Cow<'_, [u8]>> {
+    let raw = match Format::detect(bytes) {
+        Format::Raw | Format::Container => return Ok(Cow::Borrowed(bytes)),
+        Format::Packed => unpack(bytes)?
+            .iter()
+            .flat_map(|word| word.to_be_bytes())
+            .collect(),
+        Format::Gzip => gunzip(bytes)?,
+        Format::Zstd => unzstd(bytes)?,
+        Format::Xz => unxz(bytes)?,
+    };
+    Ok(Cow::Owned(raw))
+}
+
+/// Writes `words` in the packed format.
+pub fn pack(words: &[u32]) -> Vec<u8> {
+    let mut out = PACKED_MAGIC.to_vec();
+    write_words(&mut out, words).expect("writing to a Vec");
+    out
+}
+
+fn unpack(bytes: &[u8]) -> io::Result<Vec<u32>> {
+    let mut r = &bytes[PACKED_MAGIC.len()..];
+    let words = read_words(&mut r)?;
+    if !r.is_empty() {
+        return Err(invalid("corrupt packed image: bytes after the last word"));
+    }
+    Ok(words)
+}
+
+fn write_words<W: Write>(w: &mut W, words: &[u32]) -> io::Result<()> {
+    fn literals<W: Write>(w: &mut W, words: &[u32]) -> io::Result<()> {
+        for chunk in words.chunks(CHUNK) {
+            w.write_u8((chunk.len() - 1) as u8)?;
+            for &word in chunk {
+                w.write_u32::<BigEndian>(word)?;
+            }
+        }
+        Ok(())
+    }
+
+    w.write_u32::<BigEndian>(words.len() as u32)?;
+    let (mut start, mut i) = (0, 0);
+    while i < words.len() {
+        let run = words[i..]
+            .iter()
+            .take(CHUNK)
+            .take_while(|&&w| w == words[i])
+            .count();
+        if run < 2 {
+            i += 1;
+            continue;
+        }
+        literals(w, &words[start..i])?;
+        w.write_u8(0x80 | (run - 1) as u8)?;
+        w.write_u32::<BigEndian>(words[i])?;
+        i += run;
+        start = i;
+    }
+    literals(w, &words[start..])
+}
+
+fn truncated(_: io::Error) -> io::Error {
+    invalid("truncated image")
+}
+
+fn read_words(r: &mut &[u8]) -> io::Result<Vec<u32>> {
+    let len = r.read_u32::<BigEndian>().map_err(truncated)? as usize;
+    // The length may be garbage, let the words prove it.
+    let mut words = Vec::with_capacity(len.min(r.len()));
+    while words.len() < len {
+        let c = r.read_u8().map_err(truncated)?;
+        let n = usize::from(c & 0x7f) + 1;
+        if c & 0x80 != 0 {
+            let word = r.read_u32::<BigEndian>().map_err(truncated)?;
+            words.resize(words.len() + n, word);
+        } else {
+            for _ in 0..n {
+                words.push(r.read_u32::<BigEndian>().map_err(truncated)?);
+            }
+        }
+    }
+    if words.len() != len {
+        return Err(invalid(format!(
+            "corrupt image: {} words where {} were expected",
+            words.len(),
+            len
+        )));
+    }
+    Ok(words)
+}
+
+fn read_bytes(r: &mut &[u8]) -> io::Result<Vec<u8>> {
+    let len = r.read_u32::<BigEndian>().map_err(truncated)? as usize;
+    if r.len() < len {
+        return Err(invalid("truncated image"));
+    }
+    let (bytes, rest) = r.split_at(len);
+    *r = rest;
+    Ok(bytes.to_vec())
+}
+
+/// Names of addresses in a program, sorted by address.
+pub type Symbols = Vec<(u32, String)>;
+
+/// A program along with how the machine is set up to run it.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Image {
+    pub program: Vec<u32>,
+    /// Where execution starts.
+    pub entry: u32,
+    pub regs: [u32; 8],
+    /// Allocated as arrays 1, 2 and so on before the program starts.
+    pub arrays: Vec<Vec<u32>>,
+    /// Fed to the program before any other input.
+    pub input: Vec<u8>,
+    pub symbols: Symbols,
+}
+
+impl Image {
+    pub fn new(program: Vec<u32>) -> Self {
+        Image {
+            program,
+            ..Image::default()
+        }
+    }
+
+    /// Tells whether there's more to the image than the program, so that it
+    /// needs to be written as a container.
+    pub fn has_metadata(&self) -> bool {
+        self.entry != 0
+            || self.regs != [0; 8]
+            || !self.arrays.is_empty()
+            || !self.input.is_empty()
+            || !self.symbols.is_empty()
+    }
+
+    /// Reads an image in any of the formats.
+    pub fn read(bytes: &[u8]) -> io::Result<Self> {
+        match Format::detect(bytes) {
+            Format::Packed => return unpack(bytes).map(Image::new),
+            Format::Container => return Image::read_container(bytes),
+            _ => {}
+        }
+        let bytes = decompress(bytes)?;
+        match Format::detect(&bytes) {
+            Format::Container => Image::read_container(&bytes),
+            _ => words(&bytes).map(Image::new),
+        }
+    }
+
+    fn read_container(bytes: &[u8]) -> io::Result<Self> {
+        let mut r = &bytes[CONTAINER_MAGIC.len()..];
+        let version = r.read_u32::<BigEndian>().map_err(truncated)?;
+        if version != CONTAINER_VERSION {
+            return Err(invalid(format!("unsupported image version {}", version)));
+        }
+
+        let entry = r.read_u32::<BigEndian>().map_err(truncated)?;
+        let mut regs = [0; 8];
+        r.read_u32_into::<BigEndian>(&mut regs).map_err(truncated)?;
+        let program = read_words(&mut r)?;
+        let count = r.read_u32::<BigEndian>().map_err(truncated)?;
+        let mut arrays = Vec::new();
+        for _ in 0..count {
+            arrays.push(read_words(&mut r)?);
+        }
+        let input = read_bytes(&mut r)?;
+        let count = r.read_u32::<BigEndian>().map_err(truncated)?;
+        let mut symbols = Vec::new();
+        for _ in 0..count {
+            let addr = r.read_u32::<BigEndian>().map_err(truncated)?;
+            let name = String::from_utf8(read_bytes(&mut r)?)
+                .map_err(|_| invalid("corrupt image: symbol name isn't UTF-8"))?;
+            symbols.push((addr, name));
+        }
+        // Written by anyone, but `Symbols` are sorted and disasm relies on it.
+        symbols.sort_by_key(|&(addr, _)| addr);
+        if !r.is_empty() {
+            return Err(invalid("corrupt image: bytes after the symbols"));
+        }
+
+        Ok(Image {
+            program,
+            entry,
+            regs,
+            arrays,
+            input,
+            symbols,
+        })
+    }
+
+    /// Writes the image as a container.
+    pub fn write<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        w.write_all(CONTAINER_MAGIC)?;
+        w.write_u32::<BigEndian>(CONTAINER_VERSION)?;
+        w.write_u32::<BigEndian>(self.entry)?;
+        for &reg in &self.regs {
+            w.write_u32::<BigEndian>(reg)?;
+        }
+        write_words(w, &self.program)?;
+        w.write_u32::<BigEndian>(self.arrays.len() as u32)?;
+        for array in &self.arrays {
+            write_words(w, array)?;
+        }
+        w.write_u32::<BigEndian>(self.input.len() as u32)?;
+        w.write_all(&self.input)?;
+        w.write_u32::<BigEndian>(self.symbols.len() as u32)?;
+        for (addr, name) in &self.symbols {
+            w.write_u32::<BigEndian>(*addr)?;
+            w.write_u32::<BigEndian>(name.len() as u32)?;
+            w.write_all(name.as_bytes())?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(feature = "gzip")]
+fn gunzip(bytes: &[u8]) -> io::Result<Vec<u8>> {
+    use std::io::Read;
+
+    let mut out = Vec::new();
+    flate2::read::MultiGzDecoder::new(bytes)
+        .read_to_end(&mut out)
+        .map_err(|e| corrupt(Format::Gzip, e))?;
+    Ok(out)
+}
+
+#[cfg(not(feature = "gzip"))]
+fn gunzip(_: &[u8]) -> io::Result<Vec<u8>> {
+    Err(unsupported(Format::Gzip))
+}
+
+#[cfg(feature = "zstd")]
+fn unzstd(bytes: &[u8]) -> io::Result<Vec<u8>> {
+    use std::io::Read;
+
+    let mut out = Vec::new();
+    ruzstd::decoding::StreamingDecoder::new(bytes)
+        .map_err(|e| corrupt(Format::Zstd, e))?
+        .read_to_end(&mut out)
+        .map_err(|e| corrupt(Format::Zstd, e))?;
+    Ok(out)
+}
+
+#[cfg(not(feature = "zstd"))]
+fn unzstd(_: &[u8]) -> io::Result<Vec<u8>> {
+    Err(unsupported(Format::Zstd))
+}
+
+#[cfg(feature = "xz")]
+fn unxz(mut bytes: &[u8]) -> io::Result<Vec<u8>> {
+    let mut out = Vec::new();
+    lzma_rs::xz_decompress(&mut bytes, &mut out).map_err(|e| corrupt(Format::Xz, e))?;
+    Ok(out)
+}
+
+#[cfg(not(feature = "xz"))]
+fn unxz(_: &[u8]) -> io::Result<Vec<u8>> {
+    Err(unsupported(Format::Xz))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const WORDS: [u32; 8] = [0xd000_0041, 0, 0, 0, 0, 7, 7, 0x7000_0000];
+
+    fn raw() -> Vec<u8> {
+        WORDS.iter().flat_map(|w| w.to_be_bytes()).collect()
+    }
+
+    fn decode(bytes: &[u8]) -> io::Result<Vec<u32>> {
+        Image::read(bytes).map(|image| image.program)
+    }
+
+    #[test]
+    fn raw_words() {
+        assert_eq!(Format::detect(&raw()), Format::Raw);
+        assert_eq!(decode(&raw()).unwrap(), WORDS);
+        let err = decode(&raw()[..30]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(
+            err.to_string(),
+            "truncated image: 30 bytes isn't a whole number of words"
+        );
+    }
+
+    #[test]
+    fn packed() {
+        let packed = pack(&WORDS);
+        // Header, a literal, a run of 0s, a run of 7s and a literal.
+        assert_eq!(packed.len(), 8 + 5 + 5 + 5 + 5);
+        assert_eq!(Format::detect(&packed), Format::Packed);
+        assert_eq!(decode(&packed).unwrap(), WORDS);
+        assert_eq!(decompress(&packed).unwrap(), raw());
+
+        let long: Vec<u32> = (0..1000).map(|n| if n < 300 { 0 } else { n }).collect();
+        assert_eq!(decode(&pack(&long)).unwrap(), long);
+        assert_eq!(decode(&pack(&[])).unwrap(), []);
+
+        for len in 1..packed.len() - 1 {
+            assert!(decode(&packed[..len]).is_err());
+        }
+        let mut longer = packed.clone();
+        longer.push(0);
+        assert!(decode(&longer).is_err());
+    }
+
+    fn container() -> Image {
+        Image {
+            program: WORDS.to_vec(),
+            entry: 5,
+            regs: [0, 1, 2, 3, 4, 5, 6, 0xffff_ffff],
+            arrays: vec![vec![0; 300], vec![], vec![1, 2, 3]],
+            input: b"guest\n".to_vec(),
+            symbols: vec![(0, "start".to_string()), (5, "loop".to_string())],
+        }
+    }
+
+    #[test]
+    fn container_roundtrip() {
+        let image = container();
+        assert!(image.has_metadata());
+        assert!(!Image::new(WORDS.to_vec()).has_metadata());
+
+        let mut bytes = Vec::new();
+        image.write(&mut bytes).unwrap();
+        assert_eq!(Format::detect(&bytes), Format::Container);
+        assert_eq!(Image::read(&bytes).unwrap(), image);
+        assert_eq!(decompress(&bytes).unwrap(), bytes);
+
+        let mut plain = Vec::new();
+        Image::new(WORDS.to_vec()).write(&mut plain).unwrap();
+        assert_eq!(Image::read(&plain).unwrap(), Image::new(WORDS.to_vec()));
+    }
+
+    #[test]
+    fn container_symbols_sorted() {
+        let mut image = container();
+        image.symbols = vec![
+            (5, "loop".to_string()),
+            (0, "start".to_string()),
+            (5, "again".to_string()),
+        ];
+        let mut bytes = Vec::new();
+        image.write(&mut bytes).unwrap();
+        let symbols = Image::read(&bytes).unwrap().symbols;
+        assert_eq!(
+            symbols,
+            [
+                (0, "start".to_string()),
+                (5, "loop".to_string()),
+                (5, "again".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn container_errors() {
+        let mut bytes = Vec::new();
+        container().write(&mut bytes).unwrap();
+
+        for len in CONTAINER_MAGIC.len()..bytes.len() {
+            let err = Image::read(&bytes[..len]).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        }
+        let mut longer = bytes.clone();
+        longer.push(0);
+        assert_eq!(
+            Image::read(&longer).unwrap_err().to_string(),
+            "corrupt image: bytes after the symbols"
+        );
+
+        let mut newer = bytes.clone();
+        newer[11] = 2;
+        assert_eq!(
+            Image::read(&newer).unwrap_err().to_string(),
+            "unsupported image version 2"
+        );
+
+        let mut name = bytes.clone();
+        let last = name.len() - 1;
+        name[last] = 0xff;
+        assert_eq!(
+            Image::read(&name).unwrap_err().to_string(),
+            "corrupt image: symbol name isn't UTF-8"
+        );
+    }
+
+    #[cfg(feature = "gzip")]
+    #[test]
+    fn gzip() {
+        use flate2::write::GzEncoder;
+        use std::io::Write;
+
+        let mut enc = GzEncoder::new(Vec::new(), flate2::Compression::default());
+        enc.write_all(&raw()).unwrap();
+        let gz = enc.finish().unwrap();
+        assert_eq!(Format::detect(&gz), Format::Gzip);
+        assert_eq!(decode(&gz).unwrap(), WORDS);
+        assert!(decode(&gz[..gz.len() - 4]).is_err());
+
+        let mut bytes = Vec::new();
+        container().write(&mut bytes).unwrap();
+        let mut enc = GzEncoder::new(Vec::new(), flate2::Compression::default());
+        enc.write_all(&bytes).unwrap();
+        assert_eq!(Image::read(&enc.finish().unwrap()).unwrap(), container());
+    }
+
+    #[cfg(feature = "zstd")]
+    #[test]
+    fn zstd() {
+        use ruzstd::encoding::{compress_to_vec, CompressionLevel};
+
+        let zst = compress_to_vec(&raw()[..], CompressionLevel::Fastest);
+        assert_eq!(Format::detect(&zst), Format::Zstd);
+        assert_eq!(decode(&zst).unwrap(), WORDS);
+        assert!(decode(&zst[..zst.len() - 4]).is_err());
+    }
+
+    #[cfg(feature = "xz")]
+    #[test]
+    fn xz() {
+        let mut xz = Vec::new();
+        lzma_rs::xz_compress(&mut &raw()[..], &mut xz).unwrap();
+        assert_eq!(Format::detect(&xz), Format::Xz);
+        assert_eq!(decode(&xz).unwrap(), WORDS);
+        assert!(decode(&xz[..xz.len() - 4]).is_err());
+    }
+
+    #[cfg(not(feature = "gzip"))]
+    #[test]
+    fn unsupported_format() {
+        let err = decode(&[0x1f, 0x8b, 8, 0]).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "gzip compressed image, needs a build with the gzip feature"
+        );
+    }
+}
@@ -0,0 +1,334 @@
+use std::collections::VecDeque;
+use std::io;
+use std::io::{BufRead, BufWriter, Stdin, Stdout, Write};
+use std::str::FromStr;
+
+/// I/O backend used by the `Output` and `Input` instructions.
+pub trait Io {
+    /// Writes a byte produced by the `Output` instruction.
+    fn write_byte(&mut self, byte: u8) -> io::Result<()>;
+
+    /// Reads a byte for the `Input` instruction, `None` signals end of input.
+    ///
+    /// Bytes are passed to the program as is, no text decoding takes place.
+    fn read_byte(&mut self) -> io::Result<Option<u8>>;
+
+    /// Called when the machine stops running. Buffered backends should hand
+    /// pending output over unless their flush policy says otherwise.
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl<T: Io + ?Sized> Io for Box<T> {
+    fn write_byte(&mut self, byte: u8) -> io::Result<()> {
+        (**self).write_byte(byte)
+    }
+
+    fn read_byte(&mut self) -> io::Result<Option<u8>> {
+        (**self).read_byte()
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        (**self).flush()
+    }
+}
+
+impl<T: Io + ?Sized> Io for &mut T {
+    fn write_byte(&mut self, byte: u8) -> io::Result<()> {
+        (**self).write_byte(byte)
+    }
+
+    fn read_byte(&mut self) -> io::Result<Option<u8>> {
+        (**self).read_byte()
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        (**self).flush()
+    }
+}
+
+/// When buffered output is handed over to the underlying writer.
+///
+/// Each policy flushes strictly less often than the previous one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum FlushPolicy {
+    /// After every newline and before the program waits for input.
+    #[default]
+    Newline,
+    /// Before the program waits for input.
+    Input,
+    /// Only when the machine stops.
+    Halt,
+    /// Only when the buffer is full or dropped.
+    Never,
+}
+
+impl FromStr for FlushPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "newline" => Ok(FlushPolicy::Newline),
+            "input" => Ok(FlushPolicy::Input),
+            "halt" => Ok(FlushPolicy::Halt),
+            "never" => Ok(FlushPolicy::Never),
+            _ => Err(format!(
+                "unknown flush policy '{}' (expected newline, input, halt or never)",
+                s
+            )),
+        }
+    }
+}
+
+/// Output side shared by the stream based backends.
+struct Sink<W> {
+    writer: W,
+    policy: FlushPolicy,
+}
+
+impl<W: Write> Sink<W> {
+    fn put(&mut self, byte: u8) -> io::Result<()> {
+        self.writer.write_all(&[byte])?;
+        if byte == b'\n' && self.policy == FlushPolicy::Newline {
+            self.writer.flush()?;
+        }
+        Ok(())
+    }
+
+    fn before_input(&mut self) -> io::Result<()> {
+        match self.policy {
+            FlushPolicy::Newline | FlushPolicy::Input => self.writer.flush(),
+            FlushPolicy::Halt | FlushPolicy::Never => Ok(()),
+        }
+    }
+
+    fn stop(&mut self) -> io::Result<()> {
+        match self.policy {
+            FlushPolicy::Never => Ok(()),
+            _ => self.writer.flush(),
+        }
+    }
+}
+
+/// Standard input and output of the process.
+///
+/// Input is taken byte by byte from the buffered stdin, optionally preceded
+/// by a script given up front. Output is buffered and flushed according to
+/// the policy, `FlushPolicy::Newline` by default.
+pub struct Console {
+    script: io::Cursor<Vec<u8>>,
+    stdin: Option<Stdin>,
+    stdout: Sink<BufWriter<Stdout>>,
+}
+
+impl Console {
+    pub fn new() -> Self {
+        Console::with_policy(FlushPolicy::default())
+    }
+
+    pub fn with_policy(policy: FlushPolicy) -> Self {
+        Console {
+            script: io::Cursor::new(Vec::new()),
+            stdin: Some(io::stdin()),
+            stdout: Sink {
+                writer: BufWriter::new(io::stdout()),
+                policy,
+            },
+        }
+    }
+
+    /// Serves `script` as input first, then either continues with stdin or
+    /// reports end of input.
+    pub fn with_script(mut self, script: Vec<u8>, then_stdin: bool) -> Self {
+        self.script = io::Cursor::new(script);
+        if !then_stdin {
+            self.stdin = None;
+        }
+        self
+    }
+}
+
+impl Default for Console {
+    fn default() -> Self {
+        Console::new()
+    }
+}
+
+impl Io for Console {
+    fn write_byte(&mut self, byte: u8) -> io::Result<()> {
+        self.stdout.put(byte)
+    }
+
+    fn read_byte(&mut self) -> io::Result<Option<u8>> {
+        self.stdout.before_input()?;
+        if let Some(byte) = read_byte(&mut self.script)? {
+            return Ok(Some(byte));
+        }
+        match &self.stdin {
+            // Stdin is locked per byte rather than for the lifetime of the
+            // machine, so that the host can still read from it in between.
+            Some(stdin) => read_byte(&mut stdin.lock()),
+            None => Ok(None),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stdout.stop()
+    }
+}
+
+/// Arbitrary byte streams: files, pipes, sockets and so on.
+///
+/// The writer is used as is, wrap it into `BufWriter` if it isn't buffered
+/// already, otherwise the flush policy makes little difference.
+pub struct Streams<R, W> {
+    reader: R,
+    writer: Sink<W>,
+}
+
+impl<R: BufRead, W: Write> Streams<R, W> {
+    pub fn new(reader: R, writer: W) -> Self {
+        Streams::with_policy(reader, writer, FlushPolicy::default())
+    }
+
+    pub fn with_policy(reader: R, writer: W, policy: FlushPolicy) -> Self {
+        Streams {
+            reader,
+            writer: Sink { writer, policy },
+        }
+    }
+
+    pub fn into_inner(self) -> (R, W) {
+        (self.reader, self.writer.writer)
+    }
+}
+
+impl<R: BufRead, W: Write> Io for Streams<R, W> {
+    fn write_byte(&mut self, byte: u8) -> io::Result<()> {
+        self.writer.put(byte)
+    }
+
+    fn read_byte(&mut self) -> io::Result<Option<u8>> {
+        self.writer.before_input()?;
+        read_byte(&mut self.reader)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.stop()
+    }
+}
+
+fn read_byte<R: BufRead>(reader: &mut R) -> io::Result<Option<u8>> {
+    let byte = loop {
+        match reader.fill_buf() {
+            Ok(buf) => break buf.first().copied(),
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        }
+    };
+    if byte.is_some() {
+        reader.consume(1);
+    }
+    Ok(byte)
+}
+
+/// In-memory I/O: input is served from a fixed buffer and output is
+/// collected into a vector.
+#[derive(Debug, Default, Clone)]
+pub struct Buffer {
+    input: VecDeque<u8>,
+    output: Vec<u8>,
+}
+
+impl Buffer {
+    pub fn new<B: Into<Vec<u8>>>(input: B) -> Self {
+        Buffer {
+            input: input.into().into(),
+            output: Vec::new(),
+        }
+    }
+
+    /// Appends more bytes to the pending input.
+    pub fn feed(&mut self, bytes: &[u8]) {
+        self.input.extend(bytes);
+    }
+
+    /// Everything the machine has printed so far.
+    pub fn output(&self) -> &[u8] {
+        &self.output
+    }
+
+    pub fn take_output(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.output)
+    }
+}
+
+impl Io for Buffer {
+    fn write_byte(&mut self, byte: u8) -> io::Result<()> {
+        self.output.push(byte);
+        Ok(())
+    }
+
+    fn read_byte(&mut self) -> io::Result<Option<u8>> {
+        Ok(self.input.pop_front())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn streams_binary_input() {
+        let input: &[u8] = &[0x00, 0xff, 0xc3, b'\n'];
+        let mut io = Streams::new(input, Vec::new());
+        assert_eq!(io.read_byte().unwrap(), Some(0x00));
+        assert_eq!(io.read_byte().unwrap(), Some(0xff));
+        assert_eq!(io.read_byte().unwrap(), Some(0xc3));
+        assert_eq!(io.read_byte().unwrap(), Some(b'\n'));
+        assert_eq!(io.read_byte().unwrap(), None);
+        assert_eq!(io.read_byte().unwrap(), None);
+    }
+
+    #[test]
+    fn streams_output() {
+        let mut io = Streams::new(io::empty(), Vec::new());
+        io.write_byte(b'o').unwrap();
+        io.write_byte(b'k').unwrap();
+        Io::flush(&mut io).unwrap();
+        assert_eq!(io.into_inner().1, b"ok");
+    }
+
+    #[test]
+    fn flush_policies() {
+        fn written(policy: FlushPolicy, input: bool, stop: bool) -> Vec<u8> {
+            let writer = BufWriter::new(Vec::new());
+            let mut io = Streams::with_policy(io::empty(), writer, policy);
+            for &b in b"a\nb" {
+                io.write_byte(b).unwrap();
+            }
+            if input {
+                io.read_byte().unwrap();
+            }
+            if stop {
+                Io::flush(&mut io).unwrap();
+            }
+            io.writer.writer.get_ref().clone()
+        }
+
+        assert_eq!(written(FlushPolicy::Newline, false, false), b"a\n");
+        assert_eq!(written(FlushPolicy::Newline, true, false), b"a\nb");
+        assert_eq!(written(FlushPolicy::Input, false, false), b"");
+        assert_eq!(written(FlushPolicy::Input, true, false), b"a\nb");
+        assert_eq!(written(FlushPolicy::Halt, true, false), b"");
+        assert_eq!(written(FlushPolicy::Halt, false, true), b"a\nb");
+        assert_eq!(written(FlushPolicy::Never, true, true), b"");
+    }
+
+    #[test]
+    fn parse_flush_policy() {
+        assert_eq!("halt".parse(), Ok(FlushPolicy::Halt));
+        assert!("sometimes".parse::<FlushPolicy>().is_err());
+    }
+}
@@ -0,0 +1,979 @@
+//! Native code backend for x86_64, built with the `jit` feature.
+//!
+//! Straight runs of instructions in array 0 are compiled into machine code
+//! the first time execution gets to them. A block ends before `Input`,
+//! `Output`, `Halt` or an invalid word, or with a `LoadProgram`, which is
+//! compiled as a jump within array 0. When the target has been compiled
+//! too, the jump goes straight there without coming back. Registers live
+//! in the machine as usual. Words of boxed arrays are read and written in
+//! place, anything else is done through calls into `Mem`.
+//!
+//! Compiled code stops before anything it can't do and returns how many
+//! instructions it has executed, the interpreter takes over from there.
+//! That's how faults get reported, and also what happens to loads of other
+//! arrays and to writes to the words of array 0 which have been compiled.
+//! Such a write throws all compiled code away. Once a program keeps doing
+//! that, it's left to the interpreter until another program is loaded.
+
+use crate::code::Code;
+use crate::io::Io;
+use crate::machine::{Fault, Limits, Machine, Status};
+use crate::mem::{Mem, SlotLayout, BOXED};
+use crate::op::{Op, Reg};
+use std::mem;
+use std::ptr;
+
+// Longest run of instructions compiled into a single block.
+const MAX_BLOCK: usize = 256;
+// Times compiled code may be thrown away for writes to array 0 before the
+// program is left to the interpreter.
+const MAX_FLUSHES: u32 = 16;
+const CHUNK_SIZE: usize = 1 << 20;
+// Compiled code is thrown away once it fills this many chunks.
+const MAX_CHUNKS: usize = 64;
+
+// Entries for addresses which haven't been looked at yet and which no
+// block can start at.
+const UNKNOWN: u32 = 0;
+const NONE: u32 = u32::MAX;
+
+// Most instructions compiled code runs before coming back, so that the
+// clock gets looked at.
+const MAX_NATIVE: u64 = 1 << 16;
+
+/// Passed to compiled code, which sets `ip` to where execution continues
+/// and `last` to the block it stopped in.
+#[repr(C)]
+struct Ctx {
+    ip: u32,
+    last: u32,
+    // Instructions of the blocks which have been run to the end and jumped
+    // from, a block isn't entered if it would take them over `budget`.
+    done: u64,
+    budget: u64,
+    mem: *mut Mem,
+    code: *mut Code,
+    covered: *const Vec<bool>,
+    // Per address of array 0, start of the body of the block there, or
+    // null.
+    natives: *const *const u8,
+    natives_len: usize,
+    // Per block, times it has been run to the end and jumped from.
+    runs: *mut u64,
+    // As given by `Mem::slots`, kept up to date by the helpers.
+    slots: *const u8,
+    slots_len: usize,
+    zero: u32,
+}
+
+impl Ctx {
+    /// Picks up the slots again after `Mem` may have changed them.
+    unsafe fn sync(&mut self) {
+        let (slots, len, zero) = (*self.mem).slots();
+        self.slots = slots;
+        self.slots_len = len;
+        self.zero = zero;
+    }
+}
+
+// Offsets of the fields of `Ctx`, for compiled code.
+const IP: u8 = mem::offset_of!(Ctx, ip) as u8;
+const LAST: u8 = mem::offset_of!(Ctx, last) as u8;
+const DONE: u8 = mem::offset_of!(Ctx, done) as u8;
+const BUDGET: u8 = mem::offset_of!(Ctx, budget) as u8;
+const NATIVES: u8 = mem::offset_of!(Ctx, natives) as u8;
+const NATIVES_LEN: u8 = mem::offset_of!(Ctx, natives_len) as u8;
+const RUNS: u8 = mem::offset_of!(Ctx, runs) as u8;
+const SLOTS: u8 = mem::offset_of!(Ctx, slots) as u8;
+const SLOTS_LEN: u8 = mem::offset_of!(Ctx, slots_len) as u8;
+const ZERO: u8 = mem::offset_of!(Ctx, zero) as u8;
+
+/// Compiled block, returns the number of instructions it has executed.
+type Native = unsafe extern "C" fn(regs: *mut u32, ctx: *mut Ctx) -> u32;
+
+struct Block {
+    native: Native,
+    ops: Vec<Op>,
+}
+
+/// Executable memory compiled code is put into.
+struct Chunk {
+    ptr: *mut u8,
+    used: usize,
+}
+
+impl Chunk {
+    fn new() -> Option<Self> {
+        let ptr = unsafe {
+            libc::mmap(
+                ptr::null_mut(),
+                CHUNK_SIZE,
+                libc::PROT_READ | libc::PROT_EXEC,
+                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+                -1,
+                0,
+            )
+        };
+        if ptr == libc::MAP_FAILED {
+            return None;
+        }
+        Some(Chunk {
+            ptr: ptr as *mut u8,
+            used: 0,
+        })
+    }
+
+    fn fits(&self, len: usize) -> bool {
+        CHUNK_SIZE - self.used >= len
+    }
+
+    /// Copies `code` in, returns where it starts.
+    fn add(&mut self, code: &[u8]) -> Option<*const u8> {
+        let rw = libc::PROT_READ | libc::PROT_WRITE;
+        let rx = libc::PROT_READ | libc::PROT_EXEC;
+        unsafe {
+            if libc::mprotect(self.ptr as *mut libc::c_void, CHUNK_SIZE, rw) != 0 {
+                return None;
+            }
+            let start = self.ptr.add(self.used);
+            ptr::copy_nonoverlapping(code.as_ptr(), start, code.len());
+            if libc::mprotect(self.ptr as *mut libc::c_void, CHUNK_SIZE, rx) != 0 {
+                return None;
+            }
+            // Keep blocks aligned to 16 bytes.
+            self.used = (self.used + code.len() + 15) & !15;
+            Some(start)
+        }
+    }
+}
+
+impl Drop for Chunk {
+    fn drop(&mut self) {
+        unsafe {
+            libc::munmap(self.ptr as *mut libc::c_void, CHUNK_SIZE);
+        }
+    }
+}
+
+/// Runs programs by compiling them to native code as they go.
+pub struct Jit {
+    chunks: Vec<Chunk>,
+    blocks: Vec<Block>,
+    // Per block, times compiled code has jumped on from its end.
+    runs: Vec<u64>,
+    // Per address of array 0, index of the block starting there plus one,
+    // or one of `UNKNOWN` and `NONE`.
+    entries: Vec<u32>,
+    // Per address of array 0, body of the block starting there, or null.
+    natives: Vec<*const u8>,
+    // Whether compiled blocks have been made from an address of array 0.
+    covered: Vec<bool>,
+    flushes: u32,
+    disabled: bool,
+    // Without it, all memory access goes through `Mem`.
+    layout: Option<SlotLayout>,
+}
+
+impl Default for Jit {
+    fn default() -> Self {
+        Jit {
+            chunks: Vec::new(),
+            blocks: Vec::new(),
+            runs: Vec::new(),
+            entries: Vec::new(),
+            natives: Vec::new(),
+            covered: Vec::new(),
+            flushes: 0,
+            disabled: false,
+            layout: SlotLayout::get(),
+        }
+    }
+}
+
+impl Jit {
+    pub fn new() -> Self {
+        Jit::default()
+    }
+
+    /// Runs the machine within `limits`, compiling blocks as it gets to
+    /// them.
+    pub fn run<T: Io>(&mut self, um: &mut Machine<T>, limits: Limits) -> Result<Status, Fault> {
+        // Anything could have been done to array 0 since the last run.
+        self.reset(um);
+
+        let mut native = true;
+        let result = um.run_by(limits, |um, left| {
+            if native {
+                if let Some(block) = self.lookup(um) {
+                    if self.blocks[block].ops.len() as u64 <= left {
+                        // Unless the block ended with a jump, it stopped at
+                        // an instruction which is up to the interpreter.
+                        native = self.execute(um, block, left);
+                        return Ok(Status::Running);
+                    }
+                }
+            }
+            native = true;
+            self.step(um)
+        });
+        self.count_runs(um);
+        result
+    }
+
+    /// Executes a single instruction in the interpreter, keeping track of
+    /// changes to array 0.
+    fn step<T: Io>(&mut self, um: &mut Machine<T>) -> Result<Status, Fault> {
+        let op = um.next_op();
+        let regs = *um.regs();
+        let status = um.step()?;
+        match op {
+            Some(Op::MemWrite(a, b, _)) if regs[a] == 0 => self.written(um, regs[b]),
+            Some(Op::LoadProgram(b, _)) if regs[b] != 0 => self.reset(um),
+            _ => {}
+        }
+        Ok(status)
+    }
+
+    /// Runs compiled block `block` and whichever it jumps on to, at most
+    /// `left` instructions. Returns whether the last one ended with a jump.
+    fn execute<T: Io>(&mut self, um: &mut Machine<T>, block: usize, left: u64) -> bool {
+        let (regs, mem, code) = um.parts_mut();
+        let mut ctx = Ctx {
+            ip: 0,
+            last: block as u32,
+            done: 0,
+            budget: left.min(MAX_NATIVE),
+            mem,
+            code,
+            covered: &self.covered,
+            natives: self.natives.as_ptr(),
+            natives_len: self.natives.len(),
+            runs: self.runs.as_mut_ptr(),
+            slots: ptr::null(),
+            slots_len: 0,
+            zero: 0,
+        };
+        let n = unsafe {
+            ctx.sync();
+            (self.blocks[block].native)(regs.as_mut_ptr(), &mut ctx)
+        } as usize;
+        let last = &self.blocks[ctx.last as usize];
+        um.advance(ctx.done + n as u64, ctx.ip);
+        um.count_ops(&last.ops[..n], 1);
+        n > 0 && n == last.ops.len() && matches!(last.ops[n - 1], Op::LoadProgram(..))
+    }
+
+    /// Block starting at the current ip, compiled now if it hasn't been.
+    fn lookup<T: Io>(&mut self, um: &mut Machine<T>) -> Option<usize> {
+        if self.disabled {
+            return None;
+        }
+        match self.entries.get(um.ip() as usize).copied() {
+            Some(UNKNOWN) => self.compile(um),
+            Some(NONE) | None => None,
+            Some(n) => Some(n as usize - 1),
+        }
+    }
+
+    fn compile<T: Io>(&mut self, um: &mut Machine<T>) -> Option<usize> {
+        let ip = um.ip();
+        let program = um.mem().array(0).ok()?;
+        let ops = block_ops(&program[ip as usize..]);
+        if ops.is_empty() {
+            self.entries[ip as usize] = NONE;
+            return None;
+        }
+
+        let mut code = emit(ip, &ops, self.blocks.len() as u32, self.layout.as_ref());
+        if !self.room(code.len()) {
+            // Start over, the block gets to be the first one.
+            self.flush(um);
+            code = emit(ip, &ops, 0, self.layout.as_ref());
+        }
+        let start = match self.chunks.iter_mut().find(|c| c.fits(code.len())) {
+            Some(chunk) => chunk.add(&code),
+            None => None,
+        };
+        let start = match start {
+            Some(start) => start,
+            None => {
+                self.disabled = true;
+                return None;
+            }
+        };
+        let end = ip + ops.len() as u32;
+        self.blocks.push(Block {
+            native: unsafe { mem::transmute::<*const u8, Native>(start) },
+            ops,
+        });
+        self.runs.push(0);
+        self.entries[ip as usize] = self.blocks.len() as u32;
+        self.natives[ip as usize] = unsafe { start.add(PROLOGUE) };
+        for covered in &mut self.covered[ip as usize..end as usize] {
+            *covered = true;
+        }
+        Some(self.blocks.len() - 1)
+    }
+
+    /// Makes sure there's a chunk with room for `len` bytes of code, false
+    /// if they're all used up.
+    fn room(&mut self, len: usize) -> bool {
+        if self.chunks.iter().any(|c| c.fits(len)) {
+            return true;
+        }
+        if self.chunks.len() >= MAX_CHUNKS {
+            return false;
+        }
+        // If this fails, so does compiling.
+        if let Some(chunk) = Chunk::new() {
+            self.chunks.push(chunk);
+        }
+        true
+    }
+
+    /// Adds the instructions of blocks compiled code has jumped on from to
+    /// the machine's counts.
+    fn count_runs<T: Io>(&mut self, um: &mut Machine<T>) {
+        for (block, runs) in self.blocks.iter().zip(&mut self.runs) {
+            if *runs > 0 {
+                um.count_ops(&block.ops, mem::take(runs));
+            }
+        }
+    }
+
+    /// Throws away all compiled code.
+    fn flush<T: Io>(&mut self, um: &mut Machine<T>) {
+        self.count_runs(um);
+        self.blocks.clear();
+        self.runs.clear();
+        for chunk in &mut self.chunks {
+            chunk.used = 0;
+        }
+        for entry in &mut self.entries {
+            *entry = UNKNOWN;
+        }
+        for native in &mut self.natives {
+            *native = ptr::null();
+        }
+        for covered in &mut self.covered {
+            *covered = false;
+        }
+    }
+
+    /// Starts over with the program currently in array 0.
+    fn reset<T: Io>(&mut self, um: &mut Machine<T>) {
+        self.flush(um);
+        let len = um.mem().array(0).map_or(0, |p| p.len());
+        self.entries.resize(len, UNKNOWN);
+        self.natives.resize(len, ptr::null());
+        self.covered.resize(len, false);
+        self.flushes = 0;
+        self.disabled = false;
+    }
+
+    /// Keeps compiled code in sync with a write to array 0.
+    fn written<T: Io>(&mut self, um: &mut Machine<T>, offset: u32) {
+        if self.covered.get(offset as usize) == Some(&true) {
+            self.flush(um);
+            self.flushes += 1;
+            if self.flushes >= MAX_FLUSHES {
+                self.disabled = true;
+            }
+        }
+    }
+}
+
+/// Instructions of the block starting with the first of `words`.
+fn block_ops(words: &[u32]) -> Vec<Op> {
+    let mut ops = Vec::new();
+    for &word in words.iter().take(MAX_BLOCK) {
+        match Op::parse(word) {
+            Ok(op @ Op::LoadProgram(..)) => {
+                ops.push(op);
+                break;
+            }
+            Ok(Op::Halt) | Ok(Op::Output(_)) | Ok(Op::Input(_)) | Err(_) => break,
+            Ok(op) => ops.push(op),
+        }
+    }
+    ops
+}
+
+// Failure returned by the memory helpers, other results fit in 32 bits.
+const FAILED: u64 = 1 << 32;
+
+unsafe extern "C" fn mem_read(ctx: *mut Ctx, addr: u32, offset: u32) -> u64 {
+    match (*(*ctx).mem).read(addr, offset) {
+        Ok(&val) => u64::from(val),
+        Err(_) => FAILED,
+    }
+}
+
+unsafe extern "C" fn mem_write(ctx: *mut Ctx, addr: u32, offset: u32, val: u32) -> u64 {
+    let ctx = &mut *ctx;
+    // Compiled code is thrown away when written to, which is up to the
+    // interpreter.
+    let covered = &*ctx.covered;
+    if addr == 0 && covered.get(offset as usize) == Some(&true) {
+        return FAILED;
+    }
+    let result = match (*ctx.mem).write(addr, offset, val) {
+        Ok(()) if addr == 0 => {
+            (*ctx.code).update(offset, val);
+            0
+        }
+        Ok(()) => 0,
+        Err(_) => FAILED,
+    };
+    // Array 0 may have got a copy of its own.
+    ctx.sync();
+    result
+}
+
+unsafe extern "C" fn alloc(ctx: *mut Ctx, size: u32) -> u64 {
+    let ctx = &mut *ctx;
+    let result = match (*ctx.mem).alloc(size) {
+        Ok(addr) => u64::from(addr),
+        Err(_) => FAILED,
+    };
+    ctx.sync();
+    result
+}
+
+unsafe extern "C" fn free(ctx: *mut Ctx, addr: u32) -> u64 {
+    let ctx = &mut *ctx;
+    let result = match (*ctx.mem).free(addr) {
+        Ok(()) => 0,
+        Err(_) => FAILED,
+    };
+    ctx.sync();
+    result
+}
+
+// Registers of the host, as numbered in instruction encodings.
+const EAX: u8 = 0;
+const ECX: u8 = 1;
+const EDX: u8 = 2;
+const ESI: u8 = 6;
+
+const JAE: u8 = 0x83;
+const JZ: u8 = 0x84;
+const JNZ: u8 = 0x85;
+const JA: u8 = 0x87;
+
+// Length of the code which sets up the registers, compiled code jumping
+// from one block to another skips it.
+const PROLOGUE: usize = 10;
+
+/// Out of line call to a memory helper, for accesses which can't be done
+/// in place.
+struct SlowPath {
+    // Jumps to it to be patched.
+    jumps: Vec<usize>,
+    helper: *const (),
+    // Register the result of a read goes to.
+    result: Option<Reg>,
+    executed: u32,
+    // Where the block goes on.
+    resume: usize,
+}
+
+/// Machine code of a block being compiled. `rbx` points to the registers
+/// of the machine and `r12` to the `Ctx`.
+struct Emitter<'a> {
+    code: Vec<u8>,
+    // Jumps out of the block to be patched, with the number of
+    // instructions executed by then.
+    exits: Vec<(usize, u32)>,
+    slow: Vec<SlowPath>,
+    // Index of the block.
+    index: u32,
+    layout: Option<&'a SlotLayout>,
+}
+
+impl Emitter<'_> {
+    fn bytes(&mut self, bytes: &[u8]) {
+        self.code.extend_from_slice(bytes);
+    }
+
+    fn imm32(&mut self, val: u32) {
+        self.bytes(&val.to_le_bytes());
+    }
+
+    /// `op host, [rbx + 4 * reg]`, e.g. `mov`, `add`, `and`.
+    fn with_reg(&mut self, op: &[u8], host: u8, reg: Reg) {
+        self.bytes(op);
+        self.bytes(&[0x43 | host << 3, 4 * reg as u8]);
+    }
+
+    /// `op host, [r12 + field]` on 32 bits, or on 64 if `wide`.
+    fn with_ctx(&mut self, op: &[u8], wide: bool, host: u8, field: u8) {
+        self.bytes(&[0x41 | u8::from(wide) << 3]);
+        self.bytes(op);
+        self.bytes(&[0x44 | host << 3, 0x24, field]);
+    }
+
+    fn load(&mut self, host: u8, reg: Reg) {
+        self.with_reg(&[0x8B], host, reg);
+    }
+
+    fn store(&mut self, reg: Reg, host: u8) {
+        self.with_reg(&[0x89], host, reg);
+    }
+
+    /// Calls `helper` with the `Ctx` as the first argument, the rest of
+    /// them must have been loaded already.
+    fn call(&mut self, helper: *const ()) {
+        self.bytes(&[0x4C, 0x89, 0xE7]); // mov rdi, r12
+        self.bytes(&[0x48, 0xB8]); // mov rax, helper
+        self.bytes(&(helper as u64).to_le_bytes());
+        self.bytes(&[0xFF, 0xD0]); // call rax
+    }
+
+    /// Conditional jump within the block, returns where to patch it.
+    fn jump(&mut self, cond: u8) -> usize {
+        self.bytes(&[0x0F, cond]);
+        self.imm32(0);
+        self.code.len() - 4
+    }
+
+    /// Points the jump at `at` to `to`.
+    fn patch(&mut self, at: usize, to: usize) {
+        let rel = (to as isize - (at as isize + 4)) as i32;
+        self.code[at..at + 4].copy_from_slice(&rel.to_le_bytes());
+    }
+
+    /// Leaves the block if the condition holds, `executed` instructions in.
+    fn exit_if(&mut self, cond: u8, executed: u32) {
+        let at = self.jump(cond);
+        self.exits.push((at, executed));
+    }
+
+    fn exit_if_failed(&mut self, executed: u32) {
+        self.bytes(&[0x48, 0x89, 0xC1]); // mov rcx, rax
+        self.bytes(&[0x48, 0xC1, 0xE9, 0x20]); // shr rcx, 32
+        self.exit_if(JNZ, executed);
+    }
+
+    /// Points `rax` at the words of array `esi`, given that `edx` is
+    /// within them, returns the jumps taken otherwise. Those for writes
+    /// are also taken for array 0 and the one it shares storage with.
+    fn words(&mut self, layout: &SlotLayout, write: bool) -> Vec<usize> {
+        let mut jumps = Vec::new();
+        self.with_ctx(&[0x3B], true, ESI, SLOTS_LEN); // cmp rsi, [slots_len]
+        jumps.push(self.jump(JAE));
+        if write {
+            self.with_ctx(&[0x3B], false, ESI, ZERO); // cmp esi, [zero]
+            jumps.push(self.jump(JZ));
+        }
+        self.bytes(&[0x48, 0x69, 0xC6]); // imul rax, rsi, size
+        self.imm32(layout.size as u32);
+        self.with_ctx(&[0x03], true, EAX, SLOTS); // add rax, [slots]
+        self.bytes(&[0x80, 0x38, BOXED]); // cmp byte [rax], BOXED
+        jumps.push(self.jump(JNZ));
+        self.bytes(&[0x48, 0x3B, 0x50, layout.len as u8]); // cmp rdx, [rax + len]
+        jumps.push(self.jump(JAE));
+        self.bytes(&[0x48, 0x8B, 0x40, layout.ptr as u8]); // mov rax, [rax + ptr]
+        jumps
+    }
+
+    /// Calls `helper` out of line when one of `jumps` is taken, then goes
+    /// on from here.
+    fn slow(&mut self, jumps: Vec<usize>, helper: *const (), result: Option<Reg>, executed: u32) {
+        self.slow.push(SlowPath {
+            jumps,
+            helper,
+            result,
+            executed,
+            resume: self.code.len(),
+        });
+    }
+
+    /// Returns `executed` with ip already set.
+    fn ret(&mut self, executed: u32) {
+        self.with_ctx(&[0xC7], false, 0, LAST); // mov dword [r12 + last], index
+        self.imm32(self.index);
+        self.bytes(&[0xB8]); // mov eax, executed
+        self.imm32(executed);
+        self.bytes(&[0x41, 0x5C, 0x5B, 0x5D, 0xC3]); // pop r12, rbx, rbp; ret
+    }
+
+    fn ret_at(&mut self, ip: u32, executed: u32) {
+        self.with_ctx(&[0xC7], false, 0, IP); // mov dword [r12 + ip], ip
+        self.imm32(ip);
+        self.ret(executed);
+    }
+
+    fn finish(mut self, start: u32) -> Vec<u8> {
+        for path in mem::take(&mut self.slow) {
+            let here = self.code.len();
+            for at in path.jumps {
+                self.patch(at, here);
+            }
+            self.call(path.helper);
+            self.exit_if_failed(path.executed);
+            if let Some(reg) = path.result {
+                self.store(reg, EAX);
+            }
+            self.bytes(&[0xE9]); // jmp resume
+            self.imm32(0);
+            let at = self.code.len() - 4;
+            self.patch(at, path.resume);
+        }
+        for (at, executed) in mem::take(&mut self.exits) {
+            let here = self.code.len();
+            self.patch(at, here);
+            self.ret_at(start + executed, executed);
+        }
+        self.code
+    }
+}
+
+/// Compiles `ops` found at address `start` as block `index`. Without a
+/// `layout`, memory is only accessed through the helpers.
+fn emit(start: u32, ops: &[Op], index: u32, layout: Option<&SlotLayout>) -> Vec<u8> {
+    let mut e = Emitter {
+        code: Vec::new(),
+        exits: Vec::new(),
+        slow: Vec::new(),
+        index,
+        layout,
+    };
+    e.bytes(&[0x55, 0x53, 0x41, 0x54]); // push rbp, rbx, r12
+    e.bytes(&[0x48, 0x89, 0xFB]); // mov rbx, rdi
+    e.bytes(&[0x49, 0x89, 0xF4]); // mov r12, rsi
+    debug_assert_eq!(e.code.len(), PROLOGUE);
+
+    // Blocks jumped to make sure they fit the budget first.
+    let len = ops.len() as u32;
+    e.with_ctx(&[0x8B], true, EAX, DONE); // mov rax, [r12 + done]
+    e.bytes(&[0x48, 0x05]); // add rax, len
+    e.imm32(len);
+    e.with_ctx(&[0x3B], true, EAX, BUDGET); // cmp rax, [r12 + budget]
+    e.exit_if(JA, 0);
+
+    for (i, &op) in ops.iter().enumerate() {
+        let i = i as u32;
+        match op {
+            Op::CondMov(a, b, c) => {
+                e.load(EAX, c);
+                e.bytes(&[0x85, 0xC0, 0x74, 6]); // test eax, eax; jz over the move
+                e.load(EAX, b);
+                e.store(a, EAX);
+            }
+            Op::MemRead(a, b, c) => {
+                e.load(ESI, b);
+                e.load(EDX, c);
+                match e.layout {
+                    Some(layout) => {
+                        let jumps = e.words(layout, false);
+                        e.bytes(&[0x8B, 0x04, 0x90]); // mov eax, [rax + 4 * rdx]
+                        e.store(a, EAX);
+                        e.slow(jumps, mem_read as *const (), Some(a), i);
+                    }
+                    None => {
+                        e.call(mem_read as *const ());
+                        e.exit_if_failed(i);
+                        e.store(a, EAX);
+                    }
+                }
+            }
+            Op::MemWrite(a, b, c) => {
+                e.load(ESI, a);
+                e.load(EDX, b);
+                e.load(ECX, c);
+                match e.layout {
+                    Some(layout) => {
+                        let jumps = e.words(layout, true);
+                        e.bytes(&[0x89, 0x0C, 0x90]); // mov [rax + 4 * rdx], ecx
+                        e.slow(jumps, mem_write as *const (), None, i);
+                    }
+                    None => {
+                        e.call(mem_write as *const ());
+                        e.exit_if_failed(i);
+                    }
+                }
+            }
+            Op::Add(a, b, c) => {
+                e.load(EAX, b);
+                e.with_reg(&[0x03], EAX, c);
+                e.store(a, EAX);
+            }
+            Op::Mul(a, b, c) => {
+                e.load(EAX, b);
+                e.with_reg(&[0x0F, 0xAF], EAX, c);
+                e.store(a, EAX);
+            }
+            Op::Div(a, b, c) => {
+                e.load(ECX, c);
+                e.bytes(&[0x85, 0xC9]); // test ecx, ecx
+                e.exit_if(JZ, i);
+                e.load(EAX, b);
+                e.bytes(&[0x31, 0xD2, 0xF7, 0xF1]); // xor edx, edx; div ecx
+                e.store(a, EAX);
+            }
+            Op::Nand(a, b, c) => {
+                e.load(EAX, b);
+                e.with_reg(&[0x23], EAX, c);
+                e.bytes(&[0xF7, 0xD0]); // not eax
+                e.store(a, EAX);
+            }
+            Op::Alloc(b, c) => {
+                e.load(ESI, c);
+                e.call(alloc as *const ());
+                e.exit_if_failed(i);
+                e.store(b, EAX);
+            }
+            Op::Free(c) => {
+                e.load(ESI, c);
+                e.call(free as *const ());
+                e.exit_if_failed(i);
+            }
+            Op::LoadProgram(b, c) => {
+                // Loading another array is left to the interpreter.
+                e.load(EAX, b);
+                e.bytes(&[0x85, 0xC0]); // test eax, eax
+                e.exit_if(JNZ, i);
+                e.load(EAX, c);
+                e.with_ctx(&[0x89], false, EAX, IP); // mov [r12 + ip], eax
+
+                // Goes straight on to the block at the target if there is
+                // one, counting this one as done.
+                e.with_ctx(&[0x3B], true, EAX, NATIVES_LEN); // cmp rax, [r12 + natives_len]
+                let outside = e.jump(JAE);
+                e.with_ctx(&[0x8B], true, EDX, NATIVES); // mov rdx, [r12 + natives]
+                e.bytes(&[0x48, 0x8B, 0x14, 0xC2]); // mov rdx, [rdx + 8 * rax]
+                e.bytes(&[0x48, 0x85, 0xD2]); // test rdx, rdx
+                let missing = e.jump(JZ);
+                e.with_ctx(&[0x8B], true, EAX, RUNS); // mov rax, [r12 + runs]
+                e.bytes(&[0x48, 0xFF, 0x80]); // inc qword [rax + 8 * index]
+                e.imm32(8 * index);
+                e.with_ctx(&[0x81], true, 0, DONE); // add qword [r12 + done], i + 1
+                e.imm32(i + 1);
+                e.bytes(&[0xFF, 0xE2]); // jmp rdx
+
+                let here = e.code.len();
+                e.patch(outside, here);
+                e.patch(missing, here);
+                e.ret(i + 1);
+            }
+            Op::Mov(a, val) => {
+                e.with_reg(&[0xC7], EAX, a);
+                e.imm32(val);
+            }
+            Op::Halt | Op::Output(_) | Op::Input(_) => unreachable!("{} isn't compiled", op),
+        }
+    }
+
+    if !matches!(ops.last(), Some(Op::LoadProgram(..))) {
+        e.ret_at(start + len, len);
+    }
+    e.finish(start)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm;
+    use crate::io::Buffer;
+    use std::time::Duration;
+
+    // Sums up a few things over an array, allocating along the way, then
+    // echoes a byte of input.
+    const SUM: &str = "
+                mov r1, 1
+                mov r2, 100
+                alloc r3, r2
+        loop:   memwrite r3, r4, r4
+                memread r6, r3, r4
+                add r5, r5, r6
+                mul r6, r6, r6
+                mov r7, 3
+                div r6, r6, r7
+                nand r6, r6, r6
+                add r5, r5, r6
+                alloc r2, r1
+                free r2
+                add r4, r4, r1
+                mov r7, 100
+                nand r7, r7, r7
+                add r7, r7, r1
+                add r7, r7, r4          ; i - 100
+                mov r6, done
+                mov r2, loop
+                condmov r6, r2, r7
+                loadprogram r0, r6
+        done:   free r3
+                input r2
+                output r2
+                halt
+    ";
+
+    // Runs `src` in the interpreter and with the jit, which must agree, with
+    // and without statistics.
+    fn compare(src: &str, limits: Limits) -> (Jit, Machine<Buffer>) {
+        compare_with(src, limits, Jit::new)
+    }
+
+    fn compare_with(src: &str, limits: Limits, jit: fn() -> Jit) -> (Jit, Machine<Buffer>) {
+        let image = asm::image(&asm::assemble(src).unwrap());
+        let mut um = Machine::load_with_io(&image, Buffer::new("hello")).unwrap();
+        um.collect_stats(true);
+        let expected = um.run_with(limits);
+
+        let mut last = None;
+        for &stats in &[false, true] {
+            let mut jitted = Machine::load_with_io(&image, Buffer::new("hello")).unwrap();
+            jitted.collect_stats(stats);
+            let mut jit = jit();
+            assert_eq!(jit.run(&mut jitted, limits), expected);
+            assert_eq!(jitted.regs(), um.regs());
+            assert_eq!(jitted.ip(), um.ip());
+            assert_eq!(jitted.steps(), um.steps());
+            if stats {
+                assert_eq!(jitted.stats(), um.stats());
+            }
+            assert_eq!(jitted.io().output(), um.io().output());
+            last = Some((jit, jitted));
+        }
+        last.unwrap()
+    }
+
+    #[test]
+    fn same_results() {
+        let (jit, um) = compare(SUM, Limits::default());
+        assert!(!jit.blocks.is_empty());
+        assert_eq!(um.io().output(), b"h");
+    }
+
+    #[test]
+    fn helpers_only() {
+        assert!(Jit::new().layout.is_some());
+        let without = || Jit {
+            layout: None,
+            ..Jit::default()
+        };
+        compare_with(SUM, Limits::default(), without);
+        compare_with(SUM, Limits::steps(1000), without);
+    }
+
+    #[test]
+    fn budget() {
+        for &n in &[0, 1, 2, 5, 17, 18, 19, 100, 1000, 2000] {
+            compare(SUM, Limits::steps(n));
+        }
+        let (_, um) = compare("mov r1, 0\nloadprogram r1, r1\n", Limits::steps(12345));
+        assert_eq!(um.steps(), 12345);
+    }
+
+    #[test]
+    fn timeout() {
+        let spin = "mov r1, 0\nloadprogram r1, r1\n";
+        let image = asm::image(&asm::assemble(spin).unwrap());
+        let mut um = Machine::load_with_io(&image, Buffer::default()).unwrap();
+        let limits = Limits::timeout(Duration::from_millis(20));
+        assert_eq!(Jit::new().run(&mut um, limits), Ok(Status::TimedOut));
+    }
+
+    #[test]
+    fn faults() {
+        compare(
+            "mov r1, 5\nadd r1, r1, r1\ndiv r3, r1, r2\nhalt\n",
+            Limits::default(),
+        );
+        compare(
+            "mov r1, 5\nadd r1, r1, r1\nmemread r3, r1, r2\n",
+            Limits::default(),
+        );
+        compare(
+            "mov r1, 5\nalloc r2, r1\nfree r2\nfree r2\n",
+            Limits::default(),
+        );
+        compare("mov r1, 5\nloadprogram r1, r0\n", Limits::default());
+    }
+
+    #[test]
+    fn self_modifying() {
+        // Patches `target` every time round the loop, doubling r5 on odd
+        // iterations and adding 1 on even ones.
+        let src = "
+                    mov r1, 1
+                    mov r3, 40
+            loop:   add r4, r4, r1
+                    nand r7, r4, r1
+                    nand r7, r7, r7
+                    mov r6, inc
+                    mov r2, double
+                    condmov r6, r2, r7
+                    memread r2, r0, r6
+                    mov r6, target
+                    memwrite r0, r6, r2
+            target: halt
+                    nand r7, r3, r3
+                    add r7, r7, r1
+                    add r7, r7, r4
+                    mov r6, done
+                    mov r2, loop
+                    condmov r6, r2, r7
+                    loadprogram r0, r6
+            done:   halt
+            inc:    add r5, r5, r1
+            double: add r5, r5, r5
+        ";
+        let (jit, um) = compare(src, Limits::default());
+        assert_eq!(um.reg(5), 0xfffff);
+        assert!(jit.disabled);
+    }
+
+    #[test]
+    fn load_program() {
+        // Loads a program made of a single `halt` from array 1.
+        let src = "
+                    mov r1, 1
+                    alloc r2, r1
+                    mov r3, stop
+                    memread r4, r0, r3
+                    memwrite r2, r0, r4
+                    loadprogram r2, r0
+            stop:   halt
+        ";
+        let (jit, um) = compare(src, Limits::default());
+        assert_eq!(um.origin(), 1);
+        assert_eq!(jit.entries.len(), 1);
+    }
+
+    #[test]
+    fn shared_program() {
+        // Copies itself to array 1, goes on from there with array 0 sharing
+        // its storage, then writes to array 1 which must get it a copy.
+        let src = "
+                    mov r1, 1
+                    mov r7, end
+                    alloc r2, r7
+            copy:   memread r3, r0, r4
+                    memwrite r2, r4, r3
+                    add r4, r4, r1
+                    nand r5, r4, r4
+                    add r5, r5, r1
+                    add r5, r5, r7          ; end - i
+                    mov r6, next
+                    mov r3, copy
+                    condmov r6, r3, r5
+                    loadprogram r0, r6
+            next:   mov r6, shared
+                    loadprogram r2, r6
+            shared: memwrite r2, r0, r1
+                    memread r3, r0, r0
+                    memread r4, r2, r0
+                    memwrite r0, r4, r1
+                    memread r5, r2, r4
+                    halt
+            end:
+        ";
+        let (_, um) = compare(src, Limits::default());
+        // Neither write shows up in the other array.
+        assert_eq!(um.reg(3), 0xD200_0001);
+        assert_eq!(um.reg(4), 1);
+        assert_ne!(um.reg(5), 1);
+    }
+}
@@ -1 +1,18 @@
+pub mod asm;
+pub mod bench;
+mod code;
+pub mod debugger;
+pub mod disasm;
+pub mod error;
+pub mod image;
+pub mod io;
+#[cfg(all(feature = "jit", target_arch = "x86_64", unix))]
+pub mod jit;
+pub mod machine;
 pub mod mem;
+pub mod op;
+pub mod profile;
+pub mod reverse;
+pub mod sanitize;
+pub mod stats;
+pub mod trace;
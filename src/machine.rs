@@ -0,0 +1,1023 @@
+use crate::code::Code;
+use crate::error::VmError;
+use crate::image;
+use crate::image::Image;
+use crate::io::{Console, Io};
+use crate::mem::{Mem, MemLimits, Quarantine, Strategy};
+use crate::op::{Op, Reg};
+use crate::stats::Stats;
+use byteorder::BigEndian;
+use byteorder::{ReadBytesExt, WriteBytesExt};
+use std::error;
+use std::fmt;
+use std::io;
+use std::io::{Read, Write};
+use std::time::{Duration, Instant};
+
+const SNAPSHOT_MAGIC: &[u8; 8] = b"UMSNAP\0\0";
+const SNAPSHOT_VERSION: u32 = 3;
+
+/// State of the machine after executing an instruction.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Status {
+    Running,
+    Halted,
+    /// The instruction budget given to `run_for` or `run_with` ran out.
+    BudgetExhausted,
+    /// The time given to `run_with` ran out.
+    TimedOut,
+    /// The program asked for input again after it had been told that input
+    /// is over. `ip` still points to that `Input` instruction, so execution
+    /// may be resumed once more input becomes available.
+    Eof,
+}
+
+/// A machine fault together with the location where it happened.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Fault {
+    /// Value of the instruction pointer when the fault occurred.
+    pub ip: u32,
+    /// Faulting instruction, `None` if `ip` itself was out of bounds.
+    pub word: Option<u32>,
+    pub error: VmError,
+}
+
+impl fmt::Display for Fault {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.word {
+            Some(word) => write!(
+                f,
+                "vm: {} (ip: {}, insn: {:#010x})",
+                self.error, self.ip, word
+            ),
+            None => write!(f, "vm: {} (ip: {})", self.error, self.ip),
+        }
+    }
+}
+
+impl error::Error for Fault {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        Some(&self.error)
+    }
+}
+
+/// Everything a machine consists of apart from its I/O backend.
+#[derive(Clone)]
+pub struct State {
+    reg: [u32; 8],
+    mem: Mem,
+    code: Code,
+    ip: u32,
+    eof: bool,
+    origin: u32,
+    steps: u64,
+}
+
+impl State {
+    /// Number of instructions the machine had executed.
+    pub fn steps(&self) -> u64 {
+        self.steps
+    }
+}
+
+/// Bounds on how long `run_with` may keep running a program.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Limits {
+    /// Number of instructions to execute at most.
+    pub max_steps: Option<u64>,
+    /// Wall-clock time to run at most. It's checked every few thousand
+    /// instructions, so it may be overrun slightly.
+    pub timeout: Option<Duration>,
+}
+
+impl Limits {
+    pub fn steps(max_steps: u64) -> Self {
+        Limits {
+            max_steps: Some(max_steps),
+            timeout: None,
+        }
+    }
+
+    pub fn timeout(timeout: Duration) -> Self {
+        Limits {
+            max_steps: None,
+            timeout: Some(timeout),
+        }
+    }
+}
+
+#[derive(Default)]
+struct Counts {
+    ops: [u64; 14],
+    program_loads: u64,
+}
+
+// Instructions executed between two looks at the clock.
+const CLOCK_INTERVAL: u64 = 1 << 16;
+
+pub struct Machine<T = Console> {
+    reg: [u32; 8],
+    mem: Mem,
+    code: Code,
+    ip: u32,
+    io: T,
+    eof: bool,
+    origin: u32,
+    steps: u64,
+    counts: Option<Box<Counts>>,
+}
+
+impl Machine {
+    /// Loads a program which talks to the process' stdin and stdout.
+    pub fn load(bytes: &[u8]) -> io::Result<Self> {
+        Machine::load_with_io(bytes, Console::new())
+    }
+
+    /// Opens an image in any format of `image`, talking to the process'
+    /// stdin and stdout.
+    pub fn open(bytes: &[u8]) -> io::Result<Self> {
+        Machine::open_with_io(bytes, Console::new())
+    }
+
+    /// Restores a snapshot which talks to the process' stdin and stdout.
+    pub fn restore<R: Read>(r: &mut R) -> io::Result<Self> {
+        Machine::restore_with_io(r, Console::new())
+    }
+}
+
+impl<T: Io> Machine<T> {
+    /// Loads a raw image, which must be a whole number of words.
+    /// `open_with_io` takes the other formats of `image` too.
+    pub fn load_with_io(bytes: &[u8], io: T) -> io::Result<Self> {
+        Ok(Machine::from_program(image::words(bytes)?, io))
+    }
+
+    /// Opens an image in any format of `image`. The input script and
+    /// symbols of a container are left to the caller, see `from_image`.
+    pub fn open_with_io(bytes: &[u8], io: T) -> io::Result<Self> {
+        Ok(Machine::from_image(Image::read(bytes)?, io))
+    }
+
+    /// Sets the machine up as `image` describes: arrays are allocated in
+    /// order, then execution starts at the entry ip with the given
+    /// registers. `image.input` and `image.symbols` aren't used.
+    pub fn from_image(image: Image, io: T) -> Self {
+        let mut um = Machine::from_program(image.program, io);
+        for array in &image.arrays {
+            let id = um.mem.alloc(array.len() as u32).expect("image array");
+            for (offset, &word) in array.iter().enumerate() {
+                um.mem
+                    .write(id, offset as u32, word)
+                    .expect("allocated array");
+            }
+        }
+        um.reg = image.regs;
+        um.ip = image.entry;
+        um
+    }
+
+    /// Starts `program` from ip 0 with all registers 0.
+    pub fn from_program(program: Vec<u32>, io: T) -> Self {
+        Machine {
+            reg: [0; 8],
+            code: Code::decode(&program),
+            mem: Mem::init(program),
+            ip: 0,
+            io,
+            eof: false,
+            origin: 0,
+            steps: 0,
+            counts: None,
+        }
+    }
+
+    /// Reads a snapshot written by `save`. Execution continues exactly where
+    /// it was saved, with input and output going to `io`.
+    pub fn restore_with_io<R: Read>(r: &mut R, io: T) -> io::Result<Self> {
+        let mut magic = [0; 8];
+        r.read_exact(&mut magic)?;
+        if &magic != SNAPSHOT_MAGIC {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "not a machine snapshot",
+            ));
+        }
+        let version = r.read_u32::<BigEndian>()?;
+        if version != SNAPSHOT_VERSION {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("unsupported snapshot version {}", version),
+            ));
+        }
+
+        let ip = r.read_u32::<BigEndian>()?;
+        let mut reg = [0; 8];
+        r.read_u32_into::<BigEndian>(&mut reg)?;
+        let steps = r.read_u64::<BigEndian>()?;
+        let origin = r.read_u32::<BigEndian>()?;
+        let eof = r.read_u8()? != 0;
+        let mem = Mem::restore(r)?;
+
+        Ok(Machine {
+            reg,
+            code: Code::decode(mem.array(0).expect("restored array 0")),
+            mem,
+            ip,
+            io,
+            eof,
+            origin,
+            steps,
+            counts: None,
+        })
+    }
+
+    /// Writes the complete state of the machine as a versioned snapshot.
+    /// The I/O backend isn't part of it, so pending output should be
+    /// flushed beforehand.
+    pub fn save<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        w.write_all(SNAPSHOT_MAGIC)?;
+        w.write_u32::<BigEndian>(SNAPSHOT_VERSION)?;
+        w.write_u32::<BigEndian>(self.ip)?;
+        for &r in &self.reg {
+            w.write_u32::<BigEndian>(r)?;
+        }
+        w.write_u64::<BigEndian>(self.steps)?;
+        w.write_u32::<BigEndian>(self.origin)?;
+        w.write_u8(self.eof as u8)?;
+        self.mem.save(w)
+    }
+
+    pub fn reg(&self, r: Reg) -> u32 {
+        self.reg[r]
+    }
+
+    pub fn set_reg(&mut self, r: Reg, val: u32) {
+        self.reg[r] = val;
+    }
+
+    pub fn regs(&self) -> &[u32; 8] {
+        &self.reg
+    }
+
+    pub fn ip(&self) -> u32 {
+        self.ip
+    }
+
+    pub fn set_ip(&mut self, ip: u32) {
+        self.ip = ip;
+    }
+
+    pub fn mem(&self) -> &Mem {
+        &self.mem
+    }
+
+    /// Gives direct access to the memory. The decoded program is dropped
+    /// and gets rebuilt from array 0 on the next step.
+    pub fn mem_mut(&mut self) -> &mut Mem {
+        self.code.clear();
+        &mut self.mem
+    }
+
+    /// Like `Mem::set_strategy`, but keeps the decoded program as none of
+    /// the arrays change.
+    pub fn set_alloc_strategy(&mut self, strategy: Strategy) {
+        self.mem.set_strategy(strategy);
+    }
+
+    /// Like `Mem::set_quarantine`, keeping the decoded program.
+    pub fn set_quarantine(&mut self, quarantine: Quarantine) {
+        self.mem.set_quarantine(quarantine);
+    }
+
+    /// Like `Mem::set_limits`, keeping the decoded program.
+    pub fn set_mem_limits(&mut self, limits: MemLimits) {
+        self.mem.set_limits(limits);
+    }
+
+    /// Array the current program has been loaded from, 0 if it's still the
+    /// initial image. The array may have been freed or even reused since.
+    pub fn origin(&self) -> u32 {
+        self.origin
+    }
+
+    /// Number of instructions executed so far.
+    pub fn steps(&self) -> u64 {
+        self.steps
+    }
+
+    /// Turns collection of statistics on or off. Counting starts from zero
+    /// every time it's turned on.
+    pub fn collect_stats(&mut self, enable: bool) {
+        self.counts = if enable { Some(Box::default()) } else { None };
+        self.mem.track(enable);
+    }
+
+    /// Statistics collected since they were turned on, if they are.
+    pub fn stats(&self) -> Option<Stats> {
+        let counts = self.counts.as_ref()?;
+        Some(Stats {
+            instructions: counts.ops.iter().sum(),
+            op_counts: counts.ops,
+            program_loads: counts.program_loads,
+            live_arrays: self.mem.live_arrays(),
+            mem: self.mem.stats()?,
+        })
+    }
+
+    pub fn io(&self) -> &T {
+        &self.io
+    }
+
+    pub fn io_mut(&mut self) -> &mut T {
+        &mut self.io
+    }
+
+    pub fn into_io(self) -> T {
+        self.io
+    }
+
+    /// Replaces the I/O backend, keeping the rest of the machine.
+    pub fn map_io<U, F: FnOnce(T) -> U>(self, f: F) -> Machine<U> {
+        Machine {
+            reg: self.reg,
+            mem: self.mem,
+            code: self.code,
+            ip: self.ip,
+            io: f(self.io),
+            eof: self.eof,
+            origin: self.origin,
+            steps: self.steps,
+            counts: self.counts,
+        }
+    }
+
+    /// Copy of the machine state, which can be brought back with
+    /// `set_state`.
+    pub fn state(&self) -> State {
+        State {
+            reg: self.reg,
+            mem: self.mem.clone(),
+            code: self.code.clone(),
+            ip: self.ip,
+            eof: self.eof,
+            origin: self.origin,
+            steps: self.steps,
+        }
+    }
+
+    pub fn set_state(&mut self, state: State) {
+        self.reg = state.reg;
+        self.mem = state.mem;
+        self.code = state.code;
+        self.ip = state.ip;
+        self.eof = state.eof;
+        self.origin = state.origin;
+        self.steps = state.steps;
+    }
+
+    /// Registers, memory and the decoded program, for backends which
+    /// execute code themselves. Writes to array 0 must update the latter.
+    #[cfg(all(feature = "jit", target_arch = "x86_64", unix))]
+    pub(crate) fn parts_mut(&mut self) -> (&mut [u32; 8], &mut Mem, &mut Code) {
+        (&mut self.reg, &mut self.mem, &mut self.code)
+    }
+
+    /// Accounts for `steps` instructions having been executed by such a
+    /// backend, execution continues at `ip`. Which instructions they were is
+    /// told by `count_ops`.
+    #[cfg(all(feature = "jit", target_arch = "x86_64", unix))]
+    pub(crate) fn advance(&mut self, steps: u64, ip: u32) {
+        self.steps += steps;
+        self.ip = ip;
+    }
+
+    /// Counts each of `ops` as executed `times` times, for the statistics.
+    #[cfg(all(feature = "jit", target_arch = "x86_64", unix))]
+    pub(crate) fn count_ops(&mut self, ops: &[Op], times: u64) {
+        if let Some(counts) = &mut self.counts {
+            for op in ops {
+                counts.ops[op.opcode() as usize] += times;
+            }
+        }
+    }
+
+    /// Runs the machine until it stops or faults, then flushes the I/O
+    /// backend.
+    pub fn run(&mut self) -> Result<Status, Fault> {
+        self.run_with(Limits::default())
+    }
+
+    /// Like `run`, but gives up after executing `max_steps` instructions in
+    /// which case `Status::BudgetExhausted` is returned.
+    pub fn run_for(&mut self, max_steps: u64) -> Result<Status, Fault> {
+        self.run_with(Limits::steps(max_steps))
+    }
+
+    /// Like `run`, but stops with `Status::BudgetExhausted` or
+    /// `Status::TimedOut` once either of the limits is reached.
+    pub fn run_with(&mut self, limits: Limits) -> Result<Status, Fault> {
+        let timed = limits.timeout.is_some();
+        self.run_by(limits, |um, left| {
+            // Without a deadline there's no need to come back for the clock.
+            um.run_steps(if timed {
+                left.min(CLOCK_INTERVAL)
+            } else {
+                left
+            })
+        })
+    }
+
+    /// Runs the machine like `run_with`, but has `step` execute the
+    /// instructions. It gets the number of steps left in the budget and must
+    /// execute at least one and at most that many, unless it returns
+    /// something other than `Status::Running`.
+    pub fn run_by<F>(&mut self, limits: Limits, mut step: F) -> Result<Status, Fault>
+    where
+        F: FnMut(&mut Self, u64) -> Result<Status, Fault>,
+    {
+        // A deadline too far off to be represented is none at all.
+        let deadline = limits.timeout.and_then(|t| Instant::now().checked_add(t));
+        // No limit at all is a budget which never runs out in practice.
+        let end = limits
+            .max_steps
+            .map_or(u64::MAX, |n| self.steps.saturating_add(n));
+        let mut clock = self.steps;
+        let result = loop {
+            if self.steps >= end {
+                break Ok(Status::BudgetExhausted);
+            }
+            if self.steps >= clock {
+                if deadline.is_some_and(|d| Instant::now() >= d) {
+                    break Ok(Status::TimedOut);
+                }
+                clock = self.steps.saturating_add(CLOCK_INTERVAL);
+            }
+            match step(self, end - self.steps) {
+                Ok(Status::Running) => {}
+                result => break result,
+            }
+        };
+        // A fault is more interesting than a failure to flush after it.
+        let flushed = self.flush();
+        let status = result?;
+        flushed?;
+        Ok(status)
+    }
+
+    /// Executes up to `n` instructions, returns `Status::Running` if all of
+    /// them have been executed.
+    fn run_steps(&mut self, n: u64) -> Result<Status, Fault> {
+        // Separate loops so that the common one doesn't check for counting.
+        if self.counts.is_some() {
+            self.interpret::<true>(n)
+        } else {
+            self.interpret::<false>(n)
+        }
+    }
+
+    /// The interpreter loop. Everything which runs the interpreter ends up
+    /// here, so that there is a single copy of it to optimize.
+    fn interpret<const COUNT: bool>(&mut self, n: u64) -> Result<Status, Fault> {
+        let mut done = 0;
+        let result = loop {
+            if done == n {
+                break Ok(Status::Running);
+            }
+            let ip = self.ip;
+            let op = match self.code.get(ip) {
+                Some(op) => op,
+                None => match self.decode_slow(ip) {
+                    Ok(op) => op,
+                    Err(fault) => break Err(fault),
+                },
+            };
+
+            let status = match self.exec(op) {
+                Ok(Status::Eof) => break Ok(Status::Eof),
+                Ok(status) => status,
+                Err(error) => break Err(self.fault(ip, error)),
+            };
+            done += 1;
+            if COUNT {
+                if let Some(counts) = &mut self.counts {
+                    counts.ops[op.opcode() as usize] += 1;
+                }
+            }
+            if status != Status::Running {
+                break Ok(status);
+            }
+        };
+        self.steps += done;
+        result
+    }
+
+    /// Flushes pending output of the I/O backend, `run` does it on return.
+    pub fn flush(&mut self) -> Result<(), Fault> {
+        self.io.flush().map_err(|e| Fault {
+            ip: self.ip,
+            word: None,
+            error: VmError::from(e),
+        })
+    }
+
+    /// Decoded instruction at `ip`, `None` if there is no valid one.
+    pub fn next_op(&self) -> Option<Op> {
+        self.code.get(self.ip).or_else(|| {
+            let word = self.mem.read(0, self.ip).ok()?;
+            Op::parse(*word).ok()
+        })
+    }
+
+    /// Executes a single instruction at `ip`.
+    ///
+    /// On fault the machine state is left as it was before the faulting
+    /// instruction, so `ip` still points to it.
+    pub fn step(&mut self) -> Result<Status, Fault> {
+        self.run_steps(1)
+    }
+
+    /// Handles a miss in the decoded program: either the cache is stale, or
+    /// `ip` points outside of array 0 or to an invalid instruction.
+    #[cold]
+    fn decode_slow(&mut self, ip: u32) -> Result<Op, Fault> {
+        let program = self.mem.array(0).map_err(|error| self.fault(ip, error))?;
+        if self.code.len() != program.len() {
+            self.code = Code::decode(program);
+        }
+
+        let word = *self
+            .mem
+            .read(0, ip)
+            .map_err(|error| self.fault(ip, error))?;
+        Op::parse(word).map_err(|error| self.fault(ip, error))
+    }
+
+    fn fault(&self, ip: u32, error: VmError) -> Fault {
+        Fault {
+            ip,
+            word: self.mem.read(0, ip).ok().copied(),
+            error,
+        }
+    }
+
+    #[inline(always)]
+    fn exec(&mut self, op: Op) -> Result<Status, VmError> {
+        match op {
+            Op::CondMov(a, b, c) => {
+                if self.reg[c] != 0 {
+                    self.reg[a] = self.reg[b]
+                }
+            }
+
+            Op::MemRead(a, b, c) => {
+                self.reg[a] = *self.mem.read(self.reg[b], self.reg[c])?;
+            }
+
+            Op::MemWrite(a, b, c) => {
+                self.mem.write(self.reg[a], self.reg[b], self.reg[c])?;
+                if self.reg[a] == 0 {
+                    self.code.update(self.reg[b], self.reg[c]);
+                }
+            }
+
+            Op::Add(a, b, c) => {
+                self.reg[a] = self.reg[b].wrapping_add(self.reg[c]);
+            }
+
+            Op::Mul(a, b, c) => {
+                self.reg[a] = self.reg[b].wrapping_mul(self.reg[c]);
+            }
+
+            Op::Div(a, b, c) => {
+                if self.reg[c] == 0 {
+                    return Err(VmError::DivisionByZero);
+                }
+                self.reg[a] = self.reg[b].wrapping_div(self.reg[c]);
+            }
+
+            Op::Nand(a, b, c) => {
+                self.reg[a] = !(self.reg[b] & self.reg[c]);
+            }
+
+            Op::Halt => {
+                return Ok(Status::Halted);
+            }
+
+            Op::Alloc(b, c) => {
+                self.reg[b] = self.mem.alloc(self.reg[c])?;
+            }
+
+            Op::Free(c) => {
+                self.mem.free(self.reg[c])?;
+            }
+
+            Op::Output(c) => {
+                let chr = self.reg[c];
+
+                if chr > 255 {
+                    return Err(VmError::OutputOutOfRange(chr));
+                }
+
+                self.io.write_byte(chr as u8)?;
+            }
+
+            Op::Input(c) => match self.io.read_byte()? {
+                Some(byte) => {
+                    self.eof = false;
+                    self.reg[c] = u32::from(byte);
+                }
+                None if self.eof => return Ok(Status::Eof),
+                None => {
+                    // Signal end of input as the spec says: all bits set.
+                    self.eof = true;
+                    self.reg[c] = u32::MAX;
+                }
+            },
+
+            Op::LoadProgram(b, c) => {
+                let addr = self.reg[b];
+                if addr != 0 {
+                    // Array 0 still sharing storage with `addr` means that
+                    // neither was written since, so the decoded program is
+                    // still the right one.
+                    if !self.mem.is_shared(0, addr) {
+                        self.mem.copy_to_zero(addr)?;
+                        self.code = Code::decode(self.mem.array(0)?);
+                    }
+                    self.origin = addr;
+                    if let Some(counts) = &mut self.counts {
+                        counts.program_loads += 1;
+                    }
+                }
+                self.ip = self.reg[c];
+                return Ok(Status::Running); // to skip 'ip += 1'
+            }
+
+            Op::Mov(a, val) => {
+                self.reg[a] = val;
+            }
+        }
+
+        self.ip += 1;
+        Ok(Status::Running)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::io::Buffer;
+    use byteorder::WriteBytesExt;
+
+    fn image(words: &[u32]) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(words.len() * 4);
+        for w in words {
+            bytes.write_u32::<BigEndian>(*w).unwrap();
+        }
+        bytes
+    }
+
+    #[test]
+    fn load_words() {
+        let um = Machine::load(&image(&[0x7000_0000, 42])).unwrap();
+        assert_eq!(um.mem().read(0, 0), Ok(&0x7000_0000));
+        assert_eq!(um.mem().read(0, 1), Ok(&42));
+        assert_eq!(um.ip(), 0);
+    }
+
+    #[test]
+    fn load_truncated() {
+        let bytes = [0x70, 0, 0, 0, 0x70];
+        assert!(Machine::load(&bytes).is_err());
+        assert!(Machine::load_with_io(&bytes, Buffer::default()).is_err());
+        assert!(Machine::open(&bytes).is_err());
+        assert!(Machine::open_with_io(&bytes, Buffer::default()).is_err());
+
+        let mut container = Vec::new();
+        let image = Image {
+            program: vec![0x7000_0000],
+            ..Image::default()
+        };
+        image.write(&mut container).unwrap();
+        container.pop();
+        assert!(Machine::open(&container).is_err());
+        assert!(Machine::open_with_io(&container, Buffer::default()).is_err());
+
+        let um = Machine::load_with_io(&bytes[..4], Buffer::default()).unwrap();
+        let mut snapshot = Vec::new();
+        um.save(&mut snapshot).unwrap();
+        snapshot.pop();
+        assert!(Machine::restore(&mut &snapshot[..]).is_err());
+        assert!(Machine::restore_with_io(&mut &snapshot[..], Buffer::default()).is_err());
+    }
+
+    #[test]
+    fn from_image() {
+        let image = Image {
+            program: vec![
+                0xF000_0000, // invalid, skipped by the entry ip
+                0x1000_000A, // index r0, r1, r2
+                0xA000_0000, // output r0
+                0x7000_0000, // halt
+            ],
+            entry: 1,
+            regs: [0, 2, 1, 0, 0, 0, 0, 0],
+            arrays: vec![vec![0; 3], vec![b'a'.into(), b'b'.into()]],
+            ..Image::default()
+        };
+        let mut um = Machine::from_image(image, Buffer::default());
+        assert_eq!(um.ip(), 1);
+        assert_eq!(um.mem().live_arrays(), 3);
+        assert_eq!(um.run(), Ok(Status::Halted));
+        assert_eq!(um.io().output(), b"b");
+    }
+
+    #[test]
+    fn run_arith() {
+        let prog = image(&[
+            0xD200_0007, // mov r1, 7
+            0xD400_0006, // mov r2, 6
+            0x4000_0011, // mul r0, r2, r1
+            0x7000_0000, // halt
+        ]);
+        let mut um = Machine::load(&prog).unwrap();
+        um.collect_stats(true);
+        assert_eq!(um.run(), Ok(Status::Halted));
+        assert_eq!(um.reg(0), 42);
+        assert_eq!(um.ip(), 3);
+        assert_eq!(um.steps(), 4);
+
+        let stats = um.stats().unwrap();
+        assert_eq!(stats.instructions, 4);
+        assert_eq!(stats.op_counts[13], 2);
+        assert_eq!(stats.op_counts[4], 1);
+        assert_eq!(stats.op_counts[7], 1);
+    }
+
+    #[test]
+    fn run_for_steps() {
+        let prog = image(&[
+            0xD200_0001, // mov r1, 1
+            0xD200_0002, // mov r1, 2
+            0x7000_0000, // halt
+        ]);
+        let mut um = Machine::load_with_io(&prog, Buffer::default()).unwrap();
+        assert_eq!(um.next_op(), Some(Op::Mov(1, 1)));
+        assert_eq!(um.run_for(1), Ok(Status::BudgetExhausted));
+        assert_eq!(um.next_op(), Some(Op::Mov(1, 2)));
+        assert_eq!(um.steps(), 1);
+        assert_eq!(um.run_for(10), Ok(Status::Halted));
+        assert_eq!(um.reg(1), 2);
+        assert_eq!(um.steps(), 3);
+    }
+
+    #[test]
+    fn limits() {
+        let prog = image(&[
+            0xD200_0000, // mov r1, 0
+            0xC000_0009, // loadprog r1, r1
+        ]);
+        let mut um = Machine::load_with_io(&prog, Buffer::default()).unwrap();
+        assert_eq!(um.run_for(0), Ok(Status::BudgetExhausted));
+        assert_eq!(um.run_for(100_001), Ok(Status::BudgetExhausted));
+        assert_eq!(um.steps(), 100_001);
+
+        let timeout = Duration::from_millis(20);
+        assert_eq!(um.run_with(Limits::timeout(timeout)), Ok(Status::TimedOut));
+        let both = Limits {
+            max_steps: Some(10),
+            timeout: Some(timeout),
+        };
+        assert_eq!(um.run_with(both), Ok(Status::BudgetExhausted));
+        let forever = Limits {
+            max_steps: Some(10),
+            timeout: Some(Duration::MAX),
+        };
+        assert_eq!(um.run_with(forever), Ok(Status::BudgetExhausted));
+    }
+
+    #[test]
+    fn step_by_step() {
+        let mut um = Machine::load(&image(&[0xD200_0001, 0x7000_0000])).unwrap();
+        assert_eq!(um.step(), Ok(Status::Running));
+        assert_eq!(um.reg(1), 1);
+        assert_eq!(um.ip(), 1);
+        assert_eq!(um.step(), Ok(Status::Halted));
+    }
+
+    #[test]
+    fn fault_div_by_zero() {
+        let prog = image(&[
+            0xD200_0007, // mov r1, 7
+            0x5000_0008, // div r0, r1, r0
+            0x7000_0000, // halt
+        ]);
+        let mut um = Machine::load(&prog).unwrap();
+        let fault = um.run().unwrap_err();
+        assert_eq!(
+            fault,
+            Fault {
+                ip: 1,
+                word: Some(0x5000_0008),
+                error: VmError::DivisionByZero,
+            }
+        );
+        assert_eq!(um.ip(), 1);
+    }
+
+    #[test]
+    fn fault_ip_out_of_bounds() {
+        let mut um = Machine::load(&image(&[0xD200_0001])).unwrap();
+        let fault = um.run().unwrap_err();
+        assert_eq!(fault.ip, 1);
+        assert_eq!(fault.word, None);
+        assert_eq!(
+            fault.error,
+            VmError::ReadOutOfBounds {
+                addr: 0,
+                offset: 1,
+                len: 1
+            }
+        );
+    }
+
+    #[test]
+    fn fault_invalid_opcode() {
+        let mut um = Machine::load(&image(&[0xF000_0000])).unwrap();
+        assert_eq!(um.step().unwrap_err().error, VmError::InvalidOpcode(15));
+    }
+
+    #[test]
+    fn output_to_buffer() {
+        let prog = image(&[
+            0xD000_0048, // mov r0, 'H'
+            0xA000_0000, // out r0
+            0xD000_0069, // mov r0, 'i'
+            0xA000_0000, // out r0
+            0x7000_0000, // halt
+        ]);
+        let mut um = Machine::load_with_io(&prog, Buffer::default()).unwrap();
+        um.run().unwrap();
+        assert_eq!(um.io().output(), b"Hi");
+    }
+
+    #[test]
+    fn binary_echo() {
+        let prog = image(&[
+            0xB000_0000, // in r0
+            0xA000_0000, // out r0
+            0xB000_0000, // in r0
+            0xA000_0000, // out r0
+            0x7000_0000, // halt
+        ]);
+        let mut um = Machine::load_with_io(&prog, Buffer::new(vec![0xff, 0])).unwrap();
+        assert_eq!(um.run(), Ok(Status::Halted));
+        assert_eq!(um.io().output(), &[0xff, 0]);
+    }
+
+    #[test]
+    fn stop_on_repeated_eof() {
+        let prog = image(&[
+            0xB000_0000, // in r0
+            0xD200_0000, // mov r1, 0
+            0xC000_0009, // loadprog r1, r1
+        ]);
+        let mut um = Machine::load_with_io(&prog, Buffer::new("ab")).unwrap();
+        assert_eq!(um.run(), Ok(Status::Eof));
+        assert_eq!(um.ip(), 0);
+        assert_eq!(um.reg(0), u32::MAX);
+
+        // More input arrives, the machine picks up where it stopped.
+        um.io_mut().feed(b"z");
+        assert_eq!(um.step(), Ok(Status::Running));
+        assert_eq!(um.reg(0), u32::from(b'z'));
+        assert_eq!(um.run(), Ok(Status::Eof));
+    }
+
+    #[test]
+    fn input_eof_is_all_ones() {
+        let prog = image(&[
+            0xB000_0000, // in r0
+            0x7000_0000, // halt
+        ]);
+        let mut um = Machine::load_with_io(&prog, Buffer::default()).unwrap();
+        assert_eq!(um.run(), Ok(Status::Halted));
+        assert_eq!(um.reg(0), 0xFFFF_FFFF);
+    }
+
+    #[test]
+    fn self_modifying_code() {
+        let prog = image(&[
+            0xD800_0006, // mov r4, 6
+            0x1000_0084, // read r2 <- [r0 + r4]
+            0xDA00_0004, // mov r5, 4
+            0x2000_002A, // write [r0 + r5] <- r2
+            0x7000_0000, // halt, replaced by the word at 6
+            0x7000_0000, // halt
+            0xD600_002A, // mov r3, 42
+        ]);
+        let mut um = Machine::load_with_io(&prog, Buffer::default()).unwrap();
+        assert_eq!(um.run(), Ok(Status::Halted));
+        assert_eq!(um.reg(3), 42);
+        assert_eq!(um.ip(), 5);
+    }
+
+    #[test]
+    fn patch_through_mem_mut() {
+        let prog = image(&[
+            0xD200_0001, // mov r1, 1
+            0xD200_0002, // mov r1, 2
+            0x7000_0000, // halt
+        ]);
+        let mut um = Machine::load_with_io(&prog, Buffer::default()).unwrap();
+        um.step().unwrap();
+        um.mem_mut().write(0, 1, 0x7000_0000).unwrap();
+        assert_eq!(um.step(), Ok(Status::Halted));
+        assert_eq!(um.reg(1), 1);
+    }
+
+    #[test]
+    fn load_program_from_array() {
+        let prog = image(&[
+            0xD200_0001, // mov r1, 1
+            0x8000_0011, // alloc r2 <- r1
+            0xD600_0000, // mov r3, 0
+            0x1000_0103, // read r4 <- [r0 + r3]
+            0x2000_009C, // write [r2 + r3] <- r4
+            0xC000_0013, // loadprog r2, r3
+        ]);
+        let mut um = Machine::load_with_io(&prog, Buffer::default()).unwrap();
+        um.collect_stats(true);
+        // The new program is a single 'mov r1, 1' followed by nothing.
+        let fault = um.run().unwrap_err();
+        assert_eq!(fault.ip, 1);
+        assert_eq!(um.mem().array(0), Ok(&[0xD200_0001][..]));
+        assert_eq!(um.origin(), 1);
+
+        let stats = um.stats().unwrap();
+        assert_eq!(stats.program_loads, 1);
+        assert_eq!(stats.live_arrays, 2);
+        assert_eq!(stats.mem.allocs, 1);
+        assert_eq!(stats.mem.live_words, 1);
+        assert_eq!(stats.mem.bytes_copied, 0);
+    }
+
+    #[test]
+    fn reload_program() {
+        let prog = image(&[0xC000_0010]); // loadprog r2, r0
+        let mut um = Machine::load_with_io(&prog, Buffer::default()).unwrap();
+        let id = um.mem_mut().alloc(5).unwrap();
+        let program = [
+            0xA000_0001, // output r1
+            0xC000_0013, // loadprog r2, r3, array 0 is shared
+            0x2000_00A5, // write [r2 + r4] <- r5, which unshares it
+            0xA000_0001, // output r1
+            0xC000_0016, // loadprog r2, r6, array 1 has a halt at 3 now
+        ];
+        for (offset, &word) in program.iter().enumerate() {
+            um.mem_mut().write(id, offset as u32, word).unwrap();
+        }
+        for (r, val) in [
+            (1, u32::from(b'a')),
+            (2, id),
+            (3, 2),
+            (4, 3),
+            (5, 0x7000_0000),
+            (6, 3),
+        ] {
+            um.set_reg(r, val);
+        }
+
+        assert_eq!(um.run_for(100), Ok(Status::Halted));
+        assert_eq!(um.io().output(), b"aa");
+        assert_eq!(um.ip(), 3);
+    }
+
+    #[test]
+    fn snapshot_roundtrip() {
+        let prog = image(&[
+            0xD200_0001, // mov r1, 1
+            0x8000_0011, // alloc r2 <- r1
+            0xD600_0000, // mov r3, 0
+            0x1000_0103, // read r4 <- [r0 + r3]
+            0x2000_009C, // write [r2 + r3] <- r4
+            0xC000_0013, // loadprog r2, r3
+        ]);
+        let mut um = Machine::load_with_io(&prog, Buffer::default()).unwrap();
+        assert_eq!(um.run_for(6), Ok(Status::BudgetExhausted));
+        let mut snapshot = Vec::new();
+        um.save(&mut snapshot).unwrap();
+
+        let mut copy = Machine::restore_with_io(&mut &snapshot[..], Buffer::default()).unwrap();
+        assert_eq!(copy.regs(), um.regs());
+        assert_eq!(copy.ip(), 0);
+        assert_eq!(copy.steps(), 6);
+        assert_eq!(copy.origin(), 1);
+        assert!(copy.mem().is_shared(0, 1));
+        assert_eq!(copy.run(), um.run());
+
+        snapshot[8] = 2;
+        let err = Machine::restore_with_io(&mut &snapshot[..], Buffer::default()).err();
+        assert_eq!(err.map(|e| e.kind()), Some(io::ErrorKind::InvalidData));
+    }
+}
@@ -1,194 +1,482 @@
-use byteorder::BigEndian;
-use byteorder::ReadBytesExt;
-use byteorder::WriteBytesExt;
+mod cli;
+
+use cli::{BenchOptions, Command, FileOptions, ImageOptions, RunOptions};
 use std::env;
+use std::error::Error;
 use std::fs;
 use std::io;
-use std::io::Write;
-use um::mem::Mem;
+use std::io::{BufRead, Write};
+use std::process;
+use std::time::Instant;
+use um::asm;
+use um::bench::{self, Baseline, Report, Workload};
+use um::debugger::Debugger;
+use um::disasm;
+use um::image::{self, Format, Image};
+use um::io::{Console, Io};
+#[cfg(all(feature = "jit", target_arch = "x86_64", unix))]
+use um::jit::Jit;
+use um::machine::{Fault, Limits, Machine, Status};
+use um::mem::Strategy;
+use um::profile::Profiler;
+use um::sanitize::Sanitizer;
+use um::trace::Tracer;
+
+const EXIT_FAULT: i32 = 1;
+const EXIT_USAGE: i32 = 2;
+const EXIT_EOF: i32 = 3;
+const EXIT_MAX_STEPS: i32 = 4;
+const EXIT_TIMEOUT: i32 = 5;
+const EXIT_SANITIZE: i32 = 6;
+
+/// Address ranges listed by --profile.
+const PROFILE_RANGES: usize = 20;
+
+fn fail(msg: &str, code: i32) -> ! {
+    eprintln!("um: {}", msg);
+    process::exit(code);
+}
+
+fn read(path: &str) -> Vec<u8> {
+    fs::read(path).unwrap_or_else(|e| fail(&format!("{}: {}", path, e), EXIT_USAGE))
+}
 
-struct Machine {
-    reg: [u32; 8],
-    mem: Mem,
-    ip: u32,
+/// Opens `path` for writing, - stands for stderr.
+fn create(path: &str) -> io::BufWriter<Box<dyn Write>> {
+    let out: Box<dyn Write> = match path {
+        "-" => Box::new(io::stderr()),
+        _ => match fs::File::create(path) {
+            Ok(file) => Box::new(file),
+            Err(e) => fail(&format!("{}: {}", path, e), EXIT_USAGE),
+        },
+    };
+    io::BufWriter::new(out)
 }
 
-impl Machine {
-    fn load(bytes: &[u8]) -> Self {
-        // Here we convert from bytes (u8) to our VM words (u32).
-        assert_eq!(
-            bytes.len() % 4,
-            0,
-            "Program must have whole number of u32's"
-        );
+type Trace = Tracer<io::BufWriter<Box<dyn Write>>>;
 
-        let size = bytes.len() / 4;
-        let mut reader = io::Cursor::new(bytes);
-        let mut program = Vec::with_capacity(size);
+/// What executes the program.
+enum Backend {
+    Interpreter,
+    Trace(Trace),
+    Profile(Profiler),
+    Sanitize(Sanitizer),
+    #[cfg(all(feature = "jit", target_arch = "x86_64", unix))]
+    Jit(Jit),
+}
 
-        for _ in 0..size {
-            let word32 = reader.read_u32::<BigEndian>().unwrap();
-            program.push(word32);
+impl Backend {
+    fn new(opts: &RunOptions) -> Self {
+        if let Some(path) = &opts.trace {
+            Backend::Trace(Tracer::new(create(path), opts.trace_filter.clone()))
+        } else if opts.profile.is_some() || opts.profile_folded.is_some() {
+            Backend::Profile(Profiler::new())
+        } else if opts.sanitize {
+            Backend::Sanitize(Sanitizer::new())
+        } else if opts.jit {
+            Backend::jit()
+        } else {
+            Backend::Interpreter
         }
+    }
 
-        Machine {
-            reg: [0; 8],
-            mem: Mem::init(program),
-            ip: 0,
+    #[cfg(all(feature = "jit", target_arch = "x86_64", unix))]
+    fn jit() -> Self {
+        Backend::Jit(Jit::new())
+    }
+
+    #[cfg(not(all(feature = "jit", target_arch = "x86_64", unix)))]
+    fn jit() -> Self {
+        fail(
+            "--jit needs a build with the jit feature on x86_64",
+            EXIT_USAGE,
+        )
+    }
+
+    /// Runs the machine within `limits`.
+    fn run<T: Io>(&mut self, um: &mut Machine<T>, limits: Limits) -> Result<Status, Fault> {
+        match self {
+            Backend::Interpreter if limits == Limits::default() => um.run(),
+            Backend::Interpreter => um.run_with(limits),
+            Backend::Trace(tracer) => tracer.run(um, limits),
+            Backend::Profile(profiler) => profiler.run(um, limits),
+            Backend::Sanitize(sanitizer) => sanitizer.run(um, limits),
+            #[cfg(all(feature = "jit", target_arch = "x86_64", unix))]
+            Backend::Jit(jit) => jit.run(um, limits),
         }
     }
+}
 
-    fn run(&mut self) {
-        let mut input = String::new();
-        loop {
-            let word = self.mem.read(0, self.ip);
-            let op = Op::parse(*word);
+/// Writes the profile of a run to the files asked for.
+fn write_profile(profiler: &Profiler, opts: &RunOptions) {
+    if let Some(path) = &opts.profile {
+        let mut out = create(path);
+        let report = profiler.report(PROFILE_RANGES);
+        if let Err(e) = write!(out, "{}", report).and_then(|_| out.flush()) {
+            eprintln!("um: {}: {}", path, e);
+        }
+    }
+    if let Some(path) = &opts.profile_folded {
+        let mut out = create(path);
+        if let Err(e) = profiler.write_folded(&mut out).and_then(|_| out.flush()) {
+            eprintln!("um: {}: {}", path, e);
+        }
+    }
+}
 
-            match op {
-                Op::CondMov(a, b, c) => {
-                    if self.reg[c] != 0 {
-                        self.reg[a] = self.reg[b]
-                    }
-                }
+fn save<T: Io>(um: &Machine<T>, path: &str) {
+    let saved = fs::File::create(path).and_then(|file| {
+        let mut out = io::BufWriter::new(file);
+        um.save(&mut out)?;
+        out.flush()
+    });
+    match saved {
+        Ok(()) => eprintln!("um: saved snapshot at step {} to {}", um.steps(), path),
+        Err(e) => fail(&format!("{}: {}", path, e), EXIT_USAGE),
+    }
+}
 
-                Op::MemRead(a, b, c) => {
-                    self.reg[a] = *self.mem.read(self.reg[b], self.reg[c]);
-                }
+fn load(opts: &RunOptions) -> Machine<Console> {
+    let prog = read(&opts.program);
+    let image = if opts.restore {
+        None
+    } else {
+        Some(
+            Image::read(&prog)
+                .unwrap_or_else(|e| fail(&format!("{}: {}", opts.program, e), EXIT_USAGE)),
+        )
+    };
+    let mut script = image.as_ref().map_or_else(Vec::new, |i| i.input.clone());
+    for path in &opts.inputs {
+        script.extend(read(path));
+    }
 
-                Op::MemWrite(a, b, c) => {
-                    self.mem.write(self.reg[a], self.reg[b], self.reg[c]);
-                }
+    let console = Console::with_policy(opts.flush).with_script(script, opts.then_stdin);
+    let mut um = match image {
+        Some(image) => Machine::from_image(image, console),
+        None => Machine::restore_with_io(&mut &prog[..], console)
+            .unwrap_or_else(|e| fail(&format!("{}: {}", opts.program, e), EXIT_USAGE)),
+    };
+    if let Some(alloc) = opts.alloc {
+        um.set_alloc_strategy(alloc);
+    }
+    if let Some(quarantine) = opts.quarantine {
+        um.set_quarantine(quarantine);
+    }
+    um.set_mem_limits(opts.mem_limits);
+    um
+}
 
-                Op::Add(a, b, c) => {
-                    self.reg[a] = self.reg[b].wrapping_add(self.reg[c]);
-                }
+fn run(opts: RunOptions) -> i32 {
+    let mut um = load(&opts);
+    um.collect_stats(opts.stats);
 
-                Op::Mul(a, b, c) => {
-                    self.reg[a] = self.reg[b].wrapping_mul(self.reg[c]);
-                }
+    let mut backend = Backend::new(&opts);
+    let mut snapshots: Vec<u64> = opts
+        .snapshots
+        .iter()
+        .copied()
+        .filter(|&n| n >= um.steps())
+        .collect();
+    snapshots.sort_unstable();
+    snapshots.dedup();
+    let limit = opts.max_steps.map(|n| um.steps() + n);
 
-                Op::Div(a, b, c) => {
-                    if self.reg[c] == 0 {
-                        panic!("vm: division by zero!");
-                    }
-                    self.reg[a] = self.reg[b].wrapping_div(self.reg[c]);
+    let start = Instant::now();
+    let deadline = opts.timeout.and_then(|t| start.checked_add(t));
+    let result = loop {
+        let next = snapshots.first().copied();
+        let until = match (next, limit) {
+            (Some(next), Some(limit)) => Some(next.min(limit)),
+            (next, limit) => next.or(limit),
+        };
+        let limits = Limits {
+            max_steps: until.map(|n| n - um.steps()),
+            timeout: deadline.map(|d| d.saturating_duration_since(Instant::now())),
+        };
+        let result = backend.run(&mut um, limits);
+        match result {
+            Ok(Status::BudgetExhausted) if next == Some(um.steps()) => {
+                snapshots.remove(0);
+                save(&um, &format!("{}.{}.snap", opts.program, um.steps()));
+                if until != limit {
+                    continue;
                 }
+                break result;
+            }
+            result => break result,
+        }
+    };
+    let elapsed = start.elapsed();
 
-                Op::Nand(a, b, c) => {
-                    self.reg[a] = !(self.reg[b] & self.reg[c]);
-                }
+    let mut sanitized = true;
+    // Whether the fault, if any, has been reported as a finding already.
+    let mut diagnosed = false;
+    match backend {
+        Backend::Trace(tracer) => {
+            if let (Err(e), Some(path)) = (tracer.finish(), &opts.trace) {
+                eprintln!("um: {}: {}", path, e);
+            }
+        }
+        Backend::Profile(profiler) => write_profile(&profiler, &opts),
+        Backend::Sanitize(sanitizer) => {
+            for finding in sanitizer.findings() {
+                eprintln!("um: sanitize: {}", finding);
+            }
+            sanitized = sanitizer.findings().is_empty();
+            diagnosed = result.as_ref().is_err_and(|f| sanitizer.diagnosed(f));
+        }
+        _ => {}
+    }
+    if let (Ok(_), Some(path)) = (&result, &opts.save_on_halt) {
+        save(&um, path);
+    }
 
-                Op::Halt => {
-                    break;
-                }
+    let code = match result {
+        Ok(Status::Halted) if sanitized => 0,
+        Ok(Status::Halted) => EXIT_SANITIZE,
+        Ok(Status::Eof) => EXIT_EOF,
+        Ok(Status::BudgetExhausted) | Ok(Status::Running) => {
+            eprintln!("um: stopped after {} instructions", um.steps());
+            EXIT_MAX_STEPS
+        }
+        Ok(Status::TimedOut) => {
+            eprintln!(
+                "um: timed out after {:.1}s and {} instructions",
+                elapsed.as_secs_f64(),
+                um.steps()
+            );
+            EXIT_TIMEOUT
+        }
+        Err(ref fault) => {
+            if !diagnosed {
+                eprintln!("{}", fault);
+            }
+            EXIT_FAULT
+        }
+    };
 
-                Op::Alloc(b, c) => {
-                    self.reg[b] = self.mem.alloc(self.reg[c]);
-                }
+    if opts.stats {
+        let report = Report {
+            name: opts.program,
+            status: result.unwrap_or(Status::Running),
+            instructions: um.steps(),
+            elapsed,
+            stats: um.stats().expect("stats are collected"),
+        };
+        eprint!("{}", report);
+    }
+    code
+}
 
-                Op::Free(c) => {
-                    self.mem.free(self.reg[c]);
-                }
+fn debug(opts: RunOptions) -> i32 {
+    let mut dbg = Debugger::new(load(&opts));
+    let stdin = io::stdin();
+    let mut stdout = io::stdout();
+    let mut line = String::new();
 
-                Op::Output(c) => {
-                    let chr = self.reg[c];
+    let _ = dbg.command("list", &mut stdout);
+    loop {
+        print!("(um) ");
+        let _ = stdout.flush();
 
-                    if chr > 255 {
-                        panic!("vm: character for output > 255: {}", chr);
-                    }
+        line.clear();
+        match stdin.lock().read_line(&mut line) {
+            Ok(0) => break,
+            Ok(_) => {}
+            Err(e) => fail(&e.to_string(), EXIT_USAGE),
+        }
+        match dbg.command(&line, &mut stdout) {
+            Ok(true) => continue,
+            Ok(false) => break,
+            Err(e) => fail(&e.to_string(), EXIT_USAGE),
+        }
+    }
+    0
+}
 
-                    io::stdout()
-                        .write_u8(chr as u8)
-                        .and_then(|()| io::stdout().flush())
-                        .expect("vm: writing character failed");
-                }
+#[cfg(all(feature = "jit", target_arch = "x86_64", unix))]
+fn bench_jit(workload: &Workload, alloc: Strategy) -> Result<Report, Box<dyn Error>> {
+    bench::run_jit(workload, alloc)
+}
 
-                Op::Input(c) => {
-                    if input.len() == 0 {
-                        // Read a new line of input.
-                        io::stdin().read_line(&mut input).unwrap();
-                    }
-
-                    if input.len() == 0 {
-                        // If it's still empty, this means we've got EOF,
-                        // treat it as terminate.
-                        println!("");
-                        break;
-                    } else {
-                        self.reg[c] = input.remove(0) as u32;
-                    }
-                }
+#[cfg(not(all(feature = "jit", target_arch = "x86_64", unix)))]
+fn bench_jit(_: &Workload, _: Strategy) -> Result<Report, Box<dyn Error>> {
+    fail(
+        "--jit needs a build with the jit feature on x86_64",
+        EXIT_USAGE,
+    )
+}
 
-                Op::LoadProgram(b, c) => {
-                    self.mem.copy_to_zero(self.reg[b]);
-                    self.ip = self.reg[c];
-                    continue; // to skip 'ip += 1'
-                }
+fn bench(opts: BenchOptions) -> i32 {
+    let baseline: Option<Baseline> = opts.baseline.map(|path| {
+        let text = String::from_utf8_lossy(&read(&path)).into_owned();
+        text.parse()
+            .unwrap_or_else(|e| fail(&format!("{}: {}", path, e), EXIT_USAGE))
+    });
 
-                Op::Mov(a, val) => {
-                    self.reg[a] = val;
-                }
-            }
+    let workloads = match opts.files.split_first() {
+        Some((image, inputs)) => vec![Workload::from_files(image, inputs)
+            .unwrap_or_else(|e| fail(&e.to_string(), EXIT_USAGE))],
+        None => Workload::bundled("."),
+    };
+    if workloads.is_empty() {
+        fail("no images to benchmark", EXIT_USAGE);
+    }
+
+    let mut reports = Vec::new();
+    let mut regressed = false;
+    for workload in &workloads {
+        let report = if opts.jit {
+            bench_jit(workload, opts.alloc)
+        } else {
+            bench::run(workload, opts.alloc)
+        };
+        let report = report.unwrap_or_else(|e| {
+            let code = if e.is::<Fault>() {
+                EXIT_FAULT
+            } else {
+                EXIT_USAGE
+            };
+            fail(&format!("{}: {}", workload.name, e), code)
+        });
+        print!("{}", report);
 
-            self.ip += 1;
-        }
-    }
-}
-
-type Reg = usize;
-
-#[derive(Debug)]
-enum Op {
-    CondMov(Reg, Reg, Reg),
-    MemRead(Reg, Reg, Reg),
-    MemWrite(Reg, Reg, Reg),
-    Add(Reg, Reg, Reg),
-    Mul(Reg, Reg, Reg),
-    Div(Reg, Reg, Reg),
-    Nand(Reg, Reg, Reg),
-    Halt,
-    Alloc(Reg, Reg),
-    Free(Reg),
-    Output(Reg),
-    Input(Reg),
-    LoadProgram(Reg, Reg),
-    Mov(Reg, u32),
-}
-
-impl Op {
-    fn parse(v: u32) -> Op {
-        let code = v >> 28;
-        let a = ((v & 0b111000000_u32) >> 6) as usize;
-        let b = ((v & 0b111000_u32) >> 3) as usize;
-        let c = (v & 0b111_u32) as usize;
-        match code {
-            0 => Op::CondMov(a, b, c),
-            1 => Op::MemRead(a, b, c),
-            2 => Op::MemWrite(a, b, c),
-            3 => Op::Add(a, b, c),
-            4 => Op::Mul(a, b, c),
-            5 => Op::Div(a, b, c),
-            6 => Op::Nand(a, b, c),
-            7 => Op::Halt,
-            8 => Op::Alloc(b, c),
-            9 => Op::Free(c),
-            10 => Op::Output(c),
-            11 => Op::Input(c),
-            12 => Op::LoadProgram(b, c),
-            13 => {
-                let a = ((v >> 25) & 0b111_u32) as usize;
-                let val = v & 0x01FFFFFF_u32;
-                Op::Mov(a, val)
+        if let Some(cmp) = baseline.as_ref().and_then(|b| b.compare(&report)) {
+            let change = (cmp.speedup - 1.0) * 100.0;
+            println!("  {:+.1}% MIPS against baseline", change);
+            if !cmp.same_instructions {
+                println!("  instruction count differs from baseline");
+                regressed = true;
+            }
+            if change < -opts.tolerance {
+                regressed = true;
             }
-            _ => panic!("vm: unexpected op code: {}", code),
         }
+        reports.push(report);
+    }
+
+    if let Some(path) = opts.save {
+        if let Err(e) = fs::write(&path, Baseline::from_reports(&reports).to_string()) {
+            fail(&format!("{}: {}", path, e), EXIT_USAGE);
+        }
+    }
+    if regressed {
+        1
+    } else {
+        0
+    }
+}
+
+fn disasm(program: &str) -> i32 {
+    let bytes = read(program);
+    let bytes = image::decompress(&bytes)
+        .unwrap_or_else(|e| fail(&format!("{}: {}", program, e), EXIT_USAGE));
+    let (image, trailing) = if Format::detect(&bytes) == Format::Container {
+        let image = Image::read(&bytes)
+            .unwrap_or_else(|e| fail(&format!("{}: {}", program, e), EXIT_USAGE));
+        (image, 0)
+    } else {
+        let (words, trailing) = disasm::words(&bytes);
+        (Image::new(words), trailing)
+    };
+    let stdout = io::stdout();
+    let mut out = io::BufWriter::new(stdout.lock());
+    let mut symbols = image.symbols.iter().peekable();
+    for line in disasm::disassemble(&image.program) {
+        let mut written = Ok(());
+        while let Some((_, name)) = symbols.next_if(|(addr, _)| *addr <= line.addr) {
+            written = written.and_then(|()| writeln!(out, "{}:", name));
+        }
+        if written.and_then(|()| writeln!(out, "{}", line)).is_err() {
+            // Most likely a closed pipe, e.g. `um disasm prog.um | head`.
+            return 0;
+        }
+    }
+    let _ = out.flush();
+    if trailing != 0 {
+        eprintln!("um: {}: ignored {} trailing bytes", program, trailing);
+    }
+    0
+}
+
+fn asm(opts: FileOptions) -> i32 {
+    let source = String::from_utf8_lossy(&read(&opts.input)).into_owned();
+    let (words, symbols) = asm::assemble_symbols(&source)
+        .unwrap_or_else(|e| fail(&format!("{}: {}", opts.input, e), EXIT_FAULT));
+    let bytes = if opts.image.is_empty() {
+        asm::image(&words)
+    } else {
+        let mut image = Image::new(words);
+        if opts.image.symbols {
+            image.symbols = symbols;
+        }
+        container(configure(image, &opts.image))
+    };
+    if let Err(e) = fs::write(&opts.output, bytes) {
+        fail(&format!("{}: {}", opts.output, e), EXIT_USAGE);
+    }
+    0
+}
+
+fn pack(opts: FileOptions) -> i32 {
+    let image = Image::read(&read(&opts.input))
+        .unwrap_or_else(|e| fail(&format!("{}: {}", opts.input, e), EXIT_USAGE));
+    let image = configure(image, &opts.image);
+    let bytes = if image.has_metadata() {
+        container(image)
+    } else {
+        image::pack(&image.program)
+    };
+    if let Err(e) = fs::write(&opts.output, bytes) {
+        fail(&format!("{}: {}", opts.output, e), EXIT_USAGE);
     }
+    0
 }
 
-fn main() -> io::Result<()> {
-    let args: Vec<String> = env::args().collect();
-    let prog = fs::read(&args[1])?;
-    let mut um = Machine::load(&prog);
-    um.run();
-    Ok(())
+/// Applies the image options of asm and pack.
+fn configure(mut image: Image, opts: &ImageOptions) -> Image {
+    if let Some(entry) = opts.entry {
+        image.entry = entry;
+    }
+    for &(r, val) in &opts.regs {
+        image.regs[r] = val;
+    }
+    for path in &opts.arrays {
+        let array = Image::read(&read(path))
+            .unwrap_or_else(|e| fail(&format!("{}: {}", path, e), EXIT_USAGE));
+        image.arrays.push(array.program);
+    }
+    for path in &opts.inputs {
+        image.input.extend(read(path));
+    }
+    image
+}
+
+fn container(image: Image) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    image.write(&mut bytes).expect("writing to a Vec");
+    bytes
+}
+
+fn main() {
+    let code = match cli::parse(env::args().skip(1)) {
+        Ok(Command::Run(opts)) => run(opts),
+        Ok(Command::Debug(opts)) => debug(opts),
+        Ok(Command::Bench(opts)) => bench(opts),
+        Ok(Command::Disasm(program)) => disasm(&program),
+        Ok(Command::Asm(opts)) => asm(opts),
+        Ok(Command::Pack(opts)) => pack(opts),
+        Ok(Command::Help) => {
+            print!("{}", cli::USAGE);
+            0
+        }
+        Err(msg) => {
+            eprintln!("um: {}", msg);
+            eprint!("{}", cli::USAGE);
+            EXIT_USAGE
+        }
+    };
+    let _ = io::Write::flush(&mut io::stdout());
+    process::exit(code);
 }
@@ -1,106 +1,793 @@
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
 use std::cmp::Reverse;
-use std::collections::BinaryHeap;
-use std::u32;
+use std::collections::{BinaryHeap, VecDeque};
+use std::io;
+use std::io::{Read, Write};
+use std::mem;
+use std::str::FromStr;
 
+use crate::error::VmError;
+
+/// How `Mem` picks ids and storage for new arrays.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Strategy {
+    /// Reuses the lowest freed id, every array gets an allocation of its
+    /// own.
+    #[default]
+    Lowest,
+    /// Reuses the most recently freed id, in constant time.
+    Lifo,
+    /// Reuses ids like `Lifo`, arrays of up to `SLAB_MAX` words are packed
+    /// into slabs of equally sized slots.
+    Slab,
+}
+
+impl FromStr for Strategy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "lowest" => Ok(Strategy::Lowest),
+            "lifo" => Ok(Strategy::Lifo),
+            "slab" => Ok(Strategy::Slab),
+            _ => Err(format!(
+                "unknown allocator strategy '{}' (expected lowest, lifo or slab)",
+                s
+            )),
+        }
+    }
+}
+
+/// How long `Mem` holds back ids of freed arrays before handing them out
+/// again. Until then use of a stale id faults instead of reaching an array
+/// allocated since.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Quarantine {
+    /// Ids can be reused right away.
+    #[default]
+    Off,
+    /// Ids can be reused once this many arrays have been allocated since.
+    Allocs(u32),
+    /// Ids are never reused.
+    Forever,
+}
+
+impl FromStr for Quarantine {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "forever" => Ok(Quarantine::Forever),
+            _ => match s.parse() {
+                Ok(0) => Ok(Quarantine::Off),
+                Ok(n) => Ok(Quarantine::Allocs(n)),
+                Err(_) => Err(format!(
+                    "bad quarantine '{}' (expected a number of allocations or forever)",
+                    s
+                )),
+            },
+        }
+    }
+}
+
+/// Largest array which `Strategy::Slab` puts into a slab.
+pub const SLAB_MAX: u32 = 64;
+
+// The tag and the payload of a boxed array are at fixed offsets, so that
+// compiled code can get to the words, see `SlotLayout`.
+#[derive(Clone)]
+#[repr(C, u8)]
+enum Array {
+    Free,
+    Boxed(Box<[u32]>),
+    /// Slot of the slab for arrays of up to `1 << class` words.
+    Slab {
+        class: u8,
+        slot: u32,
+        len: u32,
+    },
+}
+
+impl Array {
+    fn len(&self) -> usize {
+        match self {
+            Array::Free => 0,
+            Array::Boxed(v) => v.len(),
+            Array::Slab { len, .. } => *len as usize,
+        }
+    }
+}
+
+/// Arrays of the same size class stored back to back.
+#[derive(Clone, Default)]
+struct Slab {
+    words: Vec<u32>,
+    free: Vec<u32>,
+}
+
+/// Where compiled code finds the words of array `addr`: slot `addr` of
+/// `Mem::slots` is `size` bytes long, holds a boxed array if its first byte
+/// is `BOXED`, and then the address and length of its words are the
+/// `usize`s at `ptr` and `len`.
+#[cfg(all(feature = "jit", target_arch = "x86_64", unix))]
+pub(crate) struct SlotLayout {
+    pub size: usize,
+    pub ptr: usize,
+    pub len: usize,
+}
+
+#[cfg(all(feature = "jit", target_arch = "x86_64", unix))]
+pub(crate) const BOXED: u8 = 1;
+
+#[cfg(all(feature = "jit", target_arch = "x86_64", unix))]
+impl SlotLayout {
+    /// Works the layout out, `None` if a boxed slice doesn't look like a
+    /// pointer and a length.
+    pub(crate) fn get() -> Option<Self> {
+        let array = Array::Boxed(vec![0; 3].into_boxed_slice());
+        let words = match &array {
+            Array::Boxed(v) => v.as_ptr() as usize,
+            _ => unreachable!(),
+        };
+        // After the tag, aligned like the boxed slice.
+        let payload = mem::align_of::<Box<[u32]>>();
+        let base = &array as *const Array as *const u8;
+        let at = |offset: usize| unsafe { (base.add(offset) as *const usize).read_unaligned() };
+        let (ptr, len) = match (at(payload), at(payload + 8)) {
+            (p, 3) if p == words => (payload, payload + 8),
+            (3, p) if p == words => (payload + 8, payload),
+            _ => return None,
+        };
+        if unsafe { *base } != BOXED {
+            return None;
+        }
+        Some(SlotLayout {
+            size: mem::size_of::<Array>(),
+            ptr,
+            len,
+        })
+    }
+}
+
+/// Ids of freed arrays, to be handed out again.
+#[derive(Clone)]
+enum FreeIds {
+    Lowest(BinaryHeap<Reverse<u32>>),
+    Lifo(Vec<u32>),
+}
+
+impl FreeIds {
+    fn new(strategy: Strategy) -> Self {
+        match strategy {
+            Strategy::Lowest => FreeIds::Lowest(BinaryHeap::new()),
+            Strategy::Lifo | Strategy::Slab => FreeIds::Lifo(Vec::new()),
+        }
+    }
+
+    fn push(&mut self, addr: u32) {
+        match self {
+            FreeIds::Lowest(heap) => heap.push(Reverse(addr)),
+            FreeIds::Lifo(stack) => stack.push(addr),
+        }
+    }
+
+    fn pop(&mut self) -> Option<u32> {
+        match self {
+            FreeIds::Lowest(heap) => heap.pop().map(|Reverse(addr)| addr),
+            FreeIds::Lifo(stack) => stack.pop(),
+        }
+    }
+
+    /// All the ids, pushing them in this order gives back the same list.
+    fn ids(&self) -> Vec<u32> {
+        match self {
+            FreeIds::Lowest(heap) => heap.iter().map(|&Reverse(addr)| addr).collect(),
+            FreeIds::Lifo(stack) => stack.clone(),
+        }
+    }
+}
+
+/// Program loaded from another array isn't copied into array 0 straight
+/// away. Instead array 0 refers to its source until one of the two is
+/// written to (then the copy is made) or the source is freed (then its
+/// storage is simply moved over to array 0).
+#[derive(Clone)]
 pub struct Mem {
-    data: Vec<Option<Box<[u32]>>>,
-    free_pq: BinaryHeap<Reverse<u32>>,
+    data: Vec<Array>,
+    free: FreeIds,
+    slabs: Vec<Slab>,
+    strategy: Strategy,
+    quarantine: Quarantine,
+    // Freed ids held back, with the number of allocations after which they
+    // are released.
+    held: VecDeque<(u64, u32)>,
+    allocated: u64,
+    // Slot which holds the contents of array 0, it's 0 unless array 0
+    // shares storage with the array it was loaded from.
+    zero: u32,
+    usage: Usage,
+    limits: MemLimits,
+    stats: Option<Box<Events>>,
+}
+
+/// Current and peak memory use, array 0 included.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Usage {
+    /// Words in live arrays, storage shared by array 0 and the array it
+    /// was loaded from counts once.
+    pub words: u64,
+    pub arrays: u32,
+    pub peak_words: u64,
+    pub peak_arrays: u32,
+}
+
+/// Caps on memory use which `Mem::alloc` enforces, array 0 included.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct MemLimits {
+    pub max_words: Option<u64>,
+    pub max_arrays: Option<u32>,
+}
+
+/// Allocation statistics, collected once enabled with `Mem::track`. Live
+/// and peak figures are the ones of `Mem::usage`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct MemStats {
+    pub allocs: u64,
+    pub frees: u64,
+    /// Words in live arrays, array 0 included.
+    pub live_words: u64,
+    pub peak_words: u64,
+    pub peak_arrays: u32,
+    /// Bytes copied to give array 0 its own copy of a loaded program.
+    pub bytes_copied: u64,
+}
+
+// What `MemStats` counts on top of `Usage`.
+#[derive(Clone, Copy, Default)]
+struct Events {
+    allocs: u64,
+    frees: u64,
+    bytes_copied: u64,
 }
 
 impl Mem {
     pub fn init(prog: Vec<u32>) -> Self {
+        let words = prog.len() as u64;
         Mem {
-            data: vec![Some(prog.into_boxed_slice())],
-            free_pq: BinaryHeap::new(),
+            data: vec![Array::Boxed(prog.into_boxed_slice())],
+            free: FreeIds::new(Strategy::Lowest),
+            slabs: Vec::new(),
+            strategy: Strategy::Lowest,
+            quarantine: Quarantine::Off,
+            held: VecDeque::new(),
+            allocated: 0,
+            zero: 0,
+            usage: Usage {
+                words,
+                arrays: 1,
+                peak_words: words,
+                peak_arrays: 1,
+            },
+            limits: MemLimits::default(),
+            stats: None,
+        }
+    }
+
+    pub fn strategy(&self) -> Strategy {
+        self.strategy
+    }
+
+    /// Switches to another allocator strategy. Freed ids are kept, arrays
+    /// which are already there stay where they are.
+    pub fn set_strategy(&mut self, strategy: Strategy) {
+        let ids = self.free.ids();
+        self.free = FreeIds::new(strategy);
+        for addr in ids {
+            self.free.push(addr);
+        }
+        self.strategy = strategy;
+    }
+
+    pub fn quarantine(&self) -> Quarantine {
+        self.quarantine
+    }
+
+    /// Changes how long freed ids are held back, ids already held are held
+    /// again as if they had just been freed.
+    pub fn set_quarantine(&mut self, quarantine: Quarantine) {
+        let was = mem::replace(&mut self.quarantine, quarantine);
+        let mut ids: Vec<_> = mem::take(&mut self.held)
+            .into_iter()
+            .map(|(_, addr)| addr)
+            .collect();
+        if was == Quarantine::Forever && quarantine != Quarantine::Forever {
+            ids.extend(self.retired());
+        }
+        for addr in ids {
+            self.retire(addr);
+        }
+    }
+
+    /// Puts the id of a freed array either into quarantine or up for reuse.
+    /// Ids which are never reused are simply left out of both.
+    fn retire(&mut self, addr: u32) {
+        match self.quarantine {
+            Quarantine::Off => self.free.push(addr),
+            Quarantine::Allocs(n) => self.held.push_back((self.allocated + u64::from(n), addr)),
+            Quarantine::Forever => {}
+        }
+    }
+
+    /// Ids of freed arrays which are neither free nor held.
+    #[cold]
+    fn retired(&self) -> Vec<u32> {
+        let mut known = vec![false; self.data.len()];
+        known[0] = true;
+        for addr in self.free.ids() {
+            known[addr as usize] = true;
+        }
+        for &(_, addr) in &self.held {
+            known[addr as usize] = true;
+        }
+        (1..self.len())
+            .filter(|&addr| {
+                !known[addr as usize] && matches!(self.data[addr as usize], Array::Free)
+            })
+            .collect()
+    }
+
+    #[cold]
+    fn release_held(&mut self) {
+        while let Some(&(at, addr)) = self.held.front() {
+            if at > self.allocated {
+                break;
+            }
+            self.held.pop_front();
+            self.free.push(addr);
+        }
+    }
+
+    /// Turns collection of statistics on or off. Counting starts from zero
+    /// every time it's turned on, apart from the usage.
+    pub fn track(&mut self, enable: bool) {
+        self.stats = enable.then(Box::default);
+    }
+
+    pub fn stats(&self) -> Option<MemStats> {
+        let events = self.stats.as_deref()?;
+        Some(MemStats {
+            allocs: events.allocs,
+            frees: events.frees,
+            live_words: self.usage.words,
+            peak_words: self.usage.peak_words,
+            peak_arrays: self.usage.peak_arrays,
+            bytes_copied: events.bytes_copied,
+        })
+    }
+
+    /// Number of arrays which haven't been freed, array 0 included.
+    pub fn live_arrays(&self) -> u32 {
+        self.usage.arrays
+    }
+
+    pub fn usage(&self) -> Usage {
+        self.usage
+    }
+
+    pub fn limits(&self) -> MemLimits {
+        self.limits
+    }
+
+    /// Sets caps on memory use for allocations to come, what's already
+    /// there stays even if it's over them.
+    pub fn set_limits(&mut self, limits: MemLimits) {
+        self.limits = limits;
+    }
+
+    /// Accounts for `words` more live words.
+    fn grow(&mut self, words: u64) {
+        self.usage.words += words;
+        self.usage.peak_words = self.usage.peak_words.max(self.usage.words);
+    }
+
+    /// Accounts for `words` less live words.
+    fn shrink(&mut self, words: u64) {
+        self.usage.words -= words;
+    }
+
+    pub fn copy_to_zero(&mut self, addr: u32) -> Result<(), VmError> {
+        if addr != 0 && addr != self.zero {
+            match self.data.get(addr as usize) {
+                Some(Array::Free) => return Err(VmError::FreedArray(addr)),
+                Some(_) => {
+                    let old = mem::replace(&mut self.data[0], Array::Free);
+                    self.shrink(old.len() as u64);
+                    self.release(old);
+                    self.zero = addr;
+                }
+                None => return Err(VmError::UnallocatedArray(addr)),
+            }
         }
+        Ok(())
+    }
+
+    /// Tells whether arrays `a` and `b` currently share their storage.
+    pub fn is_shared(&self, a: u32, b: u32) -> bool {
+        self.zero != 0 && a != b && (a == 0 || a == self.zero) && (b == 0 || b == self.zero)
     }
 
-    pub fn copy_to_zero(&mut self, addr: u32) {
-        if addr != 0 {
-            self.data[0] = match self.data.get(addr as usize) {
-                Some(Some(v)) => Some(v.clone()),
-                Some(None) => panic!("copy_to_zero: attempt to copy from freed address {}", addr),
-                None => panic!(
-                    "copy_to_zero: attempt to copy from unallocated address {}",
-                    addr
-                ),
+    /// The slots, as laid out by `SlotLayout`, their number and the array
+    /// which array 0 shares storage with, 0 if none.
+    #[cfg(all(feature = "jit", target_arch = "x86_64", unix))]
+    pub(crate) fn slots(&self) -> (*const u8, usize, u32) {
+        (self.data.as_ptr() as *const u8, self.data.len(), self.zero)
+    }
+
+    /// Gives array 0 its own copy of the program, so that either of the
+    /// two arrays can be written.
+    #[cold]
+    fn unshare(&mut self) {
+        if self.zero != 0 {
+            let copy = self.storage(&self.data[self.zero as usize]).to_vec();
+            self.data[0] = Array::Boxed(copy.into_boxed_slice());
+            self.zero = 0;
+            let len = self.data[0].len() as u64;
+            self.grow(len);
+            if let Some(stats) = &mut self.stats {
+                stats.bytes_copied += len * 4;
             }
         }
     }
 
+    // Array 0 is stored as `Array::Free` while it shares storage with its
+    // source, so that the common path doesn't need to look at `zero`.
+    #[cold]
+    fn shared_zero(&self) -> Option<&[u32]> {
+        match self.zero {
+            0 => None,
+            addr => Some(self.storage(&self.data[addr as usize])),
+        }
+    }
+
+    #[inline]
+    fn storage<'a>(&'a self, array: &'a Array) -> &'a [u32] {
+        match *array {
+            Array::Free => &[],
+            Array::Boxed(ref v) => v,
+            Array::Slab { class, slot, len } => {
+                let start = (slot as usize) << class;
+                &self.slabs[class as usize].words[start..start + len as usize]
+            }
+        }
+    }
+
+    /// Returns the whole contents of array `addr`.
+    #[inline]
+    pub fn array(&self, addr: u32) -> Result<&[u32], VmError> {
+        match self.data.get(addr as usize) {
+            Some(Array::Boxed(v)) => Ok(v),
+            Some(a @ Array::Slab { .. }) => Ok(self.storage(a)),
+            Some(Array::Free) => match self.shared_zero() {
+                Some(v) if addr == 0 => Ok(v),
+                _ => Err(VmError::FreedArray(addr)),
+            },
+            None => Err(VmError::UnallocatedArray(addr)),
+        }
+    }
+
     pub fn len(&self) -> u32 {
         self.data.len() as u32
     }
 
-    pub fn alloc(&mut self, size: u32) -> u32 {
-        match self.free_pq.pop() {
-            Some(Reverse(addr)) => {
-                let v = vec![0; size as usize];
-                self.data[addr as usize] = Some(v.into_boxed_slice());
-                addr
-            }
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
 
+    /// Allocates a zeroed array of `size` words, unless that would go over
+    /// the limits.
+    pub fn alloc(&mut self, size: u32) -> Result<u32, VmError> {
+        if self.limits != MemLimits::default() {
+            self.check_limits(size)?;
+        }
+        if self
+            .held
+            .front()
+            .is_some_and(|&(at, _)| at <= self.allocated)
+        {
+            self.release_held();
+        }
+        let addr = match self.free.pop() {
+            Some(addr) => addr,
             None => {
                 if self.len() == u32::MAX {
-                    panic!("alloc: memory exhausted");
+                    return Err(VmError::MemoryExhausted);
                 }
-                let v = vec![0; size as usize];
-                self.data.push(Some(v.into_boxed_slice()));
+                self.data.push(Array::Free);
                 self.len() - 1
             }
+        };
+        self.data[addr as usize] = match self.strategy {
+            Strategy::Slab if size > 0 && size <= SLAB_MAX => self.slab_alloc(size),
+            _ => Array::Boxed(vec![0; size as usize].into_boxed_slice()),
+        };
+        self.allocated += 1;
+        self.grow(u64::from(size));
+        self.usage.arrays += 1;
+        self.usage.peak_arrays = self.usage.peak_arrays.max(self.usage.arrays);
+        if let Some(stats) = &mut self.stats {
+            stats.allocs += 1;
+        }
+        Ok(addr)
+    }
+
+    fn check_limits(&self, size: u32) -> Result<(), VmError> {
+        let words = self
+            .limits
+            .max_words
+            .is_none_or(|max| self.usage.words + u64::from(size) <= max);
+        let arrays = self
+            .limits
+            .max_arrays
+            .is_none_or(|max| self.usage.arrays < max);
+        if words && arrays {
+            Ok(())
+        } else {
+            Err(VmError::MemoryLimit(size))
+        }
+    }
+
+    fn slab_alloc(&mut self, size: u32) -> Array {
+        let class = size.next_power_of_two().trailing_zeros();
+        if self.slabs.len() <= class as usize {
+            self.slabs.resize(class as usize + 1, Slab::default());
+        }
+        let slab = &mut self.slabs[class as usize];
+        let slot = match slab.free.pop() {
+            Some(slot) => {
+                let start = (slot as usize) << class;
+                for word in &mut slab.words[start..start + (1 << class)] {
+                    *word = 0;
+                }
+                slot
+            }
+            None => {
+                let slot = slab.words.len() >> class;
+                slab.words.resize(slab.words.len() + (1 << class), 0);
+                slot as u32
+            }
+        };
+        Array::Slab {
+            class: class as u8,
+            slot,
+            len: size,
+        }
+    }
+
+    /// Gives the storage of an array which is gone back to its slab.
+    fn release(&mut self, array: Array) {
+        if let Array::Slab { class, slot, .. } = array {
+            self.slabs[class as usize].free.push(slot);
         }
     }
 
-    pub fn free(&mut self, addr: u32) {
+    pub fn free(&mut self, addr: u32) -> Result<(), VmError> {
         if addr == 0 {
-            panic!("free: tried to free memory at program location (0)");
+            return Err(VmError::FreeZero);
         }
 
         match self.data.get_mut(addr as usize) {
-            Some(v @ Some(_)) => {
-                *v = None;
-                self.free_pq.push(Reverse(addr));
+            Some(Array::Free) => Err(VmError::DoubleFree(addr)),
+            Some(slot) => {
+                let array = mem::replace(slot, Array::Free);
+                // Storage shared with array 0 stays live.
+                if addr != self.zero {
+                    self.shrink(array.len() as u64);
+                }
+                self.usage.arrays -= 1;
+                if let Some(stats) = &mut self.stats {
+                    stats.frees += 1;
+                }
+                if addr == self.zero {
+                    // Array 0 is the only user left, hand the storage over.
+                    self.data[0] = array;
+                    self.zero = 0;
+                } else {
+                    self.release(array);
+                }
+                self.retire(addr);
+                Ok(())
             }
-            Some(None) => panic!(
-                "free: attempt to free address {} which is already free",
-                addr
-            ),
-            None => panic!("free: attempt to free unallocated address {}", addr),
+            None => Err(VmError::UnallocatedArray(addr)),
         }
     }
 
-    pub fn read(&self, addr: u32, offset: u32) -> &u32 {
-        match self.data.get(addr as usize) {
-            Some(Some(v)) => match v.get(offset as usize) {
-                Some(val) => val,
-                None => panic!(
-                    "read: offset {} is out of bounds for address {} (len: {})",
-                    offset,
-                    addr,
-                    v.len()
-                ),
-            },
-            Some(None) => panic!("read: address {} has been deallocated", addr),
-            None => panic!("read: address {} has not been allocated", addr),
+    /// Writes the exact state of the memory: every slot including the freed
+    /// ones, the strategy with its free ids in order, the quarantine and
+    /// whether array 0 is shared. Arrays are written the same way whichever
+    /// strategy allocated them.
+    pub fn save<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        w.write_u32::<BigEndian>(self.zero)?;
+        w.write_u32::<BigEndian>(self.len())?;
+        for array in &self.data {
+            match array {
+                Array::Free => w.write_u8(0)?,
+                _ => {
+                    let v = self.storage(array);
+                    w.write_u8(1)?;
+                    w.write_u32::<BigEndian>(v.len() as u32)?;
+                    for &word in v {
+                        w.write_u32::<BigEndian>(word)?;
+                    }
+                }
+            }
+        }
+        w.write_u8(match self.strategy {
+            Strategy::Lowest => 0,
+            Strategy::Lifo => 1,
+            Strategy::Slab => 2,
+        })?;
+        let free = self.free.ids();
+        w.write_u32::<BigEndian>(free.len() as u32)?;
+        for addr in free {
+            w.write_u32::<BigEndian>(addr)?;
+        }
+        match self.quarantine {
+            Quarantine::Off => w.write_u8(0)?,
+            Quarantine::Allocs(n) => {
+                w.write_u8(1)?;
+                w.write_u32::<BigEndian>(n)?;
+            }
+            Quarantine::Forever => w.write_u8(2)?,
+        }
+        w.write_u64::<BigEndian>(self.allocated)?;
+        w.write_u32::<BigEndian>(self.held.len() as u32)?;
+        for &(at, addr) in &self.held {
+            w.write_u64::<BigEndian>(at)?;
+            w.write_u32::<BigEndian>(addr)?;
+        }
+        Ok(())
+    }
+
+    /// Reads memory written by `save`. Arrays which were in slabs come back
+    /// on their own, new ones go into slabs again.
+    pub fn restore<R: Read>(r: &mut R) -> io::Result<Self> {
+        let invalid = |msg: &str| io::Error::new(io::ErrorKind::InvalidData, msg.to_string());
+
+        let zero = r.read_u32::<BigEndian>()?;
+        let len = r.read_u32::<BigEndian>()?;
+        let mut data = Vec::new();
+        for _ in 0..len {
+            let array = match r.read_u8()? {
+                0 => Array::Free,
+                1 => {
+                    let size = r.read_u32::<BigEndian>()? as usize;
+                    let mut v = Vec::new();
+                    // Grows as the words arrive, the size may be garbage.
+                    for _ in 0..size {
+                        v.push(r.read_u32::<BigEndian>()?);
+                    }
+                    Array::Boxed(v.into_boxed_slice())
+                }
+                _ => return Err(invalid("bad array slot")),
+            };
+            data.push(array);
+        }
+
+        // Every freed slot but array 0 is either free or held, and only once.
+        let mut seen = vec![false; data.len()];
+        let mut check = |addr: u32| match data.get(addr as usize) {
+            Some(Array::Free) if addr != 0 && !seen[addr as usize] => {
+                seen[addr as usize] = true;
+                Ok(addr)
+            }
+            Some(Array::Free) if addr != 0 => Err(invalid("duplicate free id")),
+            _ => Err(invalid("free id of a live array")),
+        };
+
+        let strategy = match r.read_u8()? {
+            0 => Strategy::Lowest,
+            1 => Strategy::Lifo,
+            2 => Strategy::Slab,
+            _ => return Err(invalid("bad allocator strategy")),
+        };
+        let count = r.read_u32::<BigEndian>()?;
+        let mut free = FreeIds::new(strategy);
+        for _ in 0..count {
+            free.push(check(r.read_u32::<BigEndian>()?)?);
+        }
+        let quarantine = match r.read_u8()? {
+            0 => Quarantine::Off,
+            1 => Quarantine::Allocs(r.read_u32::<BigEndian>()?),
+            2 => Quarantine::Forever,
+            _ => return Err(invalid("bad quarantine")),
+        };
+        let allocated = r.read_u64::<BigEndian>()?;
+        let count = r.read_u32::<BigEndian>()?;
+        let mut held = VecDeque::new();
+        for _ in 0..count {
+            let at = r.read_u64::<BigEndian>()?;
+            held.push_back((at, check(r.read_u32::<BigEndian>()?)?));
+        }
+
+        // Array 0 must either be there or share storage with a live array.
+        let zero_ok = match data.first() {
+            Some(Array::Free) => {
+                zero != 0 && matches!(data.get(zero as usize), Some(Array::Boxed(_)))
+            }
+            Some(_) => zero == 0,
+            None => false,
+        };
+        if !zero_ok {
+            return Err(invalid("bad array 0"));
         }
+        let mut mem = Mem {
+            data,
+            free,
+            slabs: Vec::new(),
+            strategy,
+            quarantine,
+            held,
+            allocated,
+            zero,
+            usage: Usage::default(),
+            limits: MemLimits::default(),
+            stats: None,
+        };
+        let words = mem.data.iter().map(|a| a.len() as u64).sum();
+        // Ids retired for good are neither free nor held, count what's live.
+        let live = mem.data.iter().filter(|a| !matches!(a, Array::Free));
+        let arrays = live.count() as u32 + u32::from(zero != 0);
+        mem.usage = Usage {
+            words,
+            arrays,
+            peak_words: words,
+            peak_arrays: arrays,
+        };
+        Ok(mem)
+    }
+
+    #[inline]
+    pub fn read(&self, addr: u32, offset: u32) -> Result<&u32, VmError> {
+        let v = self.array(addr)?;
+        v.get(offset as usize).ok_or(VmError::ReadOutOfBounds {
+            addr,
+            offset,
+            len: v.len() as u32,
+        })
     }
 
-    pub fn write(&mut self, addr: u32, offset: u32, val: u32) {
+    #[inline]
+    pub fn write(&mut self, addr: u32, offset: u32, val: u32) -> Result<(), VmError> {
+        if addr == self.zero {
+            self.unshare();
+        }
+
         match self.data.get_mut(addr as usize) {
-            Some(Some(v)) => {
-                if (offset as usize) < v.len() {
-                    v[offset as usize] = val;
-                } else {
-                    panic!(
-                        "write: offset {} is out of bounds for address {} (len: {})",
-                        offset,
-                        addr,
-                        v.len()
-                    );
+            Some(Array::Boxed(v)) => match v.get_mut(offset as usize) {
+                Some(slot) => {
+                    *slot = val;
+                    Ok(())
+                }
+                None => Err(VmError::WriteOutOfBounds {
+                    addr,
+                    offset,
+                    len: v.len() as u32,
+                }),
+            },
+            Some(&mut Array::Slab { class, slot, len }) => {
+                if offset >= len {
+                    return Err(VmError::WriteOutOfBounds { addr, offset, len });
                 }
+                let at = ((slot as usize) << class) + offset as usize;
+                self.slabs[class as usize].words[at] = val;
+                Ok(())
             }
-            Some(None) => panic!("write: address {} has been deallocated", addr),
-            None => panic!("write: address {} has not been allocated", addr),
+            Some(Array::Free) if addr == 0 && self.zero != 0 => {
+                self.unshare();
+                self.write(addr, offset, val)
+            }
+            Some(Array::Free) => Err(VmError::FreedArray(addr)),
+            None => Err(VmError::UnallocatedArray(addr)),
         }
     }
 }
@@ -112,71 +799,180 @@ mod tests {
     #[test]
     fn alloc() {
         let mut mem = Mem::init(vec![]);
-        let m0 = mem.alloc(10);
-        let m1 = mem.alloc(20);
+        let m0 = mem.alloc(10).unwrap();
+        let m1 = mem.alloc(20).unwrap();
         assert_eq!(mem.len(), 3);
         assert_eq!(m0, 1);
         assert_eq!(m1, 2);
     }
 
     #[test]
-    #[should_panic(expected = "1 has been deallocated")]
     fn free_err() {
         let mut mem = Mem::init(vec![]);
-        let m0 = mem.alloc(10);
-        mem.free(m0);
-        mem.read(m0, 1);
+        let m0 = mem.alloc(10).unwrap();
+        mem.free(m0).unwrap();
+        assert_eq!(mem.read(m0, 1), Err(VmError::FreedArray(1)));
     }
 
     #[test]
-    #[should_panic(expected = "attempt to free unallocated address 1")]
     fn free_err2() {
         let mut mem = Mem::init(vec![]);
-        mem.free(1);
+        assert_eq!(mem.free(1), Err(VmError::UnallocatedArray(1)));
     }
 
     #[test]
-    #[should_panic(expected = "tried to free memory at program location")]
     fn free_err3() {
         let mut mem = Mem::init(vec![]);
-        mem.free(0);
+        assert_eq!(mem.free(0), Err(VmError::FreeZero));
     }
 
     #[test]
-    #[should_panic(expected = "attempt to free address 1 which is already free")]
     fn double_free_err() {
         let mut mem = Mem::init(vec![]);
-        let m0 = mem.alloc(10);
-        mem.free(m0);
-        mem.free(m0);
+        let m0 = mem.alloc(10).unwrap();
+        mem.free(m0).unwrap();
+        assert_eq!(mem.free(m0), Err(VmError::DoubleFree(1)));
     }
 
     #[test]
     fn alloc_lowest() {
         let mut mem = Mem::init(vec![]);
 
-        let m0 = mem.alloc(10);
-        let m1 = mem.alloc(20);
-        let _m2 = mem.alloc(30);
+        let m0 = mem.alloc(10).unwrap();
+        let m1 = mem.alloc(20).unwrap();
+        let _m2 = mem.alloc(30).unwrap();
 
-        mem.free(m0);
-        mem.free(m1);
+        mem.free(m0).unwrap();
+        mem.free(m1).unwrap();
 
-        let m3 = mem.alloc(40);
+        let m3 = mem.alloc(40).unwrap();
         assert_eq!(m3, m0);
     }
 
+    #[test]
+    fn alloc_lifo() {
+        let mut mem = Mem::init(vec![]);
+        mem.set_strategy(Strategy::Lifo);
+        let m0 = mem.alloc(10).unwrap();
+        let m1 = mem.alloc(20).unwrap();
+        mem.free(m0).unwrap();
+        mem.free(m1).unwrap();
+        assert_eq!(mem.alloc(1), Ok(m1));
+        assert_eq!(mem.alloc(1), Ok(m0));
+        assert_eq!(mem.alloc(1), Ok(3));
+    }
+
+    #[test]
+    fn alloc_slab() {
+        let mut mem = Mem::init(vec![]);
+        mem.set_strategy(Strategy::Slab);
+        let m0 = mem.alloc(3).unwrap();
+        let m1 = mem.alloc(4).unwrap();
+        let big = mem.alloc(SLAB_MAX + 1).unwrap();
+        mem.write(m0, 2, 7).unwrap();
+        mem.write(m1, 0, 8).unwrap();
+        assert_eq!(mem.array(m0), Ok(&[0, 0, 7][..]));
+        assert_eq!(mem.array(m1), Ok(&[8, 0, 0, 0][..]));
+        assert_eq!(mem.array(big).map(|a| a.len()), Ok(65));
+        assert_eq!(
+            mem.write(m0, 3, 1),
+            Err(VmError::WriteOutOfBounds {
+                addr: m0,
+                offset: 3,
+                len: 3
+            })
+        );
+        assert_eq!(mem.read(m0, 3).map_err(|_| ()), Err(()));
+
+        // The slot is reused and comes back cleared.
+        mem.free(m1).unwrap();
+        let m2 = mem.alloc(4).unwrap();
+        assert_eq!(m2, m1);
+        assert_eq!(mem.array(m2), Ok(&[0, 0, 0, 0][..]));
+        assert_eq!(mem.slabs[2].words.len(), 8);
+    }
+
+    #[test]
+    fn slab_program() {
+        let mut mem = Mem::init(vec![1]);
+        mem.set_strategy(Strategy::Slab);
+        let m0 = mem.alloc(2).unwrap();
+        mem.write(m0, 1, 5).unwrap();
+        mem.copy_to_zero(m0).unwrap();
+        assert_eq!(mem.array(0), Ok(&[0, 5][..]));
+
+        // Array 0 takes the slot over, it's not handed out again.
+        mem.free(m0).unwrap();
+        let m1 = mem.alloc(2).unwrap();
+        mem.write(m1, 1, 6).unwrap();
+        assert_eq!(mem.array(0), Ok(&[0, 5][..]));
+
+        // Until another program replaces it.
+        mem.copy_to_zero(m1).unwrap();
+        assert_eq!(mem.slabs[1].free, [0]);
+    }
+
+    #[test]
+    fn switch_strategy() {
+        let mut mem = Mem::init(vec![]);
+        for _ in 0..3 {
+            mem.alloc(1).unwrap();
+        }
+        mem.free(2).unwrap();
+        mem.free(1).unwrap();
+        mem.free(3).unwrap();
+        mem.set_strategy(Strategy::Lifo);
+        assert_eq!(mem.live_arrays(), 1);
+        mem.set_strategy(Strategy::Lowest);
+        assert_eq!(mem.alloc(1), Ok(1));
+        assert_eq!("slab".parse(), Ok(Strategy::Slab));
+        assert!("best".parse::<Strategy>().is_err());
+    }
+
+    #[test]
+    fn quarantine() {
+        let mut mem = Mem::init(vec![]);
+        mem.set_quarantine(Quarantine::Allocs(2));
+        let m0 = mem.alloc(1).unwrap();
+        mem.free(m0).unwrap();
+        assert_eq!(mem.live_arrays(), 1);
+        assert_eq!(mem.alloc(1), Ok(2));
+        assert_eq!(mem.read(m0, 0), Err(VmError::FreedArray(m0)));
+        assert_eq!(mem.alloc(1), Ok(3));
+        assert_eq!(mem.alloc(1), Ok(m0));
+
+        mem.set_quarantine(Quarantine::Forever);
+        mem.free(m0).unwrap();
+        for n in 0..10 {
+            assert_eq!(mem.alloc(1), Ok(4 + n));
+        }
+        assert_eq!(mem.live_arrays(), 13);
+        // Nothing is queued for ids which are never released.
+        assert!(mem.held.is_empty());
+        let mut bytes = Vec::new();
+        mem.save(&mut bytes).unwrap();
+        let copy = Mem::restore(&mut &bytes[..]).unwrap();
+        assert_eq!(copy.live_arrays(), 13);
+
+        // Releases the held id after the next allocation.
+        mem.set_quarantine("1".parse().unwrap());
+        assert_eq!(mem.alloc(1), Ok(14));
+        assert_eq!(mem.alloc(1), Ok(m0));
+        assert_eq!("0".parse(), Ok(Quarantine::Off));
+        assert!("-1".parse::<Quarantine>().is_err());
+    }
+
     #[test]
     fn len4() {
         let mut mem = Mem::init(vec![]);
 
-        let m0 = mem.alloc(10);
-        let m1 = mem.alloc(20);
-        let m2 = mem.alloc(30);
+        let m0 = mem.alloc(10).unwrap();
+        let m1 = mem.alloc(20).unwrap();
+        let m2 = mem.alloc(30).unwrap();
 
-        mem.free(m0);
-        mem.free(m1);
-        mem.free(m2);
+        mem.free(m0).unwrap();
+        mem.free(m1).unwrap();
+        mem.free(m2).unwrap();
 
         assert_eq!(mem.len(), 4);
     }
@@ -185,13 +981,13 @@ mod tests {
     fn len2() {
         let mut mem = Mem::init(vec![]);
 
-        let m0 = mem.alloc(10);
-        mem.free(m0);
+        let m0 = mem.alloc(10).unwrap();
+        mem.free(m0).unwrap();
 
-        let m1 = mem.alloc(20);
-        mem.free(m1);
+        let m1 = mem.alloc(20).unwrap();
+        mem.free(m1).unwrap();
 
-        mem.alloc(30);
+        mem.alloc(30).unwrap();
         assert_eq!(mem.len(), 2);
     }
 
@@ -199,41 +995,268 @@ mod tests {
     fn init_with_zero() {
         let mut mem = Mem::init(vec![]);
 
-        let m0 = mem.alloc(10);
+        let m0 = mem.alloc(10).unwrap();
         for i in 0..10 {
-            assert_eq!(mem.read(m0, i), &0);
+            assert_eq!(mem.read(m0, i), Ok(&0));
         }
     }
 
     #[test]
-    #[should_panic]
     fn read_err_offset() {
         let mut mem = Mem::init(vec![]);
-        let m0 = mem.alloc(10);
-        mem.read(m0, 10);
+        let m0 = mem.alloc(10).unwrap();
+        assert_eq!(
+            mem.read(m0, 10),
+            Err(VmError::ReadOutOfBounds {
+                addr: 1,
+                offset: 10,
+                len: 10
+            })
+        );
     }
 
     #[test]
-    #[should_panic]
     fn read_err_zero() {
         let mut mem = Mem::init(vec![]);
-        let m0 = mem.alloc(0);
-        mem.read(m0, 0);
+        let m0 = mem.alloc(0).unwrap();
+        assert!(mem.read(m0, 0).is_err());
     }
 
     #[test]
-    #[should_panic]
     fn read_err_addr() {
         let mem = Mem::init(vec![]);
-        mem.read(1, 0);
+        assert_eq!(mem.read(1, 0), Err(VmError::UnallocatedArray(1)));
+    }
+
+    #[test]
+    fn write_err_offset() {
+        let mut mem = Mem::init(vec![]);
+        let m0 = mem.alloc(1).unwrap();
+        assert_eq!(
+            mem.write(m0, 1, 5),
+            Err(VmError::WriteOutOfBounds {
+                addr: 1,
+                offset: 1,
+                len: 1
+            })
+        );
+    }
+
+    #[test]
+    fn copy_from_freed() {
+        let mut mem = Mem::init(vec![]);
+        let m0 = mem.alloc(1).unwrap();
+        mem.free(m0).unwrap();
+        assert_eq!(mem.copy_to_zero(m0), Err(VmError::FreedArray(1)));
+    }
+
+    #[test]
+    fn copy_on_write() {
+        let mut mem = Mem::init(vec![1, 2]);
+        let m0 = mem.alloc(2).unwrap();
+        mem.write(m0, 0, 7).unwrap();
+
+        mem.copy_to_zero(m0).unwrap();
+        assert!(mem.is_shared(0, m0));
+        assert_eq!(mem.array(0), Ok(&[7, 0][..]));
+        assert_eq!(mem.read(0, 0), Ok(&7));
+
+        mem.write(0, 1, 8).unwrap();
+        assert!(!mem.is_shared(0, m0));
+        assert_eq!(mem.array(0), Ok(&[7, 8][..]));
+        assert_eq!(mem.array(m0), Ok(&[7, 0][..]));
+    }
+
+    #[test]
+    fn copy_on_write_source() {
+        let mut mem = Mem::init(vec![]);
+        let m0 = mem.alloc(1).unwrap();
+        mem.copy_to_zero(m0).unwrap();
+        mem.write(m0, 0, 3).unwrap();
+        assert!(!mem.is_shared(0, m0));
+        assert_eq!(mem.read(0, 0), Ok(&0));
+        assert_eq!(mem.read(m0, 0), Ok(&3));
+    }
+
+    #[test]
+    fn copy_then_free_source() {
+        let mut mem = Mem::init(vec![]);
+        let m0 = mem.alloc(1).unwrap();
+        mem.write(m0, 0, 5).unwrap();
+        mem.copy_to_zero(m0).unwrap();
+        mem.free(m0).unwrap();
+        assert!(!mem.is_shared(0, m0));
+        assert_eq!(mem.read(0, 0), Ok(&5));
+        assert_eq!(mem.read(m0, 0), Err(VmError::FreedArray(m0)));
+
+        // The id is reused, array 0 must not be affected.
+        let m1 = mem.alloc(1).unwrap();
+        assert_eq!(m1, m0);
+        mem.write(m1, 0, 6).unwrap();
+        assert_eq!(mem.read(0, 0), Ok(&5));
+    }
+
+    #[test]
+    fn copy_twice() {
+        let mut mem = Mem::init(vec![]);
+        let m0 = mem.alloc(1).unwrap();
+        let m1 = mem.alloc(2).unwrap();
+        mem.copy_to_zero(m0).unwrap();
+        mem.copy_to_zero(m1).unwrap();
+        assert!(mem.is_shared(0, m1));
+        assert!(!mem.is_shared(0, m0));
+        assert_eq!(mem.array(0).map(|a| a.len()), Ok(2));
+    }
+
+    #[test]
+    fn save_restore() {
+        let mut mem = Mem::init(vec![1, 2, 3]);
+        let m0 = mem.alloc(2).unwrap();
+        let m1 = mem.alloc(1).unwrap();
+        let m2 = mem.alloc(4).unwrap();
+        mem.write(m2, 3, 9).unwrap();
+        mem.free(m1).unwrap();
+        mem.free(m0).unwrap();
+        mem.copy_to_zero(m2).unwrap();
+
+        let mut bytes = Vec::new();
+        mem.save(&mut bytes).unwrap();
+        let mut copy = Mem::restore(&mut &bytes[..]).unwrap();
+        assert_eq!(copy.len(), 4);
+        assert!(copy.is_shared(0, m2));
+        assert_eq!(copy.array(0), Ok(&[0, 0, 0, 9][..]));
+        assert_eq!(copy.read(m1, 0), Err(VmError::FreedArray(m1)));
+        assert_eq!(copy.alloc(1), Ok(m0));
+        assert_eq!(copy.alloc(1), Ok(m1));
+
+        assert!(Mem::restore(&mut &bytes[..bytes.len() - 1]).is_err());
+
+        // Arrays in slabs are saved like any other, the strategy and the
+        // order of free ids are kept.
+        for strategy in [Strategy::Lowest, Strategy::Lifo, Strategy::Slab] {
+            let mut mem = Mem::init(vec![1, 2, 3]);
+            mem.set_strategy(strategy);
+            for _ in 0..6 {
+                mem.alloc(2).unwrap();
+            }
+            for addr in [3, 1, 5, 2, 6, 4] {
+                mem.free(addr).unwrap();
+            }
+            let m0 = mem.alloc(2).unwrap();
+            mem.write(m0, 1, 4).unwrap();
+            let mut bytes = Vec::new();
+            mem.save(&mut bytes).unwrap();
+            let mut copy = Mem::restore(&mut &bytes[..]).unwrap();
+            assert_eq!(copy.strategy(), strategy);
+            assert_eq!(copy.array(m0), Ok(&[0, 4][..]));
+            let ids: Vec<_> = (0..6).map(|_| mem.alloc(1).unwrap()).collect();
+            let copied: Vec<_> = (0..6).map(|_| copy.alloc(1).unwrap()).collect();
+            assert_eq!(copied, ids);
+        }
+
+        // Ids held in quarantine stay held for as long as they would have.
+        let mut mem = Mem::init(vec![]);
+        mem.set_quarantine(Quarantine::Allocs(2));
+        let m0 = mem.alloc(1).unwrap();
+        mem.free(m0).unwrap();
+        let m1 = mem.alloc(1).unwrap();
+        let mut bytes = Vec::new();
+        mem.save(&mut bytes).unwrap();
+        let mut copy = Mem::restore(&mut &bytes[..]).unwrap();
+        assert_eq!(copy.quarantine(), Quarantine::Allocs(2));
+        assert_eq!(copy.live_arrays(), 2);
+        assert_eq!(copy.alloc(1), Ok(m1 + 1));
+        assert_eq!(copy.alloc(1), Ok(m0));
+    }
+
+    #[test]
+    fn restore_garbage() {
+        // Array 0 is freed, but doesn't share storage with anything.
+        let bytes = [0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0];
+        assert!(Mem::restore(&mut &bytes[..]).is_err());
+        // Free list names a live array.
+        let bytes = [
+            0, 0, 0, 0, 0, 0, 0, 1, 1, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0, 0,
+        ];
+        assert!(Mem::restore(&mut &bytes[..]).is_err());
+        // The same free id twice.
+        let bytes = |ids: &[u8]| {
+            let mut bytes = vec![0, 0, 0, 0, 0, 0, 0, 2, 1, 0, 0, 0, 0, 0, 0];
+            bytes.extend([0, 0, 0, ids.len() as u8]);
+            for &id in ids {
+                bytes.extend([0, 0, 0, id]);
+            }
+            bytes.extend([0; 13]);
+            bytes
+        };
+        assert!(Mem::restore(&mut &bytes(&[1])[..]).is_ok());
+        assert!(Mem::restore(&mut &bytes(&[1, 1])[..]).is_err());
+    }
+
+    #[test]
+    fn stats() {
+        let mut mem = Mem::init(vec![0; 4]);
+        let m0 = mem.alloc(10).unwrap();
+        mem.track(true);
+        assert_eq!(mem.stats().map(|s| s.live_words), Some(14));
+
+        let m1 = mem.alloc(20).unwrap();
+        mem.free(m0).unwrap();
+        mem.copy_to_zero(m1).unwrap();
+        mem.write(0, 0, 1).unwrap();
+        mem.free(m1).unwrap();
+        assert_eq!(mem.live_arrays(), 1);
+        assert_eq!(
+            mem.stats(),
+            Some(MemStats {
+                allocs: 1,
+                frees: 2,
+                live_words: 20,
+                peak_words: 40,
+                peak_arrays: 3,
+                bytes_copied: 80,
+            })
+        );
+    }
+
+    #[test]
+    fn limits() {
+        let mut mem = Mem::init(vec![0; 4]);
+        mem.set_limits(MemLimits {
+            max_words: Some(10),
+            max_arrays: Some(3),
+        });
+        let m0 = mem.alloc(6).unwrap();
+        assert_eq!(mem.alloc(1), Err(VmError::MemoryLimit(1)));
+        assert_eq!(mem.alloc(u32::MAX), Err(VmError::MemoryLimit(u32::MAX)));
+
+        mem.free(m0).unwrap();
+        let m1 = mem.alloc(3).unwrap();
+        mem.alloc(0).unwrap();
+        assert_eq!(mem.alloc(0), Err(VmError::MemoryLimit(0)));
+        assert_eq!(
+            mem.usage(),
+            Usage {
+                words: 7,
+                arrays: 3,
+                peak_words: 10,
+                peak_arrays: 3,
+            }
+        );
+
+        // Words of a shared program count once.
+        mem.copy_to_zero(m1).unwrap();
+        assert_eq!(mem.usage().words, 3);
+        mem.write(0, 0, 1).unwrap();
+        assert_eq!(mem.usage().words, 6);
     }
 
     #[test]
     fn write_and_read() {
         let mut mem = Mem::init(vec![]);
-        let block0 = mem.alloc(10);
-        mem.write(block0, 0, 384);
-        assert_eq!(mem.read(block0, 0), &384);
+        let block0 = mem.alloc(10).unwrap();
+        mem.write(block0, 0, 384).unwrap();
+        assert_eq!(mem.read(block0, 0), Ok(&384));
     }
 
     #[test]
@@ -241,7 +1264,9 @@ mod tests {
     fn fill_all_memory() {
         let mut mem = Mem::init(vec![]);
         for _ in 0..=u32::MAX {
-            mem.alloc(1);
+            if mem.alloc(1).is_err() {
+                break;
+            }
         }
         assert_eq!(mem.len(), u32::MAX);
     }
@@ -0,0 +1,192 @@
+use crate::error::VmError;
+use std::fmt;
+
+pub type Reg = usize;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Op {
+    CondMov(Reg, Reg, Reg),
+    MemRead(Reg, Reg, Reg),
+    MemWrite(Reg, Reg, Reg),
+    Add(Reg, Reg, Reg),
+    Mul(Reg, Reg, Reg),
+    Div(Reg, Reg, Reg),
+    Nand(Reg, Reg, Reg),
+    Halt,
+    Alloc(Reg, Reg),
+    Free(Reg),
+    Output(Reg),
+    Input(Reg),
+    LoadProgram(Reg, Reg),
+    Mov(Reg, u32),
+}
+
+impl Op {
+    /// Names of the ops indexed by their opcode.
+    pub const NAMES: [&'static str; 14] = [
+        "CondMov",
+        "MemRead",
+        "MemWrite",
+        "Add",
+        "Mul",
+        "Div",
+        "Nand",
+        "Halt",
+        "Alloc",
+        "Free",
+        "Output",
+        "Input",
+        "LoadProgram",
+        "Mov",
+    ];
+
+    pub fn opcode(&self) -> u32 {
+        match self {
+            Op::CondMov(..) => 0,
+            Op::MemRead(..) => 1,
+            Op::MemWrite(..) => 2,
+            Op::Add(..) => 3,
+            Op::Mul(..) => 4,
+            Op::Div(..) => 5,
+            Op::Nand(..) => 6,
+            Op::Halt => 7,
+            Op::Alloc(..) => 8,
+            Op::Free(..) => 9,
+            Op::Output(..) => 10,
+            Op::Input(..) => 11,
+            Op::LoadProgram(..) => 12,
+            Op::Mov(..) => 13,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        Op::NAMES[self.opcode() as usize]
+    }
+
+    /// Inverse of `parse`. Registers must be below 8 and the `Mov`
+    /// immediate must fit in 25 bits, higher bits are dropped.
+    pub fn encode(&self) -> u32 {
+        let abc =
+            |a: Reg, b: Reg, c: Reg| ((a as u32 & 7) << 6) | ((b as u32 & 7) << 3) | (c as u32 & 7);
+        let operands = match *self {
+            Op::CondMov(a, b, c)
+            | Op::MemRead(a, b, c)
+            | Op::MemWrite(a, b, c)
+            | Op::Add(a, b, c)
+            | Op::Mul(a, b, c)
+            | Op::Div(a, b, c)
+            | Op::Nand(a, b, c) => abc(a, b, c),
+            Op::Halt => 0,
+            Op::Alloc(b, c) | Op::LoadProgram(b, c) => abc(0, b, c),
+            Op::Free(c) | Op::Output(c) | Op::Input(c) => abc(0, 0, c),
+            Op::Mov(a, val) => ((a as u32 & 7) << 25) | (val & 0x01FFFFFF),
+        };
+        (self.opcode() << 28) | operands
+    }
+
+    pub fn parse(v: u32) -> Result<Op, VmError> {
+        let code = v >> 28;
+        let a = ((v & 0b111000000_u32) >> 6) as usize;
+        let b = ((v & 0b111000_u32) >> 3) as usize;
+        let c = (v & 0b111_u32) as usize;
+        let op = match code {
+            0 => Op::CondMov(a, b, c),
+            1 => Op::MemRead(a, b, c),
+            2 => Op::MemWrite(a, b, c),
+            3 => Op::Add(a, b, c),
+            4 => Op::Mul(a, b, c),
+            5 => Op::Div(a, b, c),
+            6 => Op::Nand(a, b, c),
+            7 => Op::Halt,
+            8 => Op::Alloc(b, c),
+            9 => Op::Free(c),
+            10 => Op::Output(c),
+            11 => Op::Input(c),
+            12 => Op::LoadProgram(b, c),
+            13 => {
+                let a = ((v >> 25) & 0b111_u32) as usize;
+                let val = v & 0x01FFFFFF_u32;
+                Op::Mov(a, val)
+            }
+            _ => return Err(VmError::InvalidOpcode(code)),
+        };
+        Ok(op)
+    }
+}
+
+/// Assembly syntax: lowercase op name followed by registers in the same
+/// order as the fields of the variant, e.g. `add r0, r1, r2`.
+impl fmt::Display for Op {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = self.name().to_lowercase();
+        match *self {
+            Op::CondMov(a, b, c)
+            | Op::MemRead(a, b, c)
+            | Op::MemWrite(a, b, c)
+            | Op::Add(a, b, c)
+            | Op::Mul(a, b, c)
+            | Op::Div(a, b, c)
+            | Op::Nand(a, b, c) => write!(f, "{} r{}, r{}, r{}", name, a, b, c),
+            Op::Halt => write!(f, "{}", name),
+            Op::Alloc(b, c) | Op::LoadProgram(b, c) => write!(f, "{} r{}, r{}", name, b, c),
+            Op::Free(c) | Op::Output(c) | Op::Input(c) => write!(f, "{} r{}", name, c),
+            Op::Mov(a, val) => write!(f, "{} r{}, {:#x}", name, a, val),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_standard() {
+        // opcode 3 (Add), a = 1, b = 2, c = 3
+        let word = (3 << 28) | (1 << 6) | (2 << 3) | 3;
+        assert_eq!(Op::parse(word), Ok(Op::Add(1, 2, 3)));
+    }
+
+    #[test]
+    fn parse_mov() {
+        let word = (13 << 28) | (5 << 25) | 0x01FF_FFFF;
+        assert_eq!(Op::parse(word), Ok(Op::Mov(5, 0x01FF_FFFF)));
+    }
+
+    #[test]
+    fn opcode_roundtrip() {
+        for code in 0..14 {
+            let op = Op::parse(code << 28).unwrap();
+            assert_eq!(op.opcode(), code);
+        }
+        assert_eq!(Op::Halt.name(), "Halt");
+    }
+
+    #[test]
+    fn encode_roundtrip() {
+        let ops = [
+            Op::MemWrite(7, 1, 2),
+            Op::Halt,
+            Op::Alloc(3, 4),
+            Op::Input(5),
+            Op::Mov(6, 0x01FF_FFFF),
+        ];
+        for &op in &ops {
+            assert_eq!(Op::parse(op.encode()), Ok(op));
+        }
+        assert_eq!(Op::Mov(0, 4239).encode(), 0xD000_108F);
+    }
+
+    #[test]
+    fn display() {
+        assert_eq!(Op::Add(1, 2, 3).to_string(), "add r1, r2, r3");
+        assert_eq!(Op::LoadProgram(6, 0).to_string(), "loadprogram r6, r0");
+        assert_eq!(Op::Output(7).to_string(), "output r7");
+        assert_eq!(Op::Halt.to_string(), "halt");
+        assert_eq!(Op::Mov(0, 4239).to_string(), "mov r0, 0x108f");
+    }
+
+    #[test]
+    fn parse_invalid() {
+        assert_eq!(Op::parse(14 << 28), Err(VmError::InvalidOpcode(14)));
+    }
+}
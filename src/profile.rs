@@ -0,0 +1,401 @@
+//! Exact profiler attributing executed instructions to the program they
+//! belong to and to address ranges within it.
+//!
+//! Every `LoadProgram` from an array other than 0 starts a new program,
+//! unless the same program has been loaded from that array before.
+//! Jumps within a program, `LoadProgram` from array 0, are taken for calls,
+//! returns or plain jumps using a heuristic, since the machine has no notion
+//! of a call:
+//!
+//! - a jump to the return address of a frame on the stack returns to it,
+//! - a jump is a call if some register holds the address right after the
+//!   jump, that is a return address has been set up,
+//! - anything else is a jump within the current function.
+//!
+//! Targets of calls split each program into ranges, the report sums up
+//! instructions per range. The call stacks can also be written in the
+//! folded format understood by flamegraph tools.
+
+use crate::io::Io;
+use crate::machine::{Fault, Limits, Machine, Status};
+use crate::op::Op;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeSet, HashMap};
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::io;
+use std::io::Write;
+
+// Calls nested deeper than this are folded into the deepest frame.
+const MAX_DEPTH: usize = 256;
+
+struct Program {
+    /// Array the program was loaded from, 0 for the initial image.
+    origin: u32,
+    /// Step at which it was first loaded.
+    loaded_at: u64,
+    // Hash of the words, to recognize the program when it's loaded again.
+    hash: u64,
+    counts: Vec<u64>,
+    /// Addresses the program was entered at or called.
+    entries: BTreeSet<u32>,
+    // Root node per address the program was entered at.
+    roots: HashMap<u32, usize>,
+}
+
+struct Node {
+    parent: Option<usize>,
+    program: usize,
+    entry: u32,
+    children: HashMap<u32, usize>,
+    count: u64,
+}
+
+struct Frame {
+    node: usize,
+    ret: Option<u32>,
+}
+
+#[derive(Default)]
+pub struct Profiler {
+    programs: Vec<Program>,
+    nodes: Vec<Node>,
+    stack: Vec<Frame>,
+    total: u64,
+}
+
+impl Profiler {
+    pub fn new() -> Self {
+        Profiler::default()
+    }
+
+    fn enter_program<T: Io>(&mut self, um: &Machine<T>, origin: u32, entry: u32) {
+        let words = um.mem().array(0).unwrap_or(&[]);
+        let mut hasher = DefaultHasher::new();
+        words.hash(&mut hasher);
+        let hash = hasher.finish();
+        let known = self
+            .programs
+            .iter()
+            .position(|p| p.origin == origin && p.hash == hash && p.counts.len() == words.len());
+        let program = known.unwrap_or_else(|| {
+            self.programs.push(Program {
+                origin,
+                loaded_at: um.steps(),
+                hash,
+                counts: vec![0; words.len()],
+                entries: BTreeSet::new(),
+                roots: HashMap::new(),
+            });
+            self.programs.len() - 1
+        });
+
+        let node = match self.programs[program].roots.get(&entry) {
+            Some(&node) => node,
+            None => {
+                self.nodes.push(Node {
+                    parent: None,
+                    program,
+                    entry,
+                    children: HashMap::new(),
+                    count: 0,
+                });
+                let node = self.nodes.len() - 1;
+                self.programs[program].roots.insert(entry, node);
+                node
+            }
+        };
+        self.stack.clear();
+        self.stack.push(Frame { node, ret: None });
+        self.programs[program].entries.insert(entry);
+    }
+
+    fn jump(&mut self, regs: &[u32; 8], site: u32, target: u32) {
+        if let Some(depth) = self.stack.iter().rposition(|f| f.ret == Some(target)) {
+            self.stack.truncate(depth);
+            return;
+        }
+        let ret = site.wrapping_add(1);
+        if !regs.contains(&ret) {
+            return;
+        }
+
+        let parent = self.stack[self.stack.len() - 1].node;
+        let program = self.nodes[parent].program;
+        let node = match self.nodes[parent].children.get(&target) {
+            Some(&node) => node,
+            None => {
+                self.nodes.push(Node {
+                    parent: Some(parent),
+                    program,
+                    entry: target,
+                    children: HashMap::new(),
+                    count: 0,
+                });
+                let node = self.nodes.len() - 1;
+                self.nodes[parent].children.insert(target, node);
+                node
+            }
+        };
+        self.programs[program].entries.insert(target);
+        if self.stack.len() < MAX_DEPTH {
+            self.stack.push(Frame {
+                node,
+                ret: Some(ret),
+            });
+        }
+    }
+
+    /// Executes a single instruction and accounts for it.
+    pub fn step<T: Io>(&mut self, um: &mut Machine<T>) -> Result<Status, Fault> {
+        if self.programs.is_empty() {
+            self.enter_program(um, um.origin(), um.ip());
+        }
+
+        let ip = um.ip();
+        let op = um.next_op();
+        let regs = *um.regs();
+        let status = um.step()?;
+        if status == Status::Eof {
+            return Ok(status);
+        }
+
+        self.total += 1;
+        let node = self.stack[self.stack.len() - 1].node;
+        let program = self.nodes[node].program;
+        if let Some(count) = self.programs[program].counts.get_mut(ip as usize) {
+            *count += 1;
+        }
+        self.nodes[node].count += 1;
+
+        if let Some(Op::LoadProgram(b, c)) = op {
+            match regs[b] {
+                0 => self.jump(&regs, ip, regs[c]),
+                origin => self.enter_program(um, origin, regs[c]),
+            }
+        }
+        Ok(status)
+    }
+
+    /// Runs the machine within `limits`, counting every instruction.
+    pub fn run<T: Io>(&mut self, um: &mut Machine<T>, limits: Limits) -> Result<Status, Fault> {
+        um.run_by(limits, |um, _| self.step(um))
+    }
+
+    /// Instructions per address range, the hottest `top` ranges first.
+    pub fn report(&self, top: usize) -> Report {
+        let mut ranges = Vec::new();
+        for (p, program) in self.programs.iter().enumerate() {
+            let mut current: Option<Range> = None;
+            for (ip, &count) in program.counts.iter().enumerate() {
+                let ip = ip as u32;
+                let start = program
+                    .entries
+                    .range(..=ip)
+                    .next_back()
+                    .copied()
+                    .unwrap_or(0);
+                if current.as_ref().is_some_and(|r| r.start != start) {
+                    ranges.extend(current.take());
+                }
+                if count == 0 {
+                    continue;
+                }
+                let range = current.get_or_insert(Range {
+                    program: p,
+                    start,
+                    end: ip,
+                    count: 0,
+                    hottest: ip,
+                });
+                range.end = ip;
+                range.count += count;
+                if count > program.counts[range.hottest as usize] {
+                    range.hottest = ip;
+                }
+            }
+            ranges.extend(current);
+        }
+        ranges.sort_by(|a, b| b.count.cmp(&a.count).then(a.start.cmp(&b.start)));
+        ranges.truncate(top);
+
+        Report {
+            total: self.total,
+            programs: self
+                .programs
+                .iter()
+                .map(|p| (p.origin, p.loaded_at, p.counts.len()))
+                .collect(),
+            ranges,
+        }
+    }
+
+    fn frame_name(&self, node: usize) -> String {
+        let node = &self.nodes[node];
+        format!("p{}@{:08x}", node.program, node.entry)
+    }
+
+    /// Writes call stacks with their instruction counts in the folded
+    /// format, one `frame;frame;... count` line per stack.
+    pub fn write_folded<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        for (n, node) in self.nodes.iter().enumerate() {
+            if node.count == 0 {
+                continue;
+            }
+            let mut path = vec![self.frame_name(n)];
+            let mut parent = node.parent;
+            while let Some(p) = parent {
+                path.push(self.frame_name(p));
+                parent = self.nodes[p].parent;
+            }
+            path.reverse();
+            writeln!(out, "{} {}", path.join(";"), node.count)?;
+        }
+        Ok(())
+    }
+}
+
+/// Instructions executed within a range of a program.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Range {
+    /// Index of the program, in the order they were loaded.
+    pub program: usize,
+    pub start: u32,
+    /// Last address in the range which has been executed.
+    pub end: u32,
+    pub count: u64,
+    /// Address executed the most within the range.
+    pub hottest: u32,
+}
+
+pub struct Report {
+    pub total: u64,
+    /// Array each program was loaded from, the step it happened at and
+    /// the program's size.
+    pub programs: Vec<(u32, u64, usize)>,
+    pub ranges: Vec<Range>,
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "{} instructions in {} programs",
+            self.total,
+            self.programs.len()
+        )?;
+        for (n, &(origin, step, len)) in self.programs.iter().enumerate() {
+            match origin {
+                0 if n == 0 => writeln!(f, "  p{}: initial image, {} words", n, len)?,
+                _ => writeln!(
+                    f,
+                    "  p{}: loaded from array {} at step {}, {} words",
+                    n, origin, step, len
+                )?,
+            }
+        }
+        writeln!(f, "  {:>14} {:>7}  range", "instructions", "share")?;
+        for r in &self.ranges {
+            let share = 100.0 * r.count as f64 / self.total.max(1) as f64;
+            writeln!(
+                f,
+                "  {:>14} {:>6.2}%  p{} {:08x}-{:08x}, hottest {:08x}",
+                r.count, share, r.program, r.start, r.end, r.hottest
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm;
+    use crate::io::Buffer;
+
+    // Calls `twice` twice, which calls `inc` two times, then loads a
+    // program made of a single `halt`.
+    const PROG: &str = "
+                mov r0, 0
+                mov r1, 1
+                mov r6, twice
+                mov r7, back1
+                loadprogram r0, r6
+        back1:  mov r6, twice
+                mov r7, back2
+                loadprogram r0, r6
+        back2:  alloc r2, r1
+                mov r3, stop
+                memread r4, r0, r3
+                memwrite r2, r0, r4
+                loadprogram r2, r0
+        twice:  add r5, r7, r0
+                mov r6, inc
+                mov r7, ret1
+                loadprogram r0, r6
+        ret1:   mov r7, ret2
+                loadprogram r0, r6
+        ret2:   loadprogram r0, r5
+        inc:    add r3, r3, r1
+                loadprogram r0, r7
+        stop:   halt
+    ";
+
+    fn profile(src: &str) -> Profiler {
+        let image = asm::image(&asm::assemble(src).unwrap());
+        let mut um = Machine::load_with_io(&image, Buffer::default()).unwrap();
+        let mut profiler = Profiler::new();
+        assert_eq!(profiler.run(&mut um, Limits::default()), Ok(Status::Halted));
+        assert_eq!(profiler.total, um.steps());
+        profiler
+    }
+
+    #[test]
+    fn folded_stacks() {
+        let profiler = profile(PROG);
+        let mut out = Vec::new();
+        profiler.write_folded(&mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "p0@00000000 13\n\
+             p0@00000000;p0@0000000d 14\n\
+             p0@00000000;p0@0000000d;p0@00000014 8\n\
+             p1@00000000 1\n"
+        );
+    }
+
+    #[test]
+    fn report() {
+        let report = profile(PROG).report(3);
+        assert_eq!(report.total, 36);
+        assert_eq!(report.programs, [(0, 0, 23), (1, 35, 1)]);
+        let ranges: Vec<_> = report
+            .ranges
+            .iter()
+            .map(|r| (r.program, r.start, r.end, r.count))
+            .collect();
+        assert_eq!(
+            ranges,
+            [(0, 0xd, 0x13, 14), (0, 0, 0xc, 13), (0, 0x14, 0x15, 8)]
+        );
+        assert!(report
+            .to_string()
+            .contains("p1: loaded from array 1 at step 35, 1 words"));
+    }
+
+    #[test]
+    fn reload_program() {
+        // Both programs load array 1 over and over.
+        let image = asm::image(&asm::assemble("loadprogram r2, r3").unwrap());
+        let mut um = Machine::load_with_io(&image, Buffer::default()).unwrap();
+        let id = um.mem_mut().alloc(1).unwrap();
+        um.mem_mut().write(id, 0, 0xC000_0013).unwrap();
+        um.set_reg(2, id);
+
+        let mut profiler = Profiler::new();
+        let status = profiler.run(&mut um, Limits::steps(100));
+        assert_eq!(status, Ok(Status::BudgetExhausted));
+        assert_eq!(profiler.report(1).programs, [(0, 0, 1), (1, 1, 1)]);
+        assert_eq!(profiler.nodes.len(), 2);
+    }
+}
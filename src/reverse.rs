@@ -0,0 +1,316 @@
+//! Reverse execution: the machine is taken back to an earlier step by
+//! restoring the closest checkpoint before it and executing forward again.
+//! Input is recorded as it's read, so that re-execution sees exactly the
+//! same bytes, and output which has been produced already is not repeated.
+
+use crate::io::Io;
+use crate::machine::{Fault, Machine, State, Status};
+use std::io;
+
+/// Io backend which records all input read through it and replays it
+/// after being rewound.
+pub struct Replay<T> {
+    inner: T,
+    input: Vec<Option<u8>>,
+    // Position in `input` of the next read.
+    read: usize,
+    // Bytes written since the start, and the number of them which actually
+    // went through to `inner`.
+    written: u64,
+    output: u64,
+}
+
+/// Position of a `Replay` in its input and output.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Mark {
+    read: usize,
+    written: u64,
+}
+
+impl<T: Io> Replay<T> {
+    pub fn new(inner: T) -> Self {
+        Replay {
+            inner,
+            input: Vec::new(),
+            read: 0,
+            written: 0,
+            output: 0,
+        }
+    }
+
+    pub fn inner(&self) -> &T {
+        &self.inner
+    }
+
+    pub fn inner_mut(&mut self) -> &mut T {
+        &mut self.inner
+    }
+
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
+
+    pub fn mark(&self) -> Mark {
+        Mark {
+            read: self.read,
+            written: self.written,
+        }
+    }
+
+    /// Goes back to `mark`, everything after it is going to be replayed.
+    pub fn rewind(&mut self, mark: Mark) {
+        self.read = mark.read;
+        self.written = mark.written;
+    }
+
+    /// Whether there is recorded input left to replay.
+    pub fn replaying(&self) -> bool {
+        self.read < self.input.len()
+    }
+}
+
+impl<T: Io> Io for Replay<T> {
+    fn write_byte(&mut self, byte: u8) -> io::Result<()> {
+        if self.written == self.output {
+            self.inner.write_byte(byte)?;
+            self.output += 1;
+        }
+        self.written += 1;
+        Ok(())
+    }
+
+    fn read_byte(&mut self) -> io::Result<Option<u8>> {
+        let byte = match self.input.get(self.read) {
+            Some(&byte) => byte,
+            None => {
+                let byte = self.inner.read_byte()?;
+                self.input.push(byte);
+                byte
+            }
+        };
+        self.read += 1;
+        Ok(byte)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+struct Checkpoint {
+    state: State,
+    mark: Mark,
+}
+
+/// Periodic checkpoints of a machine, which let it go back to any step
+/// since the history was started.
+///
+/// Once there are too many checkpoints every other one is dropped and they
+/// are taken half as often, so the memory used stays bounded however long
+/// the program runs.
+pub struct History {
+    checkpoints: Vec<Checkpoint>,
+    interval: u64,
+    max: usize,
+}
+
+impl History {
+    pub const DEFAULT_INTERVAL: u64 = 100_000;
+    pub const DEFAULT_MAX: usize = 32;
+
+    /// Starts the history at the current step of `um`.
+    pub fn new<T: Io>(um: &Machine<Replay<T>>) -> Self {
+        History::with_limits(um, History::DEFAULT_INTERVAL, History::DEFAULT_MAX)
+    }
+
+    /// Starts the history taking a checkpoint every `interval` steps and
+    /// keeping at most `max` of them.
+    pub fn with_limits<T: Io>(um: &Machine<Replay<T>>, interval: u64, max: usize) -> Self {
+        let mut history = History {
+            checkpoints: Vec::new(),
+            interval: interval.max(1),
+            max: max.max(2),
+        };
+        history.take(um);
+        history
+    }
+
+    /// Earliest step the machine can go back to.
+    pub fn start(&self) -> u64 {
+        self.checkpoints[0].state.steps()
+    }
+
+    fn last(&self) -> u64 {
+        self.checkpoints[self.checkpoints.len() - 1].state.steps()
+    }
+
+    fn take<T: Io>(&mut self, um: &Machine<Replay<T>>) {
+        self.checkpoints.push(Checkpoint {
+            state: um.state(),
+            mark: um.io().mark(),
+        });
+        if self.checkpoints.len() > self.max {
+            let mut n = 0;
+            self.checkpoints.retain(|_| {
+                n += 1;
+                n % 2 == 1
+            });
+            self.interval *= 2;
+        }
+    }
+
+    /// Takes a checkpoint if one is due, to be called before each step.
+    pub fn record<T: Io>(&mut self, um: &Machine<Replay<T>>) {
+        if um.steps() >= self.last() + self.interval {
+            self.take(um);
+        }
+    }
+
+    /// Restores the latest checkpoint at or before `step`.
+    fn restore<T: Io>(&self, um: &mut Machine<Replay<T>>, step: u64) {
+        let idx = self
+            .checkpoints
+            .iter()
+            .rposition(|c| c.state.steps() <= step)
+            .unwrap_or(0);
+        let checkpoint = &self.checkpoints[idx];
+        um.set_state(checkpoint.state.clone());
+        um.io_mut().rewind(checkpoint.mark);
+    }
+
+    /// Brings the machine to `step` by re-executing from the closest
+    /// checkpoint, or to the start of the history if `step` is before it.
+    pub fn seek<T: Io>(&self, um: &mut Machine<Replay<T>>, step: u64) -> Result<(), Fault> {
+        self.restore(um, step);
+        forward(um, step, |_| false).map(|_| ())
+    }
+
+    /// Finds the latest step before `end` at which `hit` returns true.
+    /// `hit` is called before each re-executed instruction, it returns
+    /// whether the state before the instruction is a match. The machine is
+    /// left in an unspecified state and should be moved with `seek`.
+    pub fn find_last<T, F>(
+        &self,
+        um: &mut Machine<Replay<T>>,
+        end: u64,
+        mut hit: F,
+    ) -> Result<Option<u64>, Fault>
+    where
+        T: Io,
+        F: FnMut(&Machine<Replay<T>>) -> bool,
+    {
+        let mut end = end;
+        for checkpoint in self.checkpoints.iter().rev() {
+            let start = checkpoint.state.steps();
+            if start >= end {
+                continue;
+            }
+            um.set_state(checkpoint.state.clone());
+            um.io_mut().rewind(checkpoint.mark);
+            if let Some(step) = forward(um, end, &mut hit)? {
+                return Ok(Some(step));
+            }
+            end = start;
+        }
+        Ok(None)
+    }
+}
+
+/// Executes until step `end`, returns the last step before it at which
+/// `hit` returned true.
+fn forward<T, F>(um: &mut Machine<Replay<T>>, end: u64, mut hit: F) -> Result<Option<u64>, Fault>
+where
+    T: Io,
+    F: FnMut(&Machine<Replay<T>>) -> bool,
+{
+    let mut found = None;
+    while um.steps() < end {
+        if hit(um) {
+            found = Some(um.steps());
+        }
+        match um.step()? {
+            Status::Running => {}
+            // Input was over the first time round, but more came later.
+            Status::Eof if um.io().replaying() => {}
+            _ => break,
+        }
+    }
+    Ok(found)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm;
+    use crate::io::Buffer;
+
+    // Echoes its input, keeping a running sum of it in [1][0].
+    const ECHO: &str = "
+                mov r1, 1
+                alloc r2, r1
+                mov r3, 0
+                mov r6, loop
+                mov r7, 0
+        loop:   input r4
+                output r4
+                memread r5, r2, r3
+                add r5, r5, r4
+                memwrite r2, r3, r5
+                loadprogram r7, r6
+    ";
+
+    fn machine(input: &str) -> Machine<Replay<Buffer>> {
+        let image = asm::image(&asm::assemble(ECHO).unwrap());
+        Machine::load_with_io(&image, Replay::new(Buffer::new(input))).unwrap()
+    }
+
+    #[test]
+    fn seek_back_and_forth() {
+        let mut um = machine("abcdef");
+        let mut history = History::with_limits(&um, 7, 4);
+        for _ in 0..40 {
+            history.record(&um);
+            um.step().unwrap();
+        }
+        let regs = *um.regs();
+        let sum = *um.mem().read(1, 0).unwrap();
+
+        history.seek(&mut um, 12).unwrap();
+        assert_eq!(um.steps(), 12);
+        assert_eq!(um.reg(4), u32::from(b'b'));
+
+        history.seek(&mut um, 40).unwrap();
+        assert_eq!(um.regs(), &regs);
+        assert_eq!(um.mem().read(1, 0), Ok(&sum));
+        // Nothing got printed twice.
+        assert_eq!(um.io().inner().output(), b"abcdef");
+    }
+
+    #[test]
+    fn thinning() {
+        let mut um = machine("abcdefghijklmnopqrstuvwxyz");
+        let mut history = History::with_limits(&um, 2, 4);
+        for _ in 0..100 {
+            history.record(&um);
+            um.step().unwrap();
+        }
+        assert!(history.checkpoints.len() <= 4);
+        assert_eq!(history.start(), 0);
+        history.seek(&mut um, 1).unwrap();
+        assert_eq!(um.steps(), 1);
+    }
+
+    #[test]
+    fn last_write() {
+        let mut um = machine("xyz");
+        let mut history = History::with_limits(&um, 5, 8);
+        for _ in 0..20 {
+            history.record(&um);
+            um.step().unwrap();
+        }
+        // Last executed memwrite, before the one just about to happen.
+        let found = history.find_last(&mut um, 20, |um| um.ip() == 9).unwrap();
+        assert_eq!(found, Some(15));
+        assert_eq!(history.find_last(&mut um, 9, |um| um.ip() == 9), Ok(None));
+    }
+}
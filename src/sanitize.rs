@@ -0,0 +1,566 @@
+//! Checks of how a program uses its arrays, catching bugs which the machine
+//! itself doesn't fault on.
+//!
+//! Every value in the registers and in memory carries the allocation it
+//! came from, if it has been returned by `Alloc`. Only moves keep it:
+//! `CondMov`, memory reads and writes, and adding zero. That's how an array
+//! id made up by arithmetic gets caught, as well as a stale id of an array
+//! which has been freed, in particular once the id has been handed out
+//! again. Each word of an array is also tracked for whether it has been
+//! written since the array was allocated.
+
+use crate::error::VmError;
+use crate::io::Io;
+use crate::machine::{Fault, Limits, Machine, Status};
+use crate::op::{Op, Reg};
+use std::collections::HashMap;
+use std::fmt;
+
+// Shadow of a word which hasn't been written since allocation. Other
+// shadows are tags: 0 for values which aren't array ids, otherwise the
+// index of the allocation in `Sanitizer::allocs` plus one.
+const UNWRITTEN: u32 = u32::MAX;
+
+struct Allocation {
+    /// `None` if the array was there before sanitizing started.
+    ip: Option<u32>,
+    freed: Option<u32>,
+}
+
+/// Tags of the words of a live array.
+struct Shadow {
+    tag: u32,
+    words: Vec<u32>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Kind {
+    /// Use of an array id which didn't come from `Alloc`.
+    Forged,
+    /// Use of the id of an array which has been freed, possibly allocated
+    /// again since.
+    UseAfterFree,
+    /// Read of a word which hasn't been written since allocation.
+    Uninitialized,
+    /// Array still live when the program halted.
+    Leak,
+}
+
+/// A problem found by the sanitizer, reported once per kind and ip.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Finding {
+    pub kind: Kind,
+    /// Where it happened, for leaks where the arrays were allocated.
+    pub ip: Option<u32>,
+    /// Array id used the first time it happened.
+    pub id: u32,
+    /// Offset read, for reads of unwritten words.
+    pub offset: Option<u32>,
+    /// Where the array was allocated, `None` if it was there before
+    /// sanitizing started.
+    pub allocated_at: Option<u32>,
+    pub freed_at: Option<u32>,
+    /// Where the freed id was allocated again.
+    pub reused_at: Option<u32>,
+    /// Times it happened, for leaks the number of arrays.
+    pub count: u64,
+}
+
+struct At(Option<u32>);
+
+impl fmt::Display for At {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.0 {
+            Some(ip) => write!(f, "ip {:08x}", ip),
+            None => write!(f, "an unknown ip"),
+        }
+    }
+}
+
+impl fmt::Display for Finding {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.kind == Kind::Leak {
+            return write!(
+                f,
+                "{} arrays allocated at {} still live at halt",
+                self.count,
+                At(self.allocated_at)
+            );
+        }
+
+        write!(f, "{}: ", At(self.ip))?;
+        match self.kind {
+            Kind::Forged => write!(f, "array id {} wasn't returned by alloc", self.id)?,
+            Kind::UseAfterFree => {
+                write!(
+                    f,
+                    "array {} used after free, allocated at {}, freed at {}",
+                    self.id,
+                    At(self.allocated_at),
+                    At(self.freed_at)
+                )?;
+                if self.reused_at.is_some() {
+                    write!(f, ", allocated again at {}", At(self.reused_at))?;
+                }
+            }
+            Kind::Uninitialized => write!(
+                f,
+                "read of [{}][{}] never written since allocation at {}",
+                self.id,
+                self.offset.unwrap_or(0),
+                At(self.allocated_at)
+            )?,
+            Kind::Leak => unreachable!(),
+        }
+        if self.count > 1 {
+            write!(f, ", {} times", self.count)?;
+        }
+        Ok(())
+    }
+}
+
+/// Runs the machine checking every use of an array.
+#[derive(Default)]
+pub struct Sanitizer {
+    started: bool,
+    allocs: Vec<Allocation>,
+    // Indexed by array id.
+    arrays: Vec<Option<Shadow>>,
+    regs: [u32; 8],
+    findings: Vec<Finding>,
+    seen: HashMap<(Kind, Option<u32>), usize>,
+    // Ip of the instruction being executed if the array id it uses has been
+    // reported.
+    bad_id: Option<u32>,
+}
+
+impl Sanitizer {
+    pub fn new() -> Self {
+        Sanitizer::default()
+    }
+
+    /// Problems found so far, in the order they first happened.
+    pub fn findings(&self) -> &[Finding] {
+        &self.findings
+    }
+
+    /// Whether `fault` comes from an array id which has been reported
+    /// already, which says more about it than the fault itself.
+    pub fn diagnosed(&self, fault: &Fault) -> bool {
+        let bad_id = matches!(
+            fault.error,
+            VmError::FreedArray(_) | VmError::UnallocatedArray(_) | VmError::DoubleFree(_)
+        );
+        bad_id && self.bad_id == Some(fault.ip)
+    }
+
+    /// Takes over the arrays which are already there. Values which are ids
+    /// of live arrays are assumed to be those ids.
+    fn start<T: Io>(&mut self, um: &Machine<T>) {
+        self.started = true;
+        let mem = um.mem();
+        let mut tags = HashMap::new();
+        for id in 1..mem.len() {
+            if mem.array(id).is_ok() {
+                self.allocs.push(Allocation {
+                    ip: None,
+                    freed: None,
+                });
+                tags.insert(id, self.allocs.len() as u32);
+            }
+        }
+        let guess = |word: &u32| tags.get(word).copied().unwrap_or(0);
+
+        for id in 0..mem.len() {
+            let shadow = mem.array(id).ok().map(|words| Shadow {
+                tag: tags.get(&id).copied().unwrap_or(0),
+                words: words.iter().map(guess).collect(),
+            });
+            self.arrays.push(shadow);
+        }
+        for (tag, val) in self.regs.iter_mut().zip(um.regs()) {
+            *tag = guess(val);
+        }
+    }
+
+    fn report(&mut self, finding: Finding) {
+        let key = match finding.kind {
+            Kind::Leak => (Kind::Leak, finding.allocated_at),
+            kind => (kind, finding.ip),
+        };
+        match self.seen.get(&key) {
+            Some(&n) => self.findings[n].count += finding.count,
+            None => {
+                self.seen.insert(key, self.findings.len());
+                self.findings.push(finding);
+            }
+        }
+    }
+
+    /// Checks the use of the array id in register `reg`, returns whether
+    /// there was a problem with it.
+    fn check_id(&mut self, ip: u32, regs: &[u32; 8], reg: Reg) -> bool {
+        let id = regs[reg];
+        if id == 0 {
+            return false;
+        }
+        let finding = Finding {
+            kind: Kind::Forged,
+            ip: Some(ip),
+            id,
+            offset: None,
+            allocated_at: None,
+            freed_at: None,
+            reused_at: None,
+            count: 1,
+        };
+        match self.regs[reg] {
+            0 => self.report(finding),
+            tag => {
+                let alloc = &self.allocs[tag as usize - 1];
+                let freed = match alloc.freed {
+                    Some(freed) => freed,
+                    None => return false,
+                };
+                let reused = self
+                    .shadow(id)
+                    .and_then(|shadow| self.allocs[shadow.tag as usize - 1].ip);
+                let finding = Finding {
+                    kind: Kind::UseAfterFree,
+                    allocated_at: alloc.ip,
+                    freed_at: Some(freed),
+                    reused_at: reused,
+                    ..finding
+                };
+                self.report(finding);
+            }
+        }
+        true
+    }
+
+    fn shadow(&self, id: u32) -> Option<&Shadow> {
+        self.arrays.get(id as usize).and_then(Option::as_ref)
+    }
+
+    fn shadow_mut(&mut self, id: u32) -> Option<&mut Shadow> {
+        self.arrays.get_mut(id as usize).and_then(Option::as_mut)
+    }
+
+    /// Looks for problems with `op` before it's executed, returns whether
+    /// the array id it uses is one.
+    fn check(&mut self, ip: u32, op: Op, regs: &[u32; 8]) -> bool {
+        match op {
+            Op::MemRead(_, b, c) => {
+                // Whatever the words of an array which isn't the one meant
+                // hold doesn't matter.
+                if self.check_id(ip, regs, b) {
+                    return true;
+                }
+                let (id, offset) = (regs[b], regs[c]);
+                let word = self
+                    .shadow(id)
+                    .map(|s| (s.tag, s.words.get(offset as usize)));
+                if let Some((tag, Some(&UNWRITTEN))) = word {
+                    let allocated_at = self.allocs[tag as usize - 1].ip;
+                    self.report(Finding {
+                        kind: Kind::Uninitialized,
+                        ip: Some(ip),
+                        id,
+                        offset: Some(offset),
+                        allocated_at,
+                        freed_at: None,
+                        reused_at: None,
+                        count: 1,
+                    });
+                }
+                false
+            }
+            Op::MemWrite(a, _, _) => self.check_id(ip, regs, a),
+            Op::Free(c) => self.check_id(ip, regs, c),
+            Op::LoadProgram(b, _) => self.check_id(ip, regs, b),
+            _ => false,
+        }
+    }
+
+    /// Keeps track of values and arrays after `op` has been executed with
+    /// registers `regs` before it.
+    fn update<T: Io>(&mut self, um: &Machine<T>, ip: u32, op: Op, regs: &[u32; 8]) {
+        match op {
+            Op::CondMov(a, b, c) => {
+                if regs[c] != 0 {
+                    self.regs[a] = self.regs[b];
+                }
+            }
+            Op::MemRead(a, b, c) => {
+                let word = self.shadow(regs[b]).map(|s| s.words[regs[c] as usize]);
+                self.regs[a] = match word {
+                    Some(UNWRITTEN) | None => 0,
+                    Some(tag) => tag,
+                };
+            }
+            Op::MemWrite(a, b, c) => {
+                let tag = self.regs[c];
+                if let Some(shadow) = self.shadow_mut(regs[a]) {
+                    shadow.words[regs[b] as usize] = tag;
+                }
+            }
+            Op::Add(a, b, c) => {
+                self.regs[a] = match (regs[b], regs[c]) {
+                    (_, 0) => self.regs[b],
+                    (0, _) => self.regs[c],
+                    _ => 0,
+                };
+            }
+            Op::Mul(a, _, _) | Op::Div(a, _, _) | Op::Nand(a, _, _) | Op::Mov(a, _) => {
+                self.regs[a] = 0;
+            }
+            Op::Input(c) => self.regs[c] = 0,
+            Op::Alloc(b, c) => {
+                let id = um.reg(b);
+                self.allocs.push(Allocation {
+                    ip: Some(ip),
+                    freed: None,
+                });
+                let tag = self.allocs.len() as u32;
+                if self.arrays.len() <= id as usize {
+                    self.arrays.resize_with(id as usize + 1, || None);
+                }
+                self.arrays[id as usize] = Some(Shadow {
+                    tag,
+                    words: vec![UNWRITTEN; regs[c] as usize],
+                });
+                self.regs[b] = tag;
+            }
+            Op::Free(c) => {
+                if let Some(shadow) = self.arrays[regs[c] as usize].take() {
+                    self.allocs[shadow.tag as usize - 1].freed = Some(ip);
+                }
+            }
+            Op::LoadProgram(b, _) => {
+                if regs[b] != 0 {
+                    let words = self.shadow(regs[b]).map(|s| s.words.clone());
+                    self.arrays[0] = words.map(|words| Shadow { tag: 0, words });
+                }
+            }
+            Op::Output(_) | Op::Halt => {}
+        }
+    }
+
+    /// Reports arrays which are still live, the program has halted.
+    fn leaks(&mut self) {
+        let live: Vec<_> = self.arrays[1..]
+            .iter()
+            .flatten()
+            .map(|shadow| self.allocs[shadow.tag as usize - 1].ip)
+            .collect();
+        for allocated_at in live {
+            self.report(Finding {
+                kind: Kind::Leak,
+                ip: None,
+                id: 0,
+                offset: None,
+                allocated_at,
+                freed_at: None,
+                reused_at: None,
+                count: 1,
+            });
+        }
+    }
+
+    /// Executes a single instruction, checking it first.
+    pub fn step<T: Io>(&mut self, um: &mut Machine<T>) -> Result<Status, Fault> {
+        if !self.started {
+            self.start(um);
+        }
+
+        let ip = um.ip();
+        let op = um.next_op();
+        let regs = *um.regs();
+        self.bad_id = match op {
+            Some(op) if self.check(ip, op, &regs) => Some(ip),
+            _ => None,
+        };
+        let status = um.step()?;
+        match (op, status) {
+            (_, Status::Eof) => {}
+            (_, Status::Halted) => self.leaks(),
+            (Some(op), _) => self.update(um, ip, op, &regs),
+            (None, _) => {}
+        }
+        Ok(status)
+    }
+
+    /// Runs the machine within `limits`, checking every instruction.
+    pub fn run<T: Io>(&mut self, um: &mut Machine<T>, limits: Limits) -> Result<Status, Fault> {
+        um.run_by(limits, |um, _| self.step(um))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm;
+    use crate::io::Buffer;
+
+    fn sanitize(src: &str) -> Vec<Finding> {
+        let image = asm::image(&asm::assemble(src).unwrap());
+        let mut um = Machine::load_with_io(&image, Buffer::default()).unwrap();
+        let mut sanitizer = Sanitizer::new();
+        assert_eq!(
+            sanitizer.run(&mut um, Limits::default()),
+            Ok(Status::Halted)
+        );
+        sanitizer.findings().to_vec()
+    }
+
+    #[test]
+    fn clean() {
+        // Keeps the id of one array in another, then frees both.
+        let src = "
+                mov r1, 1
+                alloc r2, r1
+                alloc r3, r1
+                memwrite r3, r0, r2
+                memread r4, r3, r0
+                memwrite r4, r0, r1
+                memread r5, r4, r0
+                free r4
+                free r3
+                halt
+        ";
+        assert_eq!(sanitize(src), []);
+    }
+
+    #[test]
+    fn forged_and_unwritten() {
+        let src = "
+                mov r1, 1
+                alloc r2, r1
+                mov r3, 1
+                memread r4, r3, r0
+                memread r4, r2, r0
+                memread r4, r2, r0
+                halt
+        ";
+        let findings = sanitize(src);
+        // The read through the forged id is reported once, for the id.
+        assert_eq!(findings.len(), 4);
+        let report: Vec<_> = findings.iter().map(|f| f.to_string()).collect();
+        assert_eq!(
+            report,
+            [
+                "ip 00000003: array id 1 wasn't returned by alloc",
+                "ip 00000004: read of [1][0] never written since allocation at ip 00000001",
+                "ip 00000005: read of [1][0] never written since allocation at ip 00000001",
+                "1 arrays allocated at ip 00000001 still live at halt",
+            ]
+        );
+    }
+
+    #[test]
+    fn reused_id() {
+        let src = "
+                mov r1, 1
+                alloc r2, r1
+                add r3, r2, r0
+                free r2
+                alloc r4, r1
+                memwrite r3, r0, r1
+                free r3
+                halt
+        ";
+        let findings = sanitize(src);
+        assert_eq!(
+            findings[0],
+            Finding {
+                kind: Kind::UseAfterFree,
+                ip: Some(5),
+                id: 1,
+                offset: None,
+                allocated_at: Some(1),
+                freed_at: Some(3),
+                reused_at: Some(4),
+                count: 1,
+            }
+        );
+        assert_eq!(
+            findings[0].to_string(),
+            "ip 00000005: array 1 used after free, allocated at ip 00000001, \
+             freed at ip 00000003, allocated again at ip 00000004"
+        );
+        // The stale id frees the array allocated again, so nothing leaks.
+        assert_eq!(findings.len(), 2);
+        assert_eq!(
+            (findings[1].kind, findings[1].ip),
+            (Kind::UseAfterFree, Some(6))
+        );
+    }
+
+    #[test]
+    fn fault_on_freed_id() {
+        let src = "
+                mov r1, 1
+                alloc r2, r1
+                free r2
+                memread r3, r2, r0
+                halt
+        ";
+        let image = asm::image(&asm::assemble(src).unwrap());
+        let mut um = Machine::load_with_io(&image, Buffer::default()).unwrap();
+        let mut sanitizer = Sanitizer::new();
+        let fault = sanitizer.run(&mut um, Limits::default()).unwrap_err();
+        assert_eq!(fault.error, VmError::FreedArray(1));
+        assert_eq!(sanitizer.findings().len(), 1);
+        assert_eq!(
+            sanitizer.findings()[0].to_string(),
+            "ip 00000003: array 1 used after free, allocated at ip 00000001, \
+             freed at ip 00000002"
+        );
+        assert!(sanitizer.diagnosed(&fault));
+    }
+
+    #[test]
+    fn leaks_in_loop() {
+        let src = "
+                mov r1, 1
+                mov r2, 3
+                mov r6, loop
+                mov r7, done
+                nand r5, r0, r0
+        loop:   alloc r3, r1
+                memread r4, r3, r0
+                add r2, r2, r5
+                add r4, r7, r0
+                condmov r4, r6, r2
+                loadprogram r0, r4
+        done:   halt
+        ";
+        let report: Vec<_> = sanitize(src).iter().map(|f| f.to_string()).collect();
+        assert_eq!(
+            report,
+            [
+                "ip 00000006: read of [1][0] never written since allocation at ip 00000005, \
+                 3 times",
+                "3 arrays allocated at ip 00000005 still live at halt",
+            ]
+        );
+    }
+
+    #[test]
+    fn arrays_before_start() {
+        let image = asm::image(&asm::assemble("memread r3, r2, r0\nhalt\n").unwrap());
+        let mut um = Machine::load_with_io(&image, Buffer::default()).unwrap();
+        let id = um.mem_mut().alloc(1).unwrap();
+        um.set_reg(2, id);
+        let mut sanitizer = Sanitizer::new();
+        assert_eq!(
+            sanitizer.run(&mut um, Limits::default()),
+            Ok(Status::Halted)
+        );
+        assert_eq!(
+            sanitizer.findings()[0].to_string(),
+            "1 arrays allocated at an unknown ip still live at halt"
+        );
+        assert_eq!(sanitizer.findings().len(), 1);
+    }
+}
@@ -0,0 +1,46 @@
+use crate::mem::MemStats;
+use crate::op::Op;
+use std::fmt;
+
+/// Statistics of a run, collected once turned on with
+/// `Machine::collect_stats`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Stats {
+    pub instructions: u64,
+    /// Executed instructions per opcode.
+    pub op_counts: [u64; 14],
+    /// `LoadProgram`s which replaced the program rather than just jumping.
+    pub program_loads: u64,
+    pub live_arrays: u32,
+    pub mem: MemStats,
+}
+
+impl fmt::Display for Stats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut ops: Vec<_> = self.op_counts.iter().enumerate().collect();
+        ops.sort_by(|a, b| b.1.cmp(a.1));
+        for (code, &count) in ops {
+            if count > 0 {
+                let share = 100.0 * count as f64 / self.instructions as f64;
+                writeln!(f, "  {:<12} {:>14} {:>6.2}%", Op::NAMES[code], count, share)?;
+            }
+        }
+
+        let mem = &self.mem;
+        writeln!(
+            f,
+            "  arrays: {} allocated, {} freed, {} live, {} at peak",
+            mem.allocs, mem.frees, self.live_arrays, mem.peak_arrays
+        )?;
+        writeln!(
+            f,
+            "  words: {} live, {} at peak",
+            mem.live_words, mem.peak_words
+        )?;
+        writeln!(
+            f,
+            "  programs loaded: {}, {} bytes copied",
+            self.program_loads, mem.bytes_copied
+        )
+    }
+}
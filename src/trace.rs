@@ -0,0 +1,263 @@
+//! Execution traces, one line per executed instruction:
+//!
+//! ```text
+//! 12 0000002a 10000299 memread r2, r3, r1 | 00000000 00000007 ... -> ... | r [7][3]=00000041
+//! ```
+//!
+//! The fields are the step number, ip, instruction word, decoded op,
+//! registers before and after the instruction and the memory it touched.
+//! Traces of two runs of the same program can be compared with `diff`.
+
+use crate::debugger::parse_num;
+use crate::io::Io;
+use crate::machine::{Fault, Limits, Machine, Status};
+use crate::op::Op;
+use std::io;
+use std::io::Write;
+
+/// Selects which instructions get traced. An empty filter lets everything
+/// through.
+#[derive(Debug, Clone, Default)]
+pub struct Filter {
+    ips: Vec<(u32, u32)>,
+    // Bit per opcode, 0 if no opcodes were given.
+    ops: u16,
+}
+
+impl Filter {
+    /// Traces instructions at `start..=end`.
+    pub fn ip_range(&mut self, start: u32, end: u32) {
+        self.ips.push((start, end));
+    }
+
+    /// Traces instructions with the given opcode.
+    pub fn op(&mut self, opcode: u32) {
+        self.ops |= 1 << opcode;
+    }
+
+    /// Parses an ip range such as `0x100-0x1ff` or a single ip.
+    pub fn parse_ips(&mut self, s: &str) -> Result<(), String> {
+        let (start, end) = match s.find('-') {
+            Some(dash) => (parse_num(&s[..dash])?, parse_num(&s[dash + 1..])?),
+            None => (parse_num(s)?, parse_num(s)?),
+        };
+        if start > end {
+            return Err(format!("empty range: {}", s));
+        }
+        self.ip_range(start, end);
+        Ok(())
+    }
+
+    /// Parses a comma separated list of op names, e.g. `alloc,free`.
+    pub fn parse_ops(&mut self, s: &str) -> Result<(), String> {
+        for name in s.split(',') {
+            match Op::NAMES.iter().position(|n| n.eq_ignore_ascii_case(name)) {
+                Some(code) => self.op(code as u32),
+                None => return Err(format!("unknown op: {}", name)),
+            }
+        }
+        Ok(())
+    }
+
+    pub fn matches(&self, ip: u32, op: Option<Op>) -> bool {
+        let ip_ok = self.ips.is_empty() || self.ips.iter().any(|&(s, e)| s <= ip && ip <= e);
+        let op_ok = match op {
+            _ if self.ops == 0 => true,
+            Some(op) => self.ops & (1 << op.opcode()) != 0,
+            None => false,
+        };
+        ip_ok && op_ok
+    }
+}
+
+/// Writes a trace of everything the machine executes into `out`.
+///
+/// Errors writing the trace don't stop the program, the first one is kept
+/// and returned by `finish`.
+pub struct Tracer<W: Write> {
+    out: W,
+    filter: Filter,
+    error: Option<io::Error>,
+}
+
+impl<W: Write> Tracer<W> {
+    pub fn new(out: W, filter: Filter) -> Self {
+        Tracer {
+            out,
+            filter,
+            error: None,
+        }
+    }
+
+    /// Executes a single instruction, tracing it if it passes the filter.
+    pub fn step<T: Io>(&mut self, um: &mut Machine<T>) -> Result<Status, Fault> {
+        let ip = um.ip();
+        let op = um.next_op();
+        if !self.filter.matches(ip, op) {
+            return um.step();
+        }
+
+        let step = um.steps();
+        let before = *um.regs();
+        // Read before executing, loadprogram replaces array 0.
+        let word = um.mem().read(0, ip).ok().copied();
+        let result = um.step();
+        if let Ok(Status::Eof) = result {
+            // Nothing was executed, the instruction will be retried.
+            return result;
+        }
+        if self.error.is_none() {
+            if let Err(e) = self.record(step, ip, word, op, &before, um.regs(), &result) {
+                self.error = Some(e);
+            }
+        }
+        result
+    }
+
+    /// Runs the machine within `limits`, tracing what passes the filter.
+    pub fn run<T: Io>(&mut self, um: &mut Machine<T>, limits: Limits) -> Result<Status, Fault> {
+        um.run_by(limits, |um, _| self.step(um))
+    }
+
+    /// Flushes the trace and returns its writer, or the first write error.
+    pub fn finish(mut self) -> io::Result<W> {
+        if let Some(e) = self.error.take() {
+            return Err(e);
+        }
+        self.out.flush()?;
+        Ok(self.out)
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn record(
+        &mut self,
+        step: u64,
+        ip: u32,
+        word: Option<u32>,
+        op: Option<Op>,
+        before: &[u32; 8],
+        after: &[u32; 8],
+        result: &Result<Status, Fault>,
+    ) -> io::Result<()> {
+        let out = &mut self.out;
+        write!(out, "{} {:08x} ", step, ip)?;
+        match (word, op) {
+            (Some(word), Some(op)) => write!(out, "{:08x} {} |", word, op)?,
+            (Some(word), None) => write!(out, "{:08x} .word {:#010x} |", word, word)?,
+            (None, _) => write!(out, "-------- out of program |")?,
+        }
+        for r in before {
+            write!(out, " {:08x}", r)?;
+        }
+
+        let fault = match result {
+            Err(fault) => fault,
+            Ok(_) => {
+                write!(out, " ->")?;
+                for r in after {
+                    write!(out, " {:08x}", r)?;
+                }
+                match op {
+                    Some(Op::MemRead(a, b, c)) => {
+                        write!(out, " | r [{}][{}]={:08x}", before[b], before[c], after[a])?
+                    }
+                    Some(Op::MemWrite(a, b, c)) => {
+                        write!(out, " | w [{}][{}]={:08x}", before[a], before[b], before[c])?
+                    }
+                    Some(Op::Alloc(b, c)) => write!(out, " | alloc [{}] {}", after[b], before[c])?,
+                    Some(Op::Free(c)) => write!(out, " | free [{}]", before[c])?,
+                    Some(Op::LoadProgram(b, c)) => {
+                        write!(out, " | load [{}] {:08x}", before[b], before[c])?
+                    }
+                    _ => {}
+                }
+                return writeln!(out);
+            }
+        };
+        writeln!(out, " -> fault: {}", fault.error)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm;
+    use crate::io::Buffer;
+
+    fn trace(src: &str, filter: Filter) -> String {
+        let image = asm::image(&asm::assemble(src).unwrap());
+        let mut um = Machine::load_with_io(&image, Buffer::new("")).unwrap();
+        let mut tracer = Tracer::new(Vec::new(), filter);
+        let _ = tracer.run(&mut um, Limits::default());
+        String::from_utf8(tracer.finish().unwrap()).unwrap()
+    }
+
+    const PROG: &str = "
+        mov r1, 3
+        alloc r2, r1
+        mov r3, 'A'
+        memwrite r2, r0, r3
+        halt
+    ";
+
+    #[test]
+    fn records() {
+        let out = trace(PROG, Filter::default());
+        let lines: Vec<_> = out.lines().collect();
+        assert_eq!(lines.len(), 5);
+        assert_eq!(
+            lines[0],
+            "0 00000000 d2000003 mov r1, 0x3 | \
+             00000000 00000000 00000000 00000000 00000000 00000000 00000000 00000000 -> \
+             00000000 00000003 00000000 00000000 00000000 00000000 00000000 00000000"
+        );
+        assert!(lines[1].ends_with("| alloc [1] 3"));
+        assert!(lines[3].starts_with("3 00000003 20000083 memwrite r2, r0, r3 |"));
+        assert!(lines[3].ends_with("| w [1][0]=00000041"));
+    }
+
+    #[test]
+    fn fault() {
+        let out = trace("mov r1, 1\nfree r1\n", Filter::default());
+        assert!(out
+            .lines()
+            .nth(1)
+            .unwrap()
+            .ends_with("-> fault: address 1 has not been allocated"));
+    }
+
+    #[test]
+    fn load_program() {
+        // Array 1 is all zeros, so the word at the ip of the loadprogram
+        // changes once it's executed.
+        let src = "
+            mov r1, 8
+            alloc r2, r1
+            loadprogram r2, r0
+        ";
+        let image = asm::image(&asm::assemble(src).unwrap());
+        let mut um = Machine::load_with_io(&image, Buffer::new("")).unwrap();
+        let mut tracer = Tracer::new(Vec::new(), Filter::default());
+        let status = tracer.run(&mut um, Limits::steps(4));
+        assert_eq!(status, Ok(Status::BudgetExhausted));
+        let out = String::from_utf8(tracer.finish().unwrap()).unwrap();
+        let lines: Vec<_> = out.lines().collect();
+        assert!(lines[2].starts_with("2 00000002 c0000010 loadprogram r2, r0 |"));
+        assert!(lines[3].starts_with("3 00000000 00000000 "));
+    }
+
+    #[test]
+    fn filters() {
+        let mut filter = Filter::default();
+        filter.parse_ips("1-0x3").unwrap();
+        filter.parse_ops("Alloc,memwrite,halt").unwrap();
+        let out = trace(PROG, filter);
+        let ips: Vec<_> = out.lines().map(|l| l.split(' ').nth(1).unwrap()).collect();
+        assert_eq!(ips, ["00000001", "00000003"]);
+
+        let mut filter = Filter::default();
+        assert!(filter.parse_ips("3-1").is_err());
+        assert!(filter.parse_ips("x").is_err());
+        assert!(filter.parse_ops("alloc,jump").is_err());
+    }
+}
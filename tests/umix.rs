@@ -0,0 +1,30 @@
+use std::fs;
+use um::image::Image;
+use um::io::Buffer;
+use um::machine::{Machine, Status};
+
+#[test]
+fn umix_guest_login() {
+    let prog = fs::read("prog.um").unwrap();
+    let mut um = Machine::load_with_io(&prog, Buffer::new("guest\n")).unwrap();
+    assert_eq!(um.run(), Ok(Status::Halted));
+
+    let output = String::from_utf8_lossy(um.io().output()).into_owned();
+    assert!(output.contains("Welcome to Universal Machine IX (UMIX)."));
+    assert!(output.contains("logged in as guest"));
+}
+
+#[test]
+fn umix_container_login() {
+    let mut image = Image::read(&fs::read("prog.um").unwrap()).unwrap();
+    image.input = b"guest\n".to_vec();
+    let mut bytes = Vec::new();
+    image.write(&mut bytes).unwrap();
+
+    let image = Image::read(&bytes).unwrap();
+    let input = image.input.clone();
+    let mut um = Machine::from_image(image, Buffer::new(input));
+    assert_eq!(um.run(), Ok(Status::Halted));
+    let output = String::from_utf8_lossy(um.io().output()).into_owned();
+    assert!(output.contains("logged in as guest"));
+}